@@ -0,0 +1,83 @@
+//! HPET table: where the registers of the event timer block are.
+
+use super::read_le;
+
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+
+// Event timer block id, address structure, number, minimum tick, protection
+const TABLE_SIZE: usize = 20;
+
+// The registers are in memory, not in I/O ports
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+const BLOCK_ID_64_BITS: u64 = 1 << 13;
+const BLOCK_ID_LEGACY_ROUTE: u64 = 1 << 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetTable {
+    pub address: u64,
+    pub counter_64_bits: bool,
+    /// The comparators 0 and 1 can replace the PIT and the RTC interrupts
+    pub legacy_route: bool,
+    /// Smallest periodic tick, in counter periods
+    pub minimum_tick: u16,
+}
+
+impl HpetTable {
+    /// Reads the HPET table of the machine, if there is one
+    pub fn find() -> Option<Self> {
+        super::find_table(HPET_SIGNATURE).and_then(|table| Self::parse(table.data()))
+    }
+
+    /// Parses the content of the table after its header
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < TABLE_SIZE || data[4] != ADDRESS_SPACE_MEMORY {
+            return None;
+        }
+
+        let block_id = read_le(data, 0, 4);
+
+        Some(Self {
+            address: read_le(data, 8, 8),
+            counter_64_bits: block_id & BLOCK_ID_64_BITS != 0,
+            legacy_route: block_id & BLOCK_ID_LEGACY_ROUTE != 0,
+            minimum_tick: read_le(data, 17, 2) as u16,
+        })
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_hpet_table_parse() {
+    serial_print!("test_hpet_table_parse... ");
+
+    #[rustfmt::skip]
+    let data = [
+        // Event timer block id: 3 comparators, 64 bits, legacy route
+        0x01, 0xa2, 0x86, 0x80,
+        // Address structure: memory, 64 bits wide, at 0xfed00000
+        0, 64, 0, 0, 0x00, 0x00, 0xd0, 0xfe, 0, 0, 0, 0,
+        // HPET number, minimum tick, page protection
+        0, 0x80, 0x00, 0,
+    ];
+
+    let table = HpetTable::parse(&data).unwrap();
+
+    assert_eq!(table.address, 0xfed0_0000);
+    assert!(table.counter_64_bits);
+    assert!(table.legacy_route);
+    assert_eq!(table.minimum_tick, 0x80);
+
+    // Registers in I/O ports
+    let mut data = data;
+
+    data[4] = 1;
+
+    assert_eq!(HpetTable::parse(&data), None);
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,201 @@
+//! MADT: the interrupt controllers of the machine and the processors.
+
+use alloc::vec::Vec;
+
+use super::read_le;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+
+const LOCAL_APIC_ENABLED: u64 = 1;
+const LOCAL_APIC_ONLINE_CAPABLE: u64 = 2;
+
+// The dual 8259 PICs are installed too
+const PCAT_COMPAT: u64 = 1;
+
+// Local APIC address and flags
+const HEADER_SIZE: usize = 8;
+
+/// Processors of an NMI entry that apply to all of them
+pub const ALL_PROCESSORS: u8 = 0xff;
+
+/// Polarity of MPS INTI flags, the bus default is active high for ISA
+pub fn active_low(flags: u16) -> bool {
+    flags & 0b11 == 0b11
+}
+
+/// Trigger mode of MPS INTI flags, the bus default is edge for ISA
+pub fn level_triggered(flags: u16) -> bool {
+    (flags >> 2) & 0b11 == 0b11
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Disabled processors can only be started once enabled by the firmware
+    pub usable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u64,
+    /// First global system interrupt of its inputs
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt wired to another input than its number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// Polarity and trigger mode, MPS INTI flags
+    pub flags: u16,
+}
+
+/// Local APIC input the NMI is wired to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    pub processor_id: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub has_8259: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    /// Reads the MADT of the machine, if there is one
+    pub fn find() -> Option<Self> {
+        super::find_table(MADT_SIGNATURE).and_then(|table| Self::parse(table.data()))
+    }
+
+    /// Parses the content of the table after its header, the entries that
+    /// are unknown or truncated are skipped
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let mut madt = Self {
+            local_apic_address: read_le(data, 0, 4),
+            has_8259: read_le(data, 4, 4) & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut offset = HEADER_SIZE;
+
+        while offset + 2 <= data.len() {
+            let kind = data[offset];
+            let len = usize::from(data[offset + 1]);
+
+            if len < 2 || offset + len > data.len() {
+                break;
+            }
+
+            let entry = &data[offset..offset + len];
+
+            match (kind, len) {
+                (ENTRY_LOCAL_APIC, 8) => {
+                    let flags = read_le(entry, 4, 4);
+
+                    madt.processors.push(Processor {
+                        processor_id: entry[2],
+                        apic_id: entry[3],
+                        usable: flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0,
+                    });
+                }
+                (ENTRY_IO_APIC, 12) => madt.io_apics.push(IoApicEntry {
+                    id: entry[2],
+                    address: read_le(entry, 4, 4),
+                    gsi_base: read_le(entry, 8, 4) as u32,
+                }),
+                (ENTRY_INTERRUPT_OVERRIDE, 10) => madt.overrides.push(InterruptOverride {
+                    irq: entry[3],
+                    gsi: read_le(entry, 4, 4) as u32,
+                    flags: read_le(entry, 8, 2) as u16,
+                }),
+                (ENTRY_LOCAL_APIC_NMI, 6) => madt.nmis.push(LocalApicNmi {
+                    processor_id: entry[2],
+                    flags: read_le(entry, 3, 2) as u16,
+                    lint: entry[5],
+                }),
+                (ENTRY_LOCAL_APIC_ADDRESS, 12) => {
+                    madt.local_apic_address = read_le(entry, 4, 8);
+                }
+                _ => (),
+            }
+
+            offset += len;
+        }
+
+        Some(madt)
+    }
+
+    /// Input an ISA interrupt arrives on, with its polarity and trigger flags
+    pub fn legacy_irq(&self, irq: u8) -> (u32, u16) {
+        self.overrides
+            .iter()
+            .find(|entry| entry.irq == irq)
+            .map_or((u32::from(irq), 0), |entry| (entry.gsi, entry.flags))
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_madt_parse() {
+    serial_print!("test_madt_parse... ");
+
+    #[rustfmt::skip]
+    let data = [
+        // Local APIC address, flags
+        0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00,
+        // Local APIC: processor 0, APIC 0, enabled
+        0, 8, 0, 0, 1, 0, 0, 0,
+        // Local APIC: processor 1, APIC 2, disabled
+        0, 8, 1, 2, 0, 0, 0, 0,
+        // I/O APIC 1 at 0xfec00000, GSI 0
+        1, 12, 1, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0,
+        // IRQ 0 on GSI 2
+        2, 10, 0, 0, 2, 0, 0, 0, 0, 0,
+        // Unknown entry
+        9, 4, 0, 0,
+        // NMI on LINT1 of all the processors
+        4, 6, 0xff, 0, 0, 1,
+    ];
+
+    let madt = Madt::parse(&data).unwrap();
+
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert!(madt.has_8259);
+    assert_eq!(madt.processors.len(), 2);
+    assert_eq!(madt.processors[1].apic_id, 2);
+    assert!(!madt.processors[1].usable);
+    assert_eq!(madt.io_apics[0].address, 0xfec0_0000);
+    assert_eq!(madt.legacy_irq(0), (2, 0));
+    assert_eq!(madt.legacy_irq(1), (1, 0));
+    assert_eq!(madt.nmis[0].processor_id, ALL_PROCESSORS);
+    assert_eq!(madt.nmis[0].lint, 1);
+
+    serial_println!("[ok]");
+}
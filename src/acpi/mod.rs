@@ -0,0 +1,172 @@
+//! ACPI tables left in memory by the firmware.
+//!
+//! The root table is found through the RSDP in the BIOS area. Every table is
+//! identity mapped, read only, when it is first reached.
+
+use alloc::vec::Vec;
+use core::slice;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+
+use crate::memory::paging::helpers;
+use crate::serial_println;
+
+pub mod hpet;
+pub mod madt;
+
+// The EBDA is not searched, reading its segment would need page zero mapped
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// Part covered by the checksum of ACPI 1.0, the rest comes with ACPI 2.0
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+// Header shared by all the tables
+const SDT_HEADER_SIZE: usize = 36;
+
+/// A mapped table with a valid checksum
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    addr: PhysAddr,
+    pub signature: [u8; 4],
+    pub revision: u8,
+    len: usize,
+}
+
+impl Table {
+    /// Content after the header
+    pub fn data(&self) -> &'static [u8] {
+        let start = self.addr.as_u64() as usize + SDT_HEADER_SIZE;
+
+        unsafe { slice::from_raw_parts(start as *const u8, self.len - SDT_HEADER_SIZE) }
+    }
+}
+
+lazy_static! {
+    /// Tables listed by the root table
+    static ref TABLES: Mutex<Vec<PhysAddr>> = { Mutex::new(Vec::new()) };
+}
+
+fn map(addr: PhysAddr, size: u64) {
+    helpers::identity_map_range(
+        addr,
+        size,
+        PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+    );
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Address of the root table and size of its entries
+fn find_root() -> Option<(u64, usize)> {
+    map(
+        PhysAddr::new(BIOS_AREA_START),
+        BIOS_AREA_END - BIOS_AREA_START,
+    );
+
+    let rsdp = (BIOS_AREA_START..BIOS_AREA_END)
+        .step_by(16)
+        .map(|addr| unsafe { slice::from_raw_parts(addr as *const u8, RSDP_V1_SIZE) })
+        .find(|bytes| &bytes[..RSDP_SIGNATURE.len()] == RSDP_SIGNATURE && checksum_ok(bytes))?;
+
+    let revision = rsdp[15];
+
+    serial_println!("   RSDP at {:p}, revision {}", rsdp.as_ptr(), revision);
+
+    if revision >= 2 {
+        let rsdp = unsafe { slice::from_raw_parts(rsdp.as_ptr(), RSDP_V2_SIZE) };
+        let xsdt = read_le(rsdp, 24, 8);
+
+        // The XSDT holds 64 bits addresses
+        if checksum_ok(rsdp) && xsdt != 0 {
+            return Some((xsdt, 8));
+        }
+    }
+
+    Some((read_le(rsdp, 16, 4), 4))
+}
+
+/// Maps the table at `addr`, `None` if it is not valid
+fn map_table(addr: PhysAddr) -> Option<Table> {
+    map(addr, SDT_HEADER_SIZE as u64);
+
+    let header = unsafe { slice::from_raw_parts(addr.as_u64() as *const u8, SDT_HEADER_SIZE) };
+    let len = read_le(header, 4, 4) as usize;
+
+    if len < SDT_HEADER_SIZE {
+        return None;
+    }
+
+    map(addr, len as u64);
+
+    let bytes = unsafe { slice::from_raw_parts(addr.as_u64() as *const u8, len) };
+
+    if !checksum_ok(bytes) {
+        serial_println!("   Bad checksum for the ACPI table at {:?}", addr);
+
+        return None;
+    }
+
+    let mut signature = [0; 4];
+
+    signature.copy_from_slice(&bytes[..4]);
+
+    Some(Table {
+        addr,
+        signature,
+        revision: bytes[8],
+        len,
+    })
+}
+
+/// Finds the tables. A machine without ACPI only has no table.
+pub fn init() {
+    let (root_addr, entry_size) = match find_root() {
+        Some(root) => root,
+        None => {
+            serial_println!("   No RSDP found");
+
+            return;
+        }
+    };
+
+    let root = match map_table(PhysAddr::new(root_addr)) {
+        Some(root) => root,
+        None => return,
+    };
+
+    let tables: Vec<PhysAddr> = root
+        .data()
+        .chunks_exact(entry_size)
+        .map(|entry| PhysAddr::new(read_le(entry, 0, entry_size)))
+        .collect();
+
+    serial_println!("   {} ACPI tables listed at {:#x}", tables.len(), root_addr);
+
+    *TABLES.lock() = tables;
+}
+
+/// First valid table with `signature`
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    let tables = TABLES.lock().clone();
+
+    tables
+        .into_iter()
+        .filter_map(map_table)
+        .find(|table| &table.signature == signature)
+}
+
+/// Reads a little endian integer of `len` bytes from `bytes` at `offset`,
+/// for the fields of the tables
+fn read_le(bytes: &[u8], offset: usize, len: usize) -> u64 {
+    let mut value = [0; 8];
+
+    value[..len].copy_from_slice(&bytes[offset..offset + len]);
+
+    u64::from_le_bytes(value)
+}
@@ -0,0 +1,195 @@
+//! Keyboard input as an async stream of scancodes.
+//!
+//! The interrupt handler only queues the scancodes, decoding and printing
+//! the keys happens in `print_keypresses`, run by an executor.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::interrupts::irq::{self, IRQ_KEYBOARD};
+use crate::{print, serial_println};
+
+const SCANCODE_QUEUE_SIZE: usize = 128;
+
+const DATA_PORT: u16 = 0x60;
+
+struct ScancodeQueue {
+    scancodes: [u8; SCANCODE_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    /// Waker of the reader waiting for a scancode
+    waker: Option<Waker>,
+}
+
+/// Only locked with interrupts disabled, the keyboard interrupt fills it
+static SCANCODES: Mutex<ScancodeQueue> = Mutex::new(ScancodeQueue {
+    scancodes: [0; SCANCODE_QUEUE_SIZE],
+    head: 0,
+    len: 0,
+    waker: None,
+});
+
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Queues a scancode and wakes up the reader.
+/// Called by the keyboard interrupt handler, it must not allocate.
+pub fn add_scancode(scancode: u8) {
+    let waker = interrupts::without_interrupts(|| {
+        let queue = &mut *SCANCODES.lock();
+
+        if queue.len == SCANCODE_QUEUE_SIZE {
+            serial_println!("WARNING: scancode queue full, dropping keyboard input");
+
+            return None;
+        }
+
+        queue.scancodes[(queue.head + queue.len) % SCANCODE_QUEUE_SIZE] = scancode;
+        queue.len += 1;
+
+        queue.waker.take()
+    });
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Registers the keyboard interrupt handler
+pub fn init() {
+    irq::register_irq(IRQ_KEYBOARD, keyboard_interrupt).expect("keyboard IRQ already registered");
+}
+
+fn keyboard_interrupt(_irq: u8, _stack_frame: &mut InterruptStackFrame) -> bool {
+    let scancode: u8 = unsafe { Port::new(DATA_PORT).read() };
+
+    // Decoded by the keyboard future, outside of the interrupt
+    add_scancode(scancode);
+
+    true
+}
+
+/// The reading end of the scancode queue, there is only one
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        assert!(
+            !STREAM_TAKEN.swap(true, Ordering::SeqCst),
+            "the scancode stream already exists"
+        );
+
+        Self { _private: () }
+    }
+
+    /// Waits for the next scancode
+    pub fn next(&mut self) -> NextScancode<'_> {
+        NextScancode { _stream: self }
+    }
+}
+
+pub struct NextScancode<'a> {
+    _stream: &'a mut ScancodeStream,
+}
+
+impl<'a> Future for NextScancode<'a> {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<u8> {
+        // Cloned before disabling interrupts, it may allocate
+        let waker = context.waker().clone();
+
+        interrupts::without_interrupts(|| {
+            let queue = &mut *SCANCODES.lock();
+
+            if queue.len == 0 {
+                // Checked under the same lock the interrupt takes, a scancode
+                // cannot arrive unnoticed
+                queue.waker = Some(waker);
+
+                return Poll::Pending;
+            }
+
+            let scancode = queue.scancodes[queue.head];
+
+            queue.head = (queue.head + 1) % SCANCODE_QUEUE_SIZE;
+            queue.len -= 1;
+
+            Poll::Ready(scancode)
+        })
+    }
+}
+
+/// Decodes the keys typed and prints them
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1);
+
+    loop {
+        let scancode = scancodes.next().await;
+
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => print!("{}", character),
+                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                }
+            }
+        }
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_scancode_stream() {
+    use super::Executor;
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicU32;
+
+    serial_print!("test_scancode_stream... ");
+
+    let received = Arc::new(AtomicU32::new(0));
+    let mut stream = ScancodeStream::new();
+    let mut executor = Executor::new();
+
+    // One scancode already queued, the second one comes while waiting
+    add_scancode(0x1e);
+
+    {
+        let received = received.clone();
+
+        executor.spawn(async move {
+            for _ in 0..2 {
+                let scancode = stream.next().await;
+
+                received.fetch_add(u32::from(scancode), Ordering::SeqCst);
+            }
+        });
+    }
+
+    executor.run_ready();
+
+    assert_eq!(received.load(Ordering::SeqCst), 0x1e);
+    assert_eq!(executor.pending(), 1);
+
+    add_scancode(0x30);
+    executor.run_ready();
+
+    assert_eq!(received.load(Ordering::SeqCst), 0x1e + 0x30);
+    assert_eq!(executor.pending(), 0);
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,356 @@
+//! Cooperative executor for `async` kernel code.
+//!
+//! An executor polls its futures on the kernel task that runs it, one at a
+//! time. A future that cannot progress keeps the waker it was polled with and
+//! returns; waking it, from an interrupt handler or another task, queues it
+//! for the next poll. While nothing is ready the executor task sleeps and the
+//! scheduler runs the other tasks.
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    mem::ManuallyDrop,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::schedule::WaitQueue;
+
+pub mod keyboard;
+
+/// Most futures an executor can run at once
+pub const MAX_FUTURES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FutureId(u64);
+
+/// Fixed size queue of the futures to poll, wakers never allocate so they
+/// can be used from interrupt handlers
+struct ReadyQueue {
+    ids: Mutex<([FutureId; MAX_FUTURES], usize, usize)>,
+    /// Where the executor sleeps while the queue is empty
+    wakeup: WaitQueue,
+}
+
+impl ReadyQueue {
+    fn new() -> Self {
+        Self {
+            ids: Mutex::new(([FutureId(0); MAX_FUTURES], 0, 0)),
+            wakeup: WaitQueue::new(),
+        }
+    }
+
+    /// A future is queued at most once, the queue cannot overflow
+    fn push(&self, id: FutureId) {
+        interrupts::without_interrupts(|| {
+            let (ids, head, len) = &mut *self.ids.lock();
+
+            assert!(*len < MAX_FUTURES, "ready queue overflow");
+
+            ids[(*head + *len) % MAX_FUTURES] = id;
+            *len += 1;
+        });
+
+        self.wakeup.wake_one();
+    }
+
+    fn pop(&self) -> Option<FutureId> {
+        interrupts::without_interrupts(|| {
+            let (ids, head, len) = &mut *self.ids.lock();
+
+            if *len == 0 {
+                return None;
+            }
+
+            let id = ids[*head];
+
+            *head = (*head + 1) % MAX_FUTURES;
+            *len -= 1;
+
+            Some(id)
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.ids.lock().2 == 0)
+    }
+}
+
+struct FutureWaker {
+    id: FutureId,
+    /// Set while the future is in the ready queue
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+impl FutureWaker {
+    fn wake(&self) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            self.ready.push(self.id);
+        }
+    }
+
+    /// The executor keeps a reference to every waker until the others are
+    /// gone, even after its future completed: dropping one from an interrupt
+    /// handler never frees it
+    fn waker(waker: Arc<FutureWaker>) -> Waker {
+        unsafe { Waker::from_raw(raw_waker(Arc::into_raw(waker))) }
+    }
+}
+
+fn raw_waker(waker: *const FutureWaker) -> RawWaker {
+    RawWaker::new(waker as *const (), &WAKER_VTABLE)
+}
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    let waker = ManuallyDrop::new(Arc::from_raw(data as *const FutureWaker));
+
+    raw_waker(Arc::into_raw(Arc::clone(&waker)))
+}
+
+unsafe fn wake(data: *const ()) {
+    Arc::from_raw(data as *const FutureWaker).wake();
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    (*(data as *const FutureWaker)).wake();
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(Arc::from_raw(data as *const FutureWaker));
+}
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    waker: Arc<FutureWaker>,
+}
+
+pub struct Executor {
+    tasks: BTreeMap<FutureId, Task>,
+    /// Wakers of completed futures that are still referenced elsewhere
+    retired: Vec<Arc<FutureWaker>>,
+    ready: Arc<ReadyQueue>,
+    next_id: u64,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            retired: Vec::new(),
+            ready: Arc::new(ReadyQueue::new()),
+            next_id: 0,
+        }
+    }
+
+    /// Adds a future, polled a first time on the next run
+    pub fn spawn<F>(&mut self, future: F) -> FutureId
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        assert!(self.tasks.len() < MAX_FUTURES, "too many futures");
+
+        let id = FutureId(self.next_id);
+
+        self.next_id += 1;
+
+        let waker = Arc::new(FutureWaker {
+            id,
+            queued: AtomicBool::new(false),
+            ready: self.ready.clone(),
+        });
+
+        waker.wake();
+
+        self.tasks.insert(
+            id,
+            Task {
+                future: Box::pin(future),
+                waker,
+            },
+        );
+
+        id
+    }
+
+    /// Number of futures not completed yet
+    pub fn pending(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Polls the futures that were woken up until none is left ready
+    pub fn run_ready(&mut self) {
+        while let Some(id) = self.ready.pop() {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) => task,
+                None => continue,
+            };
+
+            // Woken up again from now on, even while it is being polled
+            task.waker.queued.store(false, Ordering::SeqCst);
+
+            let waker = FutureWaker::waker(task.waker.clone());
+            let mut context = Context::from_waker(&waker);
+
+            if let Poll::Ready(()) = task.future.as_mut().poll(&mut context) {
+                let task = self.tasks.remove(&id).unwrap();
+
+                self.retired.push(task.waker);
+            }
+        }
+
+        // Only the executor can still reach these, they are freed here rather
+        // than in an interrupt handler
+        self.retired.retain(|waker| Arc::strong_count(waker) > 1);
+    }
+
+    /// Runs the futures forever on the current task, sleeping while none is
+    /// ready
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready();
+
+            let ready = self.ready.clone();
+
+            ready.wakeup.wait_until(|| !ready.is_empty());
+        }
+    }
+}
+
+impl Drop for Executor {
+    /// Leaks the wakers still held elsewhere, the last reference must not be
+    /// dropped from an interrupt handler
+    fn drop(&mut self) {
+        let tasks = core::mem::replace(&mut self.tasks, BTreeMap::new());
+        let wakers = tasks
+            .into_iter()
+            .map(|(_, task)| task.waker)
+            .chain(self.retired.drain(..));
+
+        for waker in wakers {
+            if Arc::strong_count(&waker) > 1 {
+                core::mem::forget(waker);
+            }
+        }
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_executor_wakeups() {
+    use crate::schedule;
+
+    serial_print!("test_executor_wakeups... ");
+
+    /// Completes once `set` is true, like a future waiting for an interrupt
+    struct Flag {
+        set: AtomicBool,
+        waker: Mutex<Option<Waker>>,
+    }
+
+    struct WaitFlag(Arc<Flag>);
+
+    impl Future for WaitFlag {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            *self.0.waker.lock() = Some(context.waker().clone());
+
+            if self.0.set.load(Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    let flag = Arc::new(Flag {
+        set: AtomicBool::new(false),
+        waker: Mutex::new(None),
+    });
+    let done = Arc::new(AtomicBool::new(false));
+
+    let mut executor = Executor::new();
+
+    {
+        let flag = flag.clone();
+        let done = done.clone();
+
+        executor.spawn(async move {
+            WaitFlag(flag).await;
+            done.store(true, Ordering::SeqCst);
+        });
+    }
+
+    executor.run_ready();
+
+    assert!(!done.load(Ordering::SeqCst));
+    assert_eq!(executor.pending(), 1);
+
+    // Woken up by another task, the executor sleeps until then
+    schedule::spawn(move || {
+        flag.set.store(true, Ordering::SeqCst);
+
+        if let Some(waker) = flag.waker.lock().take() {
+            waker.wake();
+        }
+    });
+
+    while executor.pending() > 0 {
+        let ready = executor.ready.clone();
+
+        ready.wakeup.wait_until(|| !ready.is_empty());
+        executor.run_ready();
+    }
+
+    assert!(done.load(Ordering::SeqCst));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_executor_keeps_wakers() {
+    serial_print!("test_executor_keeps_wakers... ");
+
+    /// Completes right away, a copy of its waker stays behind like in the
+    /// scancode queue
+    struct KeepWaker(Arc<Mutex<Option<Waker>>>);
+
+    impl Future for KeepWaker {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            *self.0.lock() = Some(context.waker().clone());
+
+            Poll::Ready(())
+        }
+    }
+
+    let kept = Arc::new(Mutex::new(None));
+    let mut executor = Executor::new();
+
+    executor.spawn(KeepWaker(kept.clone()));
+
+    executor.run_ready();
+
+    assert_eq!(executor.pending(), 0);
+    assert_eq!(executor.retired.len(), 1);
+
+    // The executor frees the waker once it holds the last reference
+    drop(kept.lock().take());
+    executor.run_ready();
+
+    assert!(executor.retired.is_empty());
+
+    serial_println!("[ok]");
+}
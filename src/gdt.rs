@@ -1,71 +1,211 @@
 use super::serial_println;
+use alloc::{boxed::Box, vec};
+use core::cell::{Cell, UnsafeCell};
 use lazy_static::lazy_static;
 use x86_64::{
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
     },
-    VirtAddr,
+    PrivilegeLevel, VirtAddr,
 };
 
+use crate::percpu;
+use crate::smp::cpu;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// NMIs and machine checks can arrive anywhere, even on a broken stack
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+// Raw segment descriptors. The data segments are flagged writable, which is
+// required to load them in SS.
+const KERNEL_DATA_SEGMENT: u64 = 0x00cf_9200_0000_ffff;
+const USER_CODE_SEGMENT: u64 = 0x00af_fa00_0000_ffff;
+const USER_DATA_SEGMENT: u64 = 0x00cf_f200_0000_ffff;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 16;
+const EXCEPTION_STACK_SIZE: usize = 4096 * 4;
+
+/// The TSS is updated on every task switch to point at the kernel stack of
+/// the next task
+struct TssCell(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for TssCell {}
+
+percpu! {
+    /// TSS loaded on the processor
+    static CURRENT_TSS: Cell<*mut TaskStateSegment> = Cell::new(core::ptr::null_mut());
+}
+
+/// Start of an interrupt stack ending at `end`. The address of the `Cpu`
+/// area of the current processor is stored right above the frames, for the
+/// paranoid entries of asm/exceptions.S.
+fn interrupt_stack(end: VirtAddr) -> VirtAddr {
+    let start = (end - 8u64).align_down(16u64);
+
+    unsafe { *start.as_mut_ptr::<u64>() = cpu::current() as *const cpu::Cpu as u64 };
+
+    start
+}
+
+/// TSS with the given tops of the interrupt stacks, of the current processor
+fn new_tss(double_fault: VirtAddr, nmi: VirtAddr, machine_check: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = interrupt_stack(double_fault);
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = interrupt_stack(nmi);
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = interrupt_stack(machine_check);
+
+    tss
+}
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: TssCell = {
         serial_println!("   Create TSS:");
 
-        let mut tss = TaskStateSegment::new();
+        let double_fault = {
+            static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 16;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            serial_println!("       Set kernel stack: Size: {:#?}", STACK_SIZE);
+            serial_println!(
+                "       Set kernel stack: Size: {:#?}",
+                DOUBLE_FAULT_STACK_SIZE
+            );
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
 
             stack_end
         };
-        tss
+
+        let nmi = {
+            static mut STACK: [u8; EXCEPTION_STACK_SIZE] = [0; EXCEPTION_STACK_SIZE];
+
+            VirtAddr::from_ptr(unsafe { &STACK }) + EXCEPTION_STACK_SIZE
+        };
+
+        let machine_check = {
+            static mut STACK: [u8; EXCEPTION_STACK_SIZE] = [0; EXCEPTION_STACK_SIZE];
+
+            VirtAddr::from_ptr(unsafe { &STACK }) + EXCEPTION_STACK_SIZE
+        };
+
+        TssCell(UnsafeCell::new(new_tss(double_fault, nmi, machine_check)))
     };
 }
 
+/// GDT pointing at `tss`, every processor has its own with the same
+/// selectors
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    // The order matters for SYSCALL/SYSRET: kernel data right after kernel
+    // code, user data right before user code
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT));
+    let user_data_selector = gdt.add_entry(Descriptor::UserSegment(USER_DATA_SEGMENT));
+    let user_code_selector = gdt.add_entry(Descriptor::UserSegment(USER_CODE_SEGMENT));
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector: SegmentSelector::new(
+                user_code_selector.index(),
+                PrivilegeLevel::Ring3,
+            ),
+            user_data_selector: SegmentSelector::new(
+                user_data_selector.index(),
+                PrivilegeLevel::Ring3,
+            ),
+            tss_selector,
+        },
+    )
+}
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         serial_println!("   Create GDT");
 
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+        new_gdt(unsafe { &*TSS.0.get() })
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+/// Loads the GDT and the TSS of the boot processor. Needs the per-CPU
+/// variables.
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    serial_println!("   Load GDT: {:#?}", GDT.0);
+    serial_println!("   Set CS: {:#?}", GDT.1.code_selector);
+    serial_println!("   Set SS/DS/ES: {:#?}", GDT.1.data_selector);
+    serial_println!("   Set TSS: {:#?}", GDT.1.tss_selector);
+
+    unsafe { load(&GDT) };
+
+    CURRENT_TSS.get().set(TSS.0.get());
+}
+
+/// Loads a new GDT and TSS on an application processor, with interrupt
+/// stacks on the heap
+pub fn init_ap() {
+    let stack = |size: usize| {
+        let stack = Box::leak(vec![0u8; size].into_boxed_slice());
+
+        VirtAddr::from_ptr(stack.as_ptr()) + size
+    };
+
+    let tss = Box::leak(Box::new(TssCell(UnsafeCell::new(new_tss(
+        stack(DOUBLE_FAULT_STACK_SIZE),
+        stack(EXCEPTION_STACK_SIZE),
+        stack(EXCEPTION_STACK_SIZE),
+    )))));
+    let gdt = Box::leak(Box::new(new_gdt(unsafe { &*tss.0.get() })));
+
+    unsafe { load(gdt) };
+
+    CURRENT_TSS.get().set(tss.0.get());
+}
+
+unsafe fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
-    serial_println!("   Load GDT: {:#?}", GDT.0);
+    gdt.0.load();
+
+    set_cs(gdt.1.code_selector);
+    load_ss(gdt.1.data_selector);
+    load_ds(gdt.1.data_selector);
+    load_es(gdt.1.data_selector);
+    load_tss(gdt.1.tss_selector);
+}
 
-    GDT.0.load();
+pub fn kernel_code_selector() -> SegmentSelector {
+    GDT.1.code_selector
+}
 
-    unsafe {
-        serial_println!("   Set CS: {:#?}", GDT.1.code_selector);
-        set_cs(GDT.1.code_selector);
+pub fn kernel_data_selector() -> SegmentSelector {
+    GDT.1.data_selector
+}
+
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
 
-        serial_println!("   Set TSS: {:#?}", GDT.1.tss_selector);
-        load_tss(GDT.1.tss_selector);
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+/// Sets the stack the current CPU switches to when an interrupt arrives in
+/// ring 3
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        (*CURRENT_TSS.get().get()).privilege_stack_table[0] = stack_top;
     }
 }
@@ -1,216 +0,0 @@
-use lazy_static::lazy_static;
-use pic8259_simple::ChainedPics;
-use spin;
-use x86_64::structures::idt::PageFaultErrorCode;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-
-use crate::gdt;
-use crate::hlt_loop;
-use crate::{print, println, serial_println};
-
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        serial_println!("   Create IDT:");
-
-        let mut idt = InterruptDescriptorTable::new();
-
-
-        idt.divide_error.set_handler_fn(not_implemented_yet);
-        idt.debug.set_handler_fn(not_implemented_yet);
-        idt.non_maskable_interrupt
-            .set_handler_fn(not_implemented_yet);
-        idt.overflow.set_handler_fn(not_implemented_yet);
-        idt.bound_range_exceeded.set_handler_fn(not_implemented_yet);
-        idt.invalid_opcode.set_handler_fn(not_implemented_yet);
-        idt.device_not_available.set_handler_fn(not_implemented_yet);
-        idt.invalid_tss.set_handler_fn(not_implemented_yet_code);
-        idt.segment_not_present
-            .set_handler_fn(not_implemented_yet_code);
-        idt.stack_segment_fault
-            .set_handler_fn(not_implemented_yet_code);
-        idt.general_protection_fault
-            .set_handler_fn(not_implemented_yet_code);
-        idt.x87_floating_point.set_handler_fn(not_implemented_yet);
-        idt.alignment_check.set_handler_fn(not_implemented_yet_code);
-        // idt.machine_check.set_handler_fn(not_implemented_yet);
-        idt.simd_floating_point.set_handler_fn(not_implemented_yet);
-        idt.virtualization.set_handler_fn(not_implemented_yet);
-        idt.security_exception
-            .set_handler_fn(not_implemented_yet_code);
-
-
-        serial_println!("       Set Breackpoint handler");
-
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-
-        serial_println!("       Set DoubleFault handler");
-
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-
-        serial_println!("       Set PIC handler");
-
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-
-        serial_println!("       Set Keyboard handler");
-
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-
-        serial_println!("       Set PageFault handler");
-
-        idt.page_fault.set_handler_fn(page_fault_handler);
-
-        idt
-    };
-}
-pub fn init_idt() {
-    IDT.load();
-
-    serial_println!("       Init PICS");
-    unsafe { PICS.lock().initialize() };
-}
-
-extern "x86-interrupt" fn not_implemented_yet(stack_frame: &mut InterruptStackFrame) {
-    serial_println!("INTERRUPT: NOT IMPLEMENTED: {:#?}", stack_frame);
-    println!("EXCEPTION: NOT IMPLEMENTED\n{:#?}", stack_frame);
-
-    loop {}
-}
-
-extern "x86-interrupt" fn not_implemented_yet_code(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) {
-    serial_println!("INTERRUPT: NOT IMPLEMENTED: {:#?}", stack_frame);
-    println!("EXCEPTION: NOT IMPLEMENTED\n{:#?}", stack_frame);
-
-    loop {}
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    serial_println!("INTERRUPT: Breakpoint: {:#?}", stack_frame);
-
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    serial_println!(
-        "INTERRUPT: DOUBLE FAULT: {:#?} ({})",
-        stack_frame,
-        _error_code
-    );
-
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    // serial_println!("INTERRUPT: Timer: {:#?}", _stack_frame);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    // serial_println!(
-    //     "INTERRUPT: PageFault: {:#?} ({:#?})",
-    //     stack_frame,
-    //     error_code
-    // );
-    // println!("EXCEPTION: PAGE FAULT");
-    // println!("Accessed Address: {:?}", Cr2::read());
-    // println!("Error Code: {:?}", error_code);
-    // println!("{:#?}", stack_frame);
-    // hlt_loop();
-
-    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
-        super::memory::paging::helpers::alloc_page(Cr2::read());
-    } else {
-        serial_println!(
-            "INTERRUPT: PageFault: {:#?} ({:#?})",
-            stack_frame,
-            error_code
-        );
-        println!("EXCEPTION: PAGE FAULT");
-        println!("Accessed Address: {:?}", Cr2::read());
-        println!("Error Code: {:?}", error_code);
-        println!("{:#?}", stack_frame);
-        hlt_loop();
-    }
-}
-
-pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
-
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
-
-// tests
-
-#[cfg(test)]
-use crate::serial_print;
-
-#[test_case]
-fn test_breakpoint_exception() {
-    serial_print!("test_breakpoint_exception...");
-    x86_64::instructions::interrupts::int3();
-    serial_println!("[ok]");
-}
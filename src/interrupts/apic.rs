@@ -0,0 +1,247 @@
+//! Local APIC: the interrupt controller of each processor.
+//!
+//! Every processor reaches its own local APIC at the same physical address,
+//! identity mapped uncached.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::acpi::madt::{self, Madt};
+use crate::memory::paging::helpers;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const CPUID_FEATURES: u32 = 1;
+const CPUID_FEATURES_EDX_APIC: u32 = 1 << 9;
+
+// Registers, as offsets from the base
+const REG_ID: u64 = 0x20;
+const REG_TASK_PRIORITY: u64 = 0x80;
+const REG_EOI: u64 = 0xb0;
+const REG_SPURIOUS: u64 = 0xf0;
+const REG_ERROR_STATUS: u64 = 0x280;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_LVT_LINT0: u64 = 0x350;
+const REG_LVT_LINT1: u64 = 0x360;
+const REG_LVT_ERROR: u64 = 0x370;
+const REG_TIMER_INITIAL_COUNT: u64 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u64 = 0x390;
+const REG_TIMER_DIVIDE: u64 = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+// The timer counts down at the bus clock divided by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Sent by a processor waking up a task of another one
+pub const RESCHEDULE_VECTOR: u8 = 0xfb;
+/// Sent by `smp::ipi::call_function_mask()`
+pub const CALL_FUNCTION_VECTOR: u8 = 0xfc;
+pub const TIMER_VECTOR: u8 = 0xfd;
+
+pub const ERROR_VECTOR: u8 = 0xfe;
+/// Raised when an interrupt vanishes before it is delivered, never
+/// acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Address of the registers, zero while the local APIC is not used
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(CPUID_FEATURES).edx & CPUID_FEATURES_EDX_APIC != 0 }
+}
+
+/// Whether the APICs deliver the interrupts instead of the PIC
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+unsafe fn read(reg: u64) -> u32 {
+    core::ptr::read_volatile((BASE.load(Ordering::Relaxed) + reg) as *const u32)
+}
+
+unsafe fn write(reg: u64, value: u32) {
+    core::ptr::write_volatile((BASE.load(Ordering::Relaxed) + reg) as *mut u32, value)
+}
+
+/// Maps the registers and enables the local APIC of the current processor.
+/// The interrupts the PIC delivers on LINT0 are masked.
+pub fn init(madt: &Madt) {
+    let base = madt.local_apic_address;
+
+    helpers::identity_map_device(PhysAddr::new(base), 4096);
+
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        let value = msr.read();
+
+        msr.write((value & !APIC_BASE_ADDRESS_MASK) | base | APIC_BASE_ENABLE);
+    }
+
+    BASE.store(base, Ordering::SeqCst);
+
+    unsafe {
+        write(REG_TASK_PRIORITY, 0);
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_LVT_LINT0, LVT_MASKED);
+        write(REG_LVT_LINT1, LVT_MASKED);
+
+        let processor_id = madt
+            .processors
+            .iter()
+            .find(|processor| processor.apic_id == id())
+            .map(|processor| processor.processor_id);
+
+        for nmi in &madt.nmis {
+            if nmi.processor_id != madt::ALL_PROCESSORS && Some(nmi.processor_id) != processor_id {
+                continue;
+            }
+
+            let mut lvt = LVT_DELIVERY_NMI;
+
+            if madt::active_low(nmi.flags) {
+                lvt |= LVT_ACTIVE_LOW;
+            }
+
+            if madt::level_triggered(nmi.flags) {
+                lvt |= LVT_LEVEL_TRIGGERED;
+            }
+
+            match nmi.lint {
+                0 => write(REG_LVT_LINT0, lvt),
+                1 => write(REG_LVT_LINT1, lvt),
+                _ => (),
+            }
+        }
+
+        write(REG_LVT_ERROR, u32::from(ERROR_VECTOR));
+
+        // Cleared by a write first
+        write(REG_ERROR_STATUS, 0);
+        write(REG_ERROR_STATUS, 0);
+
+        write(
+            REG_SPURIOUS,
+            SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+
+        end_of_interrupt();
+    }
+}
+
+/// APIC id of the current processor
+pub fn id() -> u8 {
+    unsafe { (read(REG_ID) >> 24) as u8 }
+}
+
+/// Acknowledges the interrupt being handled
+pub fn end_of_interrupt() {
+    unsafe { write(REG_EOI, 0) }
+}
+
+/// Errors noticed since the last call
+pub fn error_status() -> u32 {
+    unsafe {
+        write(REG_ERROR_STATUS, 0);
+
+        read(REG_ERROR_STATUS)
+    }
+}
+
+/// Starts the timer counting down from its maximum, without interrupt, to
+/// measure its rate
+pub fn start_timer_count() {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+        write(REG_TIMER_INITIAL_COUNT, u32::max_value());
+    }
+}
+
+/// Stops the timer, returns the counts since `start_timer_count()`
+pub fn stop_timer_count() -> u32 {
+    unsafe {
+        let current = read(REG_TIMER_CURRENT_COUNT);
+
+        write(REG_TIMER_INITIAL_COUNT, 0);
+
+        u32::max_value() - current
+    }
+}
+
+/// Raises `TIMER_VECTOR` every `count` timer counts
+pub fn set_timer_periodic(count: u32) {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(TIMER_VECTOR));
+        write(REG_TIMER_INITIAL_COUNT, count);
+    }
+}
+
+/// Raises `TIMER_VECTOR` once, after `count` timer counts
+pub fn set_timer_oneshot(count: u32) {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, u32::from(TIMER_VECTOR));
+        write(REG_TIMER_INITIAL_COUNT, count);
+    }
+}
+
+/// A zero count stops the timer
+pub fn stop_timer() {
+    unsafe { write(REG_TIMER_INITIAL_COUNT, 0) }
+}
+
+/// Sends an interrupt command to the processor `apic_id` and waits until it
+/// is accepted
+fn send_command(apic_id: u8, command: u32) {
+    // An interrupt handler sending an IPI between the two writes would change
+    // the destination
+    interrupts::without_interrupts(|| unsafe {
+        write(REG_ICR_HIGH, u32::from(apic_id) << 24);
+        // Writing the low half sends it
+        write(REG_ICR_LOW, command);
+
+        while read(REG_ICR_LOW) & ICR_SEND_PENDING != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    })
+}
+
+/// Raises `vector` on the processor `apic_id`
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send_command(apic_id, ICR_DELIVERY_FIXED | u32::from(vector));
+}
+
+/// Resets the processor `apic_id`, it then waits for a STARTUP IPI
+pub fn send_init(apic_id: u8) {
+    send_command(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Starts the processor `apic_id` in real mode at the address
+/// `page * 4096`, which must be below 1 MiB
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_command(
+        apic_id,
+        ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(page),
+    );
+}
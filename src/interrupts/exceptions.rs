@@ -0,0 +1,627 @@
+//! Handlers of the CPU exceptions.
+//!
+//! An exception raised by userland becomes a signal for its process, whose
+//! default action kills it. One raised by the kernel is a bug: the handler
+//! prints what it can decode about it with the registers, and panics.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::{Cr0, Cr2};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+use super::TrapFrame;
+use crate::gdt;
+use crate::memory::paging;
+use crate::process::{
+    self,
+    signal::{self, Signal},
+};
+use crate::{println, serial_println};
+
+extern "C" {
+    fn read_dr6() -> u64;
+    fn read_mxcsr() -> u32;
+    fn read_fpu_status() -> u16;
+
+    fn divide_error_entry();
+    fn debug_entry();
+    fn nmi_entry();
+    fn breakpoint_entry();
+    fn overflow_entry();
+    fn bound_range_exceeded_entry();
+    fn invalid_opcode_entry();
+    fn device_not_available_entry();
+    fn double_fault_entry();
+    fn invalid_tss_entry();
+    fn segment_not_present_entry();
+    fn stack_segment_fault_entry();
+    fn general_protection_fault_entry();
+    fn page_fault_entry();
+    fn x87_floating_point_entry();
+    fn alignment_check_entry();
+    fn machine_check_entry();
+    fn simd_floating_point_entry();
+    fn virtualization_entry();
+    fn security_exception_entry();
+}
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MC0_STATUS: u32 = 0x401;
+
+const MCI_STATUS_VALID: u64 = 1 << 63;
+
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+// System control port B, reports the source of an NMI
+const NMI_STATUS_PORT: u16 = 0x61;
+const NMI_MEMORY_PARITY: u8 = 0x80;
+const NMI_IO_CHANNEL_CHECK: u8 = 0x40;
+
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// The entry stubs save the registers and call `exception_dispatch`, the IDT
+/// only needs their address whatever its handler type
+unsafe fn entry<F>(stub: unsafe extern "C" fn()) -> F {
+    core::mem::transmute_copy(&stub)
+}
+
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_fn(entry(divide_error_entry));
+        idt.debug.set_handler_fn(entry(debug_entry));
+        idt.overflow.set_handler_fn(entry(overflow_entry));
+        idt.bound_range_exceeded
+            .set_handler_fn(entry(bound_range_exceeded_entry));
+        idt.invalid_opcode
+            .set_handler_fn(entry(invalid_opcode_entry));
+        idt.device_not_available
+            .set_handler_fn(entry(device_not_available_entry));
+        idt.invalid_tss.set_handler_fn(entry(invalid_tss_entry));
+        idt.segment_not_present
+            .set_handler_fn(entry(segment_not_present_entry));
+        idt.stack_segment_fault
+            .set_handler_fn(entry(stack_segment_fault_entry));
+        idt.general_protection_fault
+            .set_handler_fn(entry(general_protection_fault_entry));
+        idt.page_fault.set_handler_fn(entry(page_fault_entry));
+        idt.x87_floating_point
+            .set_handler_fn(entry(x87_floating_point_entry));
+        idt.alignment_check
+            .set_handler_fn(entry(alignment_check_entry));
+        idt.simd_floating_point
+            .set_handler_fn(entry(simd_floating_point_entry));
+        idt.virtualization
+            .set_handler_fn(entry(virtualization_entry));
+        idt.security_exception
+            .set_handler_fn(entry(security_exception_entry));
+
+        // Userland may use int3 to stop in a debugger
+        idt.breakpoint
+            .set_handler_fn(entry(breakpoint_entry))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+
+        idt.double_fault
+            .set_handler_fn(entry(double_fault_entry))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.non_maskable_interrupt
+            .set_handler_fn(entry(nmi_entry))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(entry(machine_check_entry))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+}
+
+/// Called by the entry stubs with interrupts disabled
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut TrapFrame) {
+    match frame.vector {
+        0 => divide_error(frame),
+        1 => debug(frame),
+        2 => nmi(frame),
+        3 => breakpoint(frame),
+        4 => overflow(frame),
+        5 => bound_range_exceeded(frame),
+        6 => invalid_opcode(frame),
+        7 => device_not_available(frame),
+        8 => double_fault(frame),
+        10 => invalid_tss(frame),
+        11 => segment_not_present(frame),
+        12 => stack_segment_fault(frame),
+        13 => general_protection_fault(frame),
+        14 => page_fault(frame),
+        16 => x87_floating_point(frame),
+        17 => alignment_check(frame),
+        18 => machine_check(frame),
+        19 => simd_floating_point(frame),
+        20 => virtualization(frame),
+        30 => security_exception(frame),
+        vector => kernel_fault(
+            frame,
+            "UNKNOWN EXCEPTION",
+            format_args!("vector {}", vector),
+        ),
+    }
+}
+
+/// Sends `signal` to the process that raised the exception, or panics if the
+/// kernel did
+fn fault(frame: &TrapFrame, name: &str, signal: Signal, details: fmt::Arguments) -> ! {
+    if frame.from_user() {
+        serial_println!(
+            "INTERRUPT: User {} in process {}: {}\n{}",
+            name,
+            process::current_pid(),
+            details,
+            frame
+        );
+
+        signal::raise_exception(frame, signal);
+    }
+
+    kernel_fault(frame, name, details)
+}
+
+fn kernel_fault(frame: &TrapFrame, name: &str, details: fmt::Arguments) -> ! {
+    serial_println!("INTERRUPT: {}: {}\n{}", name, details, frame);
+
+    panic!("EXCEPTION: {}: {}\n{}", name, details, frame);
+}
+
+/// Error code of the exceptions caused by a segment selector or a gate
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let index = (code >> 3) & 0x1fff;
+
+        if code == 0 {
+            return write!(f, "no selector");
+        }
+
+        match (code >> 1) & 0b11 {
+            0 => write!(f, "GDT selector {:#x}", code & 0xfff8)?,
+            2 => write!(f, "LDT selector {:#x}", (code & 0xfff8) | 0b100)?,
+            _ => write!(f, "IDT vector {}", index)?,
+        }
+
+        if code & 1 != 0 {
+            write!(f, " (external event)")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes at an instruction pointer, up to the longest instruction or the first
+/// page that cannot be read
+struct InstructionBytes {
+    bytes: [u8; MAX_INSTRUCTION_LEN],
+    len: usize,
+}
+
+impl InstructionBytes {
+    /// Only reads user pages for an exception raised by userland
+    fn read(rip: VirtAddr, user: bool) -> Self {
+        let mut instruction = Self {
+            bytes: [0; MAX_INSTRUCTION_LEN],
+            len: 0,
+        };
+
+        while instruction.len < MAX_INSTRUCTION_LEN {
+            let addr = match rip
+                .as_u64()
+                .checked_add(instruction.len as u64)
+                .and_then(|addr| VirtAddr::try_new(addr).ok())
+            {
+                Some(addr) => addr,
+                None => break,
+            };
+
+            if !readable(addr, user) {
+                break;
+            }
+
+            instruction.bytes[instruction.len] =
+                unsafe { core::ptr::read_volatile(addr.as_ptr::<u8>()) };
+            instruction.len += 1;
+        }
+
+        instruction
+    }
+}
+
+fn readable(addr: VirtAddr, user: bool) -> bool {
+    if user && !paging::is_user_addr(addr) {
+        return false;
+    }
+
+    match paging::helpers::page_flags(Page::containing_address(addr)) {
+        Some(flags) => !user || flags.contains(PageTableFlags::USER_ACCESSIBLE),
+        None => false,
+    }
+}
+
+impl fmt::Display for InstructionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.len == 0 {
+            return write!(f, "unreadable");
+        }
+
+        for (i, byte) in self.bytes[..self.len].iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Exception flags, in the same bits of the x87 status word and MXCSR
+struct FloatExceptions(u32);
+
+const FLOAT_EXCEPTIONS: [&str; 6] = [
+    "invalid operation",
+    "denormal operand",
+    "divide by zero",
+    "overflow",
+    "underflow",
+    "precision",
+];
+
+impl fmt::Display for FloatExceptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+
+        for (bit, name) in FLOAT_EXCEPTIONS.iter().enumerate() {
+            if self.0 & (1 << bit) != 0 {
+                write!(f, "{}{}", if first { "" } else { ", " }, name)?;
+
+                first = false;
+            }
+        }
+
+        if first {
+            write!(f, "none")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn divide_error(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "DIVIDE ERROR",
+        signal::SIGFPE,
+        format_args!("division by zero or quotient too large"),
+    );
+}
+
+fn debug(frame: &mut TrapFrame) {
+    let dr6 = unsafe { read_dr6() };
+
+    // The kernel sets no breakpoint and never single steps
+    fault(
+        frame,
+        "DEBUG",
+        signal::SIGTRAP,
+        format_args!(
+            "DR6 {:#x}, breakpoints {:#06b}, single step: {}",
+            dr6,
+            dr6 & 0xf,
+            dr6 & DR6_SINGLE_STEP != 0
+        ),
+    );
+}
+
+fn nmi(frame: &mut TrapFrame) {
+    let status: u8 = unsafe { Port::new(NMI_STATUS_PORT).read() };
+
+    // A hardware failure, whatever was running
+    kernel_fault(
+        frame,
+        "NON MASKABLE INTERRUPT",
+        format_args!(
+            "memory parity error: {}, I/O channel check: {}",
+            status & NMI_MEMORY_PARITY != 0,
+            status & NMI_IO_CHANNEL_CHECK != 0
+        ),
+    );
+}
+
+fn breakpoint(frame: &mut TrapFrame) {
+    if frame.from_user() {
+        fault(frame, "BREAKPOINT", signal::SIGTRAP, format_args!("int3"));
+    }
+
+    serial_println!("INTERRUPT: Breakpoint:\n{}", frame);
+
+    println!("EXCEPTION: BREAKPOINT\n{}", frame);
+}
+
+fn overflow(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "OVERFLOW",
+        signal::SIGSEGV,
+        format_args!("INTO with the overflow flag set"),
+    );
+}
+
+fn bound_range_exceeded(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "BOUND RANGE EXCEEDED",
+        signal::SIGSEGV,
+        format_args!("BOUND index out of range"),
+    );
+}
+
+fn invalid_opcode(frame: &mut TrapFrame) {
+    let instruction = InstructionBytes::read(frame.instruction_pointer(), frame.from_user());
+
+    fault(
+        frame,
+        "INVALID OPCODE",
+        signal::SIGILL,
+        format_args!("instruction {}", instruction),
+    );
+}
+
+fn device_not_available(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "DEVICE NOT AVAILABLE",
+        signal::SIGFPE,
+        format_args!("x87 or SSE instruction with CR0 {:#x}", Cr0::read_raw()),
+    );
+}
+
+fn double_fault(frame: &mut TrapFrame) -> ! {
+    kernel_fault(
+        frame,
+        "DOUBLE FAULT",
+        format_args!("error code {:#x}", frame.error_code),
+    );
+}
+
+fn invalid_tss(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "INVALID TSS",
+        signal::SIGSEGV,
+        format_args!("{}", SelectorErrorCode(frame.error_code)),
+    );
+}
+
+fn segment_not_present(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "SEGMENT NOT PRESENT",
+        signal::SIGBUS,
+        format_args!("{}", SelectorErrorCode(frame.error_code)),
+    );
+}
+
+fn stack_segment_fault(frame: &mut TrapFrame) {
+    if frame.error_code == 0 {
+        fault(
+            frame,
+            "STACK SEGMENT FAULT",
+            signal::SIGBUS,
+            format_args!("non-canonical stack address"),
+        );
+    }
+
+    fault(
+        frame,
+        "STACK SEGMENT FAULT",
+        signal::SIGBUS,
+        format_args!("{}", SelectorErrorCode(frame.error_code)),
+    );
+}
+
+fn general_protection_fault(frame: &mut TrapFrame) {
+    let instruction = InstructionBytes::read(frame.instruction_pointer(), frame.from_user());
+
+    fault(
+        frame,
+        "GENERAL PROTECTION FAULT",
+        signal::SIGSEGV,
+        format_args!(
+            "{}, instruction {}",
+            SelectorErrorCode(frame.error_code),
+            instruction
+        ),
+    );
+}
+
+fn page_fault(frame: &mut TrapFrame) {
+    let addr = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+
+    // Write to a page shared with another address space since a fork
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && paging::is_user_addr(addr)
+        && paging::cow::copy_on_write(addr)
+    {
+        return;
+    }
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        // Userland only gets demand paging inside its own part of the address
+        // space, anything else kills the task instead of the kernel
+        if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+            && paging::is_user_addr(addr)
+        {
+            if process::demand_page(addr).is_err() {
+                serial_println!(
+                    "Process {} is over its resident page limit at {:?}",
+                    process::current_pid(),
+                    addr
+                );
+
+                signal::raise_exception(frame, signal::SIGSEGV);
+            }
+        } else {
+            fault(
+                frame,
+                "PAGE FAULT",
+                signal::SIGSEGV,
+                format_args!("{:?} accessing {:?}", error_code, addr),
+            );
+        }
+    } else if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        paging::helpers::alloc_page(addr);
+    } else {
+        kernel_fault(
+            frame,
+            "PAGE FAULT",
+            format_args!("{:?} accessing {:?}", error_code, addr),
+        );
+    }
+}
+
+fn x87_floating_point(frame: &mut TrapFrame) {
+    let status = unsafe { read_fpu_status() };
+
+    fault(
+        frame,
+        "X87 FLOATING POINT",
+        signal::SIGFPE,
+        format_args!(
+            "status word {:#06x}: {}{}",
+            status,
+            FloatExceptions(u32::from(status)),
+            if status & 0x40 != 0 {
+                ", stack fault"
+            } else {
+                ""
+            }
+        ),
+    );
+}
+
+fn alignment_check(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "ALIGNMENT CHECK",
+        signal::SIGBUS,
+        format_args!("unaligned access with alignment checking enabled"),
+    );
+}
+
+fn machine_check(frame: &mut TrapFrame) -> ! {
+    let (capabilities, status) = unsafe {
+        (
+            Msr::new(IA32_MCG_CAP).read(),
+            Msr::new(IA32_MCG_STATUS).read(),
+        )
+    };
+    let banks = (capabilities & 0xff) as u32;
+
+    for bank in 0..banks {
+        let bank_status = unsafe { Msr::new(IA32_MC0_STATUS + bank * 4).read() };
+
+        if bank_status & MCI_STATUS_VALID != 0 {
+            serial_println!(
+                "INTERRUPT: Machine check bank {}: {:#018x}",
+                bank,
+                bank_status
+            );
+        }
+    }
+
+    kernel_fault(
+        frame,
+        "MACHINE CHECK",
+        format_args!("MCG_STATUS {:#x}, {} banks", status, banks),
+    );
+}
+
+fn simd_floating_point(frame: &mut TrapFrame) {
+    let mxcsr = unsafe { read_mxcsr() };
+    // Only the unmasked exceptions raise the fault
+    let unmasked = mxcsr & !(mxcsr >> 7);
+
+    fault(
+        frame,
+        "SIMD FLOATING POINT",
+        signal::SIGFPE,
+        format_args!("MXCSR {:#06x}: {}", mxcsr, FloatExceptions(unmasked & 0x3f)),
+    );
+}
+
+fn virtualization(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "VIRTUALIZATION",
+        signal::SIGSEGV,
+        format_args!("EPT violation outside of a guest"),
+    );
+}
+
+fn security_exception(frame: &mut TrapFrame) {
+    fault(
+        frame,
+        "SECURITY EXCEPTION",
+        signal::SIGSEGV,
+        format_args!("error code {:#x}", frame.error_code),
+    );
+}
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_breakpoint_exception() {
+    serial_print!("test_breakpoint_exception...");
+    x86_64::instructions::interrupts::int3();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_exception_decoding() {
+    use alloc::format;
+
+    serial_print!("test_exception_decoding... ");
+
+    assert_eq!(format!("{}", SelectorErrorCode(0)), "no selector");
+    assert_eq!(format!("{}", SelectorErrorCode(0x28)), "GDT selector 0x28");
+    assert_eq!(
+        format!("{}", SelectorErrorCode((0x80 << 3) | 0b011)),
+        "IDT vector 128 (external event)"
+    );
+    assert_eq!(
+        format!("{}", FloatExceptions(0b000101)),
+        "invalid operation, divide by zero"
+    );
+    assert_eq!(format!("{}", FloatExceptions(0)), "none");
+
+    // ud2 followed by nops, read from a kernel page
+    static CODE: [u8; 4] = [0x0f, 0x0b, 0x90, 0x90];
+    let instruction = InstructionBytes::read(VirtAddr::from_ptr(&CODE), false);
+
+    assert!(format!("{}", instruction).starts_with("0f 0b 90 90"));
+    assert_eq!(
+        format!(
+            "{}",
+            InstructionBytes::read(VirtAddr::from_ptr(&CODE), true)
+        ),
+        "unreadable"
+    );
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,198 @@
+//! I/O APICs: route the device interrupts to the local APICs.
+//!
+//! Each I/O APIC handles a range of global system interrupts, one per input.
+//! The ISA interrupts are routed at startup, masked until a handler is
+//! registered. The other inputs stay masked until they are routed.
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+
+use super::irq::ISA_IRQ_COUNT;
+use crate::acpi::madt::{self, Madt};
+use crate::memory::paging::helpers;
+use crate::serial_println;
+
+// Registers are reached through a select register and a window
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        core::ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        core::ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+    }
+
+    fn input(&self, gsi: u32) -> Option<u32> {
+        if gsi >= self.gsi_base && gsi - self.gsi_base < self.inputs {
+            Some(gsi - self.gsi_base)
+        } else {
+            None
+        }
+    }
+
+    fn entry(&self, input: u32) -> u64 {
+        let reg = REG_REDIRECTION + input * 2;
+
+        unsafe { u64::from(self.read(reg)) | (u64::from(self.read(reg + 1)) << 32) }
+    }
+
+    /// The high half holds the destination, written while the entry may still
+    /// be masked
+    fn set_entry(&self, input: u32, entry: u64) {
+        let reg = REG_REDIRECTION + input * 2;
+
+        unsafe {
+            self.write(reg + 1, (entry >> 32) as u32);
+            self.write(reg, entry as u32);
+        }
+    }
+}
+
+lazy_static! {
+    static ref IO_APICS: Mutex<Vec<IoApic>> = { Mutex::new(Vec::new()) };
+}
+
+/// Input of each ISA interrupt, once routed
+static ISA_GSIS: Mutex<[Option<u32>; ISA_IRQ_COUNT]> = Mutex::new([None; ISA_IRQ_COUNT]);
+
+fn with_io_apics<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Vec<IoApic>) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut IO_APICS.lock()))
+}
+
+/// Maps the I/O APICs of the MADT, masks all their inputs and routes the ISA
+/// interrupts to `vector_base` and next on the local APIC `apic_id`
+pub fn init(madt: &Madt, vector_base: u8, apic_id: u8) {
+    for entry in &madt.io_apics {
+        helpers::identity_map_device(PhysAddr::new(entry.address), 4096);
+
+        let mut io_apic = IoApic {
+            base: entry.address,
+            gsi_base: entry.gsi_base,
+            inputs: 0,
+        };
+
+        io_apic.inputs = unsafe { (io_apic.read(REG_VERSION) >> 16) & 0xff } + 1;
+
+        for input in 0..io_apic.inputs {
+            io_apic.set_entry(input, ENTRY_MASKED);
+        }
+
+        serial_println!(
+            "   I/O APIC {} at {:#x}, GSI {} to {}",
+            entry.id,
+            entry.address,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.inputs - 1
+        );
+
+        with_io_apics(|io_apics| io_apics.push(io_apic));
+    }
+
+    for irq in 0..ISA_IRQ_COUNT as u8 {
+        let (gsi, flags) = madt.legacy_irq(irq);
+
+        // The input of an ISA interrupt can be taken over by another one,
+        // the timer usually gets the input 2 of the cascade
+        if madt
+            .overrides
+            .iter()
+            .any(|entry| entry.irq != irq && entry.gsi == gsi)
+        {
+            continue;
+        }
+
+        if set_route(
+            gsi,
+            redirection_entry(flags, vector_base + irq, apic_id) | ENTRY_MASKED,
+        ) {
+            interrupts::without_interrupts(|| ISA_GSIS.lock()[irq as usize] = Some(gsi));
+        } else {
+            serial_println!("   No I/O APIC input for IRQ {}", irq);
+        }
+    }
+}
+
+fn redirection_entry(flags: u16, vector: u8, apic_id: u8) -> u64 {
+    let mut entry = u64::from(vector) | (u64::from(apic_id) << 56);
+
+    if madt::active_low(flags) {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+
+    if madt::level_triggered(flags) {
+        entry |= ENTRY_LEVEL_TRIGGERED;
+    }
+
+    entry
+}
+
+fn set_route(gsi: u32, entry: u64) -> bool {
+    with_io_apics(|io_apics| {
+        for io_apic in io_apics.iter() {
+            if let Some(input) = io_apic.input(gsi) {
+                io_apic.set_entry(input, entry);
+
+                return true;
+            }
+        }
+
+        false
+    })
+}
+
+/// Delivers the global system interrupt `gsi` as `vector` to the local APIC
+/// `apic_id`. `flags` are the polarity and trigger mode, as in the MADT.
+/// Returns false if no I/O APIC handles `gsi`.
+pub fn route(gsi: u32, flags: u16, vector: u8, apic_id: u8) -> bool {
+    set_route(gsi, redirection_entry(flags, vector, apic_id))
+}
+
+/// Stops or resumes the delivery of `gsi`, keeping its route
+pub fn set_masked(gsi: u32, masked: bool) {
+    with_io_apics(|io_apics| {
+        for io_apic in io_apics.iter() {
+            if let Some(input) = io_apic.input(gsi) {
+                let entry = io_apic.entry(input);
+
+                if masked {
+                    io_apic.set_entry(input, entry | ENTRY_MASKED);
+                } else {
+                    io_apic.set_entry(input, entry & !ENTRY_MASKED);
+                }
+            }
+        }
+    });
+}
+
+/// Masks or unmasks the input of the ISA interrupt `irq`
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let gsi = interrupts::without_interrupts(|| ISA_GSIS.lock()[irq as usize]);
+
+    if let Some(gsi) = gsi {
+        set_masked(gsi, masked);
+    }
+}
@@ -0,0 +1,317 @@
+//! Handlers of the ISA interrupts, registered at runtime by the drivers.
+//!
+//! An interrupt line can be shared by several devices: every handler of the
+//! line is called and reports whether its device raised the interrupt. The
+//! line is unmasked while it has a handler, and the interrupt is acknowledged
+//! before the handlers run, a handler may switch tasks.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+use super::pic::{self, PIC_1_OFFSET};
+use super::{apic, ioapic};
+use crate::serial_println;
+use crate::smp::cpu::KernelGs;
+use crate::syscall::Errno;
+
+pub const ISA_IRQ_COUNT: usize = 16;
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+pub const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_COM1: u8 = 4;
+pub const IRQ_RTC: u8 = 8;
+
+/// Called with the number of the interrupt, returns true if its device
+/// raised it
+pub type IrqHandler = fn(irq: u8, stack_frame: &mut InterruptStackFrame) -> bool;
+
+/// Counters of an interrupt line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IrqStats {
+    pub handlers: usize,
+    pub raised: u64,
+    /// Raised but claimed by no handler
+    pub unhandled: u64,
+    /// Raised by the PIC for an interrupt that vanished
+    pub spurious: u64,
+}
+
+#[derive(Clone, Copy)]
+struct IrqLine {
+    handlers: [Option<IrqHandler>; MAX_SHARED_HANDLERS],
+    raised: u64,
+    unhandled: u64,
+    spurious: u64,
+}
+
+impl IrqLine {
+    const EMPTY: Self = Self {
+        handlers: [None; MAX_SHARED_HANDLERS],
+        raised: 0,
+        unhandled: 0,
+        spurious: 0,
+    };
+
+    fn handler_count(&self) -> usize {
+        self.handlers
+            .iter()
+            .filter(|handler| handler.is_some())
+            .count()
+    }
+}
+
+static LINES: Mutex<[IrqLine; ISA_IRQ_COUNT]> = Mutex::new([IrqLine::EMPTY; ISA_IRQ_COUNT]);
+
+fn with_lines<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut [IrqLine; ISA_IRQ_COUNT]) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut LINES.lock()))
+}
+
+/// Compared by address, function pointers with references have no `PartialEq`
+fn same_handler(a: IrqHandler, b: IrqHandler) -> bool {
+    a as usize == b as usize
+}
+
+/// Masks or unmasks `irq` on the controller that delivers it
+fn set_masked(irq: u8, masked: bool) {
+    if apic::is_enabled() {
+        ioapic::set_irq_masked(irq, masked);
+    } else {
+        pic::set_masked(irq, masked);
+    }
+}
+
+fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        pic::end_of_interrupt(irq);
+    }
+}
+
+/// Adds `handler` to the handlers of `irq` and unmasks it.
+/// Fails with `EEXIST` if it is already registered, and with `EBUSY` if
+/// the line has too many handlers.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), Errno> {
+    if usize::from(irq) >= ISA_IRQ_COUNT {
+        return Err(Errno::EINVAL);
+    }
+
+    with_lines(|lines| {
+        let line = &mut lines[usize::from(irq)];
+
+        if line
+            .handlers
+            .iter()
+            .flatten()
+            .any(|&registered| same_handler(registered, handler))
+        {
+            return Err(Errno::EEXIST);
+        }
+
+        let slot = line
+            .handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(Errno::EBUSY)?;
+
+        *slot = Some(handler);
+
+        if line.handler_count() == 1 {
+            set_masked(irq, false);
+        }
+
+        Ok(())
+    })
+}
+
+/// Removes `handler` from the handlers of `irq`, masking it after the last
+/// one. Fails with `ENOENT` if it is not registered.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), Errno> {
+    if usize::from(irq) >= ISA_IRQ_COUNT {
+        return Err(Errno::EINVAL);
+    }
+
+    with_lines(|lines| {
+        let line = &mut lines[usize::from(irq)];
+
+        let slot = line
+            .handlers
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |registered| same_handler(registered, handler)))
+            .ok_or(Errno::ENOENT)?;
+
+        *slot = None;
+
+        if line.handler_count() == 0 {
+            set_masked(irq, true);
+        }
+
+        Ok(())
+    })
+}
+
+/// Unmasks the lines with handlers, after a change of controller
+pub fn apply_masks() {
+    with_lines(|lines| {
+        for (irq, line) in lines.iter().enumerate() {
+            set_masked(irq as u8, line.handler_count() == 0);
+        }
+    });
+}
+
+pub fn stats(irq: u8) -> IrqStats {
+    with_lines(|lines| {
+        let line = &lines[usize::from(irq)];
+
+        IrqStats {
+            handlers: line.handler_count(),
+            raised: line.raised,
+            unhandled: line.unhandled,
+            spurious: line.spurious,
+        }
+    })
+}
+
+/// Prints the counters of the lines that were used, like `/proc/interrupts`
+pub fn print_irqs() {
+    serial_println!(
+        "{:>3} {:>8} {:>10} {:>10} {:>10}",
+        "IRQ",
+        "HANDLERS",
+        "RAISED",
+        "UNHANDLED",
+        "SPURIOUS"
+    );
+
+    for irq in 0..ISA_IRQ_COUNT as u8 {
+        let stats = stats(irq);
+
+        if stats == IrqStats::default() {
+            continue;
+        }
+
+        serial_println!(
+            "{:>3} {:>8} {:>10} {:>10} {:>10}",
+            irq,
+            stats.handlers,
+            stats.raised,
+            stats.unhandled,
+            stats.spurious
+        );
+    }
+}
+
+fn dispatch(irq: u8, stack_frame: &mut InterruptStackFrame) {
+    if !apic::is_enabled() && pic::is_spurious(irq) {
+        with_lines(|lines| lines[usize::from(irq)].spurious += 1);
+
+        return;
+    }
+
+    end_of_interrupt(irq);
+
+    // Copied, the lock must not be held while a handler switches tasks
+    let handlers = with_lines(|lines| {
+        let line = &mut lines[usize::from(irq)];
+
+        line.raised += 1;
+        line.handlers
+    });
+
+    let mut handled = false;
+
+    for handler in handlers.iter().flatten() {
+        handled |= handler(irq, stack_frame);
+    }
+
+    if !handled {
+        with_lines(|lines| lines[usize::from(irq)].unhandled += 1);
+    }
+}
+
+macro_rules! irq_entries {
+    ($($irq:expr => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: &mut InterruptStackFrame) {
+                let _gs = KernelGs::enter(stack_frame);
+
+                dispatch($irq, stack_frame);
+            }
+        )*
+
+        const ENTRIES: [HandlerFunc; ISA_IRQ_COUNT] = [$($name),*];
+    };
+}
+
+irq_entries! {
+    0 => irq0_entry,
+    1 => irq1_entry,
+    2 => irq2_entry,
+    3 => irq3_entry,
+    4 => irq4_entry,
+    5 => irq5_entry,
+    6 => irq6_entry,
+    7 => irq7_entry,
+    8 => irq8_entry,
+    9 => irq9_entry,
+    10 => irq10_entry,
+    11 => irq11_entry,
+    12 => irq12_entry,
+    13 => irq13_entry,
+    14 => irq14_entry,
+    15 => irq15_entry,
+}
+
+/// Installs the entries of the ISA interrupts, at the same vectors with the
+/// PIC and the I/O APIC
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    for (irq, &entry) in ENTRIES.iter().enumerate() {
+        idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(entry);
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_irq_registry() {
+    fn first(_irq: u8, _stack_frame: &mut InterruptStackFrame) -> bool {
+        false
+    }
+
+    fn second(_irq: u8, _stack_frame: &mut InterruptStackFrame) -> bool {
+        false
+    }
+
+    serial_print!("test_irq_registry... ");
+
+    // Nothing is wired to the IRQ 5 of the machines the tests run on
+    let irq = 5;
+
+    assert_eq!(register_irq(16, first), Err(Errno::EINVAL));
+    assert_eq!(unregister_irq(irq, first), Err(Errno::ENOENT));
+
+    register_irq(irq, first).unwrap();
+    register_irq(irq, second).unwrap();
+
+    assert_eq!(register_irq(irq, first), Err(Errno::EEXIST));
+    assert_eq!(stats(irq).handlers, 2);
+
+    unregister_irq(irq, first).unwrap();
+
+    assert_eq!(unregister_irq(irq, first), Err(Errno::ENOENT));
+    assert_eq!(stats(irq).handlers, 1);
+
+    unregister_irq(irq, second).unwrap();
+
+    assert_eq!(stats(irq).handlers, 0);
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,116 @@
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::PrivilegeLevel;
+
+use crate::acpi::madt::Madt;
+use crate::serial_println;
+use crate::smp::{cpu::KernelGs, ipi};
+use crate::syscall;
+
+pub mod apic;
+pub mod exceptions;
+pub mod ioapic;
+pub mod irq;
+pub mod pic;
+pub mod trap_frame;
+
+pub use irq::{register_irq, unregister_irq};
+pub use pic::PIC_1_OFFSET;
+pub use trap_frame::TrapFrame;
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        serial_println!("   Create IDT:");
+
+        let mut idt = InterruptDescriptorTable::new();
+
+        serial_println!("       Set exception handlers");
+
+        exceptions::set_handlers(&mut idt);
+
+        serial_println!("       Set IRQ handlers");
+
+        irq::set_handlers(&mut idt);
+
+        serial_println!("       Set APIC handlers");
+
+        idt[usize::from(apic::TIMER_VECTOR)].set_handler_fn(crate::time::apic_timer_handler);
+        idt[usize::from(apic::ERROR_VECTOR)].set_handler_fn(apic_error_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+
+        serial_println!("       Set IPI handlers");
+
+        idt[usize::from(apic::RESCHEDULE_VECTOR)].set_handler_fn(ipi::reschedule_handler);
+        idt[usize::from(apic::CALL_FUNCTION_VECTOR)].set_handler_fn(ipi::call_function_handler);
+
+        serial_println!("       Set Syscall gate");
+
+        idt[syscall::SYSCALL_VECTOR]
+            .set_handler_fn(syscall::int80_handler())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+
+        idt
+    };
+}
+pub fn init_idt() {
+    IDT.load();
+
+    // Remapped even when the APIC replaces it, its spurious interrupts must
+    // not look like exceptions
+    serial_println!("       Init PICS");
+    pic::init();
+}
+
+/// Delivers the interrupts through the APICs instead of the PIC, when the
+/// processor has a local APIC and the ACPI tables describe an I/O APIC.
+/// Needs the heap and the ACPI tables.
+pub fn init_apic() {
+    let madt = match Madt::find() {
+        Some(madt) if apic::is_supported() && !madt.io_apics.is_empty() => madt,
+        _ => {
+            serial_println!("   No APIC, keeping the 8259 PIC");
+
+            return;
+        }
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if madt.has_8259 {
+            pic::disable();
+        }
+
+        apic::init(&madt);
+
+        let apic_id = apic::id();
+
+        ioapic::init(&madt, PIC_1_OFFSET, apic_id);
+        irq::apply_masks();
+
+        serial_println!("   Local APIC {} enabled, PIC masked", apic_id);
+    });
+}
+
+/// Loads the IDT and enables the local APIC of an application processor.
+/// The I/O APIC keeps sending the IRQs to the boot processor.
+pub fn init_ap() {
+    IDT.load();
+
+    if let Some(madt) = Madt::find() {
+        apic::init(&madt);
+    }
+}
+
+/// Whether the interrupt came from userland
+pub fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 0x3 == u64::from(PrivilegeLevel::Ring3 as u8)
+}
+
+extern "x86-interrupt" fn apic_error_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+
+    serial_println!("INTERRUPT: APIC error {:#x}", apic::error_status());
+
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {}
@@ -0,0 +1,87 @@
+//! The two chained 8259 PICs, used when there is no APIC.
+
+use pic8259_simple::ChainedPics;
+use spin;
+use x86_64::instructions::port::Port;
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+
+const COMMAND_END_OF_INTERRUPT: u8 = 0x20;
+const COMMAND_READ_IN_SERVICE: u8 = 0x0b;
+
+// The slave PIC is wired to the IRQ 2 of the master
+const CASCADE_IRQ: u8 = 2;
+
+pub static PICS: spin::Mutex<ChainedPics> =
+    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Remaps the PICs after the exceptions, with all the IRQs masked
+pub fn init() {
+    unsafe { PICS.lock().initialize() };
+
+    disable();
+    set_masked(CASCADE_IRQ, false);
+}
+
+/// Masks every input of both PICs
+pub fn disable() {
+    unsafe {
+        Port::<u8>::new(PIC_1_DATA).write(0xff);
+        Port::<u8>::new(PIC_2_DATA).write(0xff);
+    }
+}
+
+pub fn set_masked(irq: u8, masked: bool) {
+    let (mut port, bit) = if irq < 8 {
+        (Port::<u8>::new(PIC_1_DATA), irq)
+    } else {
+        (Port::<u8>::new(PIC_2_DATA), irq - 8)
+    };
+
+    unsafe {
+        let mask = port.read();
+
+        if masked {
+            port.write(mask | (1 << bit));
+        } else {
+            port.write(mask & !(1 << bit));
+        }
+    }
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) };
+}
+
+/// The PICs raise IRQ 7 or 15 for an interrupt that vanished, without
+/// marking it in service. Only the master is acknowledged for a spurious
+/// IRQ 15, it did raise the cascade.
+pub fn is_spurious(irq: u8) -> bool {
+    let (command, bit) = match irq {
+        7 => (PIC_1_COMMAND, 7),
+        15 => (PIC_2_COMMAND, 7),
+        _ => return false,
+    };
+
+    unsafe {
+        let mut command = Port::<u8>::new(command);
+
+        command.write(COMMAND_READ_IN_SERVICE);
+
+        if command.read() & (1 << bit) != 0 {
+            return false;
+        }
+
+        if irq == 15 {
+            Port::<u8>::new(PIC_1_COMMAND).write(COMMAND_END_OF_INTERRUPT);
+        }
+    }
+
+    true
+}
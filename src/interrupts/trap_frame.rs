@@ -0,0 +1,154 @@
+use core::fmt;
+use x86_64::registers::control::{Cr0, Cr2, Cr3};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+use crate::syscall::SyscallFrame;
+
+extern "C" {
+    fn read_cr4() -> u64;
+}
+
+/// CPU state saved by the exception entry stubs: the general purpose
+/// registers in push order reversed, then the vector, the error code and the
+/// interrupt frame pushed by the CPU.
+/// Any modification is restored when the handler returns.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for the exceptions without one
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn from_user(&self) -> bool {
+        self.cs & 0x3 == u64::from(PrivilegeLevel::Ring3 as u8)
+    }
+
+    pub fn instruction_pointer(&self) -> VirtAddr {
+        VirtAddr::new(self.rip)
+    }
+}
+
+/// The registers a syscall would have saved, rcx and r11 are lost
+impl From<&TrapFrame> for SyscallFrame {
+    fn from(frame: &TrapFrame) -> Self {
+        Self {
+            r15: frame.r15,
+            r14: frame.r14,
+            r13: frame.r13,
+            r12: frame.r12,
+            rbp: frame.rbp,
+            rbx: frame.rbx,
+            r9: frame.r9,
+            r8: frame.r8,
+            r10: frame.r10,
+            rdx: frame.rdx,
+            rsi: frame.rsi,
+            rdi: frame.rdi,
+            rax: frame.rax,
+            rip: frame.rip,
+            rflags: frame.rflags,
+            rsp: frame.rsp,
+        }
+    }
+}
+
+/// Register dump, with the control registers read when it is printed
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX {:#018x}  RBX {:#018x}  RCX {:#018x}",
+            self.rax, self.rbx, self.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX {:#018x}  RSI {:#018x}  RDI {:#018x}",
+            self.rdx, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP {:#018x}  R8  {:#018x}  R9  {:#018x}",
+            self.rbp, self.r8, self.r9
+        )?;
+        writeln!(
+            f,
+            "R10 {:#018x}  R11 {:#018x}  R12 {:#018x}",
+            self.r10, self.r11, self.r12
+        )?;
+        writeln!(
+            f,
+            "R13 {:#018x}  R14 {:#018x}  R15 {:#018x}",
+            self.r13, self.r14, self.r15
+        )?;
+        writeln!(
+            f,
+            "RIP {:#018x}  CS {:#06x}  RFLAGS {:#018x}",
+            self.rip, self.cs, self.rflags
+        )?;
+        writeln!(
+            f,
+            "RSP {:#018x}  SS {:#06x}  vector {}  error code {:#x}",
+            self.rsp, self.ss, self.vector, self.error_code
+        )?;
+        write!(
+            f,
+            "CR0 {:#018x}  CR2 {:#018x}  CR3 {:#018x}  CR4 {:#018x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            unsafe { read_cr4() }
+        )
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_trap_frame() {
+    use alloc::format;
+
+    serial_print!("test_trap_frame... ");
+
+    let frame = TrapFrame {
+        rbx: 0x1234,
+        r11: 0x5678,
+        r12: 0x9abc,
+        rip: 0x80_0000_1000,
+        cs: 0x23,
+        ..TrapFrame::default()
+    };
+    let syscall_frame = SyscallFrame::from(&frame);
+
+    assert!(frame.from_user());
+    assert_eq!(syscall_frame.rbx, 0x1234);
+    assert_eq!(syscall_frame.r12, 0x9abc);
+    assert_eq!(syscall_frame.rip, 0x80_0000_1000);
+    assert!(format!("{}", frame).contains("R11 0x0000000000005678"));
+
+    serial_println!("[ok]");
+}
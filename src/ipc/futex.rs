@@ -0,0 +1,176 @@
+//! Futexes: waiting on a 32 bits word of user memory.
+//!
+//! Userland takes its locks with atomic operations and only calls the kernel
+//! to sleep when a lock is contended, or to wake up the sleepers. A futex is
+//! identified by the physical address of the word, every mapping of the same
+//! memory reaches the same futex.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use crate::memory::paging::helpers;
+use crate::schedule::{self, WaitQueue};
+use crate::syscall::{user_ptr, Errno};
+
+lazy_static! {
+    /// Futexes with sleepers, by physical address
+    static ref FUTEXES: Mutex<BTreeMap<u64, Arc<WaitQueue>>> = { Mutex::new(BTreeMap::new()) };
+}
+
+fn with_futexes<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BTreeMap<u64, Arc<WaitQueue>>) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut FUTEXES.lock()))
+}
+
+/// Physical address of the word at `addr` in the current address space
+fn key(addr: u64) -> Result<u64, Errno> {
+    if addr % 4 != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    // Checked for writing so that a copy-on-write page gets its own frame
+    // first, a futex in private memory is not shared with a forked process
+    user_ptr::check_range(addr, 4, true)?;
+
+    Ok(helpers::translate_addr(VirtAddr::new(addr)).as_u64())
+}
+
+/// Sleeps until a `wake` on `addr` if the word there still holds `expected`,
+/// otherwise fails with `EAGAIN`. Gives up with `ETIMEDOUT` after
+/// `timeout` ticks.
+///
+/// The word is checked with interrupts disabled: a wake issued after the
+/// waker changed the word cannot be missed.
+pub fn wait(addr: u64, expected: u32, timeout: Option<u64>) -> Result<(), Errno> {
+    wait_key(key(addr)?, addr as *const u32, expected, timeout)
+}
+
+fn wait_key(key: u64, word: *const u32, expected: u32, timeout: Option<u64>) -> Result<(), Errno> {
+    let queue = with_futexes(|futexes| {
+        futexes
+            .entry(key)
+            .or_insert_with(|| Arc::new(WaitQueue::new()))
+            .clone()
+    });
+
+    let mut checked = false;
+    let mut changed = false;
+
+    // Only the first evaluation checks the word, the next ones follow a wake
+    let mut condition = || {
+        if checked {
+            return true;
+        }
+
+        checked = true;
+        changed = unsafe { core::ptr::read_volatile(word) } != expected;
+
+        changed
+    };
+
+    let woken = match timeout {
+        Some(ticks) => {
+            queue.wait_until_deadline(&mut condition, schedule::ticks().saturating_add(ticks))
+        }
+        None => {
+            queue.wait_until(&mut condition);
+
+            true
+        }
+    };
+
+    // Forget the futex when nobody else uses it
+    with_futexes(|futexes| {
+        if Arc::strong_count(&queue) == 2 && queue.is_empty() {
+            futexes.remove(&key);
+        }
+    });
+
+    if changed {
+        Err(Errno::EAGAIN)
+    } else if !woken {
+        Err(Errno::ETIMEDOUT)
+    } else {
+        Ok(())
+    }
+}
+
+/// Wakes up at most `count` tasks waiting on `addr`, returns how many
+pub fn wake(addr: u64, count: usize) -> Result<usize, Errno> {
+    Ok(wake_key(key(addr)?, count))
+}
+
+fn wake_key(key: u64, count: usize) -> usize {
+    let queue = match with_futexes(|futexes| futexes.get(&key).cloned()) {
+        Some(queue) => queue,
+        None => return 0,
+    };
+
+    let mut woken = 0;
+
+    while woken < count && queue.wake_one() {
+        woken += 1;
+    }
+
+    woken
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_futex_ping_pong() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    serial_print!("test_futex_ping_pong... ");
+
+    // The tests run in the kernel process, a kernel word stands for the user
+    // one
+    static WORD: AtomicU32 = AtomicU32::new(0);
+    const ROUNDS: u32 = 200;
+
+    let word = &WORD as *const AtomicU32 as *const u32;
+    let key = helpers::translate_addr(VirtAddr::new(word as u64)).as_u64();
+
+    assert_eq!(wait_key(key, word, 1, None), Err(Errno::EAGAIN));
+    assert_eq!(wait_key(key, word, 0, Some(2)), Err(Errno::ETIMEDOUT));
+
+    // Each side flips the word and wakes the other one. A wake-up lost
+    // between the check of the word and the sleep hangs the test.
+    schedule::spawn(move || {
+        let word = &WORD as *const AtomicU32 as *const u32;
+
+        for round in 0..ROUNDS {
+            while WORD.load(Ordering::SeqCst) == round * 2 {
+                let _ = wait_key(key, word, round * 2, None);
+            }
+
+            WORD.store(round * 2 + 2, Ordering::SeqCst);
+            wake_key(key, 1);
+        }
+    });
+
+    for round in 0..ROUNDS {
+        WORD.store(round * 2 + 1, Ordering::SeqCst);
+        wake_key(key, 1);
+
+        if round % 3 == 0 {
+            schedule::yield_now();
+        }
+
+        while WORD.load(Ordering::SeqCst) == round * 2 + 1 {
+            let _ = wait_key(key, word, round * 2 + 1, None);
+        }
+    }
+
+    assert_eq!(WORD.load(Ordering::SeqCst), ROUNDS * 2);
+    assert_eq!(wake_key(key, 1), 0);
+
+    serial_println!("[ok]");
+}
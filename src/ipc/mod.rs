@@ -0,0 +1,5 @@
+//! Communication between processes
+
+pub mod futex;
+pub mod pipe;
+pub mod port;
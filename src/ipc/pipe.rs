@@ -0,0 +1,255 @@
+//! Anonymous pipes: a bounded byte stream with a read end and a write end.
+//!
+//! Each end is a `File`, shared between processes through their file
+//! tables. An end is closed when its last descriptor goes away: readers then
+//! see the end of file, writers get `EPIPE` and a SIGPIPE.
+
+use alloc::{boxed::Box, sync::Arc, vec};
+use core::cmp::min;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::process::{self, signal, File};
+use crate::schedule::WaitQueue;
+use crate::syscall::Errno;
+
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// Writes up to this size are never interleaved with other writes
+pub const PIPE_BUF: usize = 512;
+
+struct RingBuffer {
+    data: Box<[u8]>,
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            data: vec![0; capacity].into_boxed_slice(),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn free(&self) -> usize {
+        self.data.len() - self.len
+    }
+
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let count = min(buf.len(), self.len);
+
+        for byte in buf.iter_mut().take(count) {
+            *byte = self.data[self.head];
+            self.head = (self.head + 1) % self.data.len();
+        }
+
+        self.len -= count;
+
+        count
+    }
+
+    fn push(&mut self, buf: &[u8]) -> usize {
+        let count = min(buf.len(), self.free());
+
+        for &byte in buf.iter().take(count) {
+            let tail = (self.head + self.len) % self.data.len();
+
+            self.data[tail] = byte;
+            self.len += 1;
+        }
+
+        count
+    }
+}
+
+struct PipeState {
+    buffer: RingBuffer,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+/// Shared by both ends. The state is only locked with interrupts disabled,
+/// it is checked by the wait queue conditions.
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+impl Pipe {
+    fn with_state<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut PipeState) -> R,
+    {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+}
+
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+/// Creates a pipe and returns its read and write ends
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buffer: RingBuffer::new(PIPE_CAPACITY),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl File for PipeReader {
+    /// Blocks until some data is available. Returns 0 at the end of file,
+    /// once the buffer is empty and the write end closed.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut read = 0;
+
+        self.pipe.readable.wait_until(|| {
+            self.pipe.with_state(|state| {
+                read = state.buffer.pop(buf);
+
+                read > 0 || !state.writer_open
+            })
+        });
+
+        if read > 0 {
+            self.pipe.writable.wake_all();
+        }
+
+        Ok(read)
+    }
+}
+
+impl File for PipeWriter {
+    /// Blocks until everything is written. Fails with `EPIPE` once the read
+    /// end is closed, unless part of the data was already written.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let mut written = 0;
+        let mut broken = false;
+
+        // Small writes wait for room for all of it, to stay in one piece
+        let atomic = buf.len() <= PIPE_BUF;
+
+        while written < buf.len() && !broken {
+            let remaining = &buf[written..];
+
+            self.pipe.writable.wait_until(|| {
+                self.pipe.with_state(|state| {
+                    if !state.reader_open {
+                        broken = true;
+
+                        return true;
+                    }
+
+                    if atomic && state.buffer.free() < remaining.len() {
+                        return false;
+                    }
+
+                    let count = state.buffer.push(remaining);
+
+                    written += count;
+
+                    count > 0
+                })
+            });
+
+            self.pipe.readable.wake_all();
+        }
+
+        if broken && written == 0 {
+            // Processes that do not check the errors are stopped there
+            let _ = signal::send(process::current_pid(), u64::from(signal::SIGPIPE));
+
+            return Err(Errno::EPIPE);
+        }
+
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.with_state(|state| state.reader_open = false);
+        self.pipe.writable.wake_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.with_state(|state| state.writer_open = false);
+        self.pipe.readable.wake_all();
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_pipe_blocking_and_eof() {
+    use crate::schedule;
+
+    serial_print!("test_pipe_blocking_and_eof... ");
+
+    let (reader, writer) = pipe();
+
+    // More than the capacity, the writer has to wait for the reader
+    const TOTAL: usize = PIPE_CAPACITY * 3 + 100;
+
+    schedule::spawn(move || {
+        let data: alloc::vec::Vec<u8> = (0..TOTAL).map(|i| i as u8).collect();
+
+        assert_eq!(writer.write(&data), Ok(TOTAL));
+    });
+
+    let mut received = 0;
+    let mut buf = [0; 1000];
+
+    loop {
+        let count = reader.read(&mut buf).unwrap();
+
+        if count == 0 {
+            break;
+        }
+
+        for (i, &byte) in buf[..count].iter().enumerate() {
+            assert_eq!(byte, (received + i) as u8);
+        }
+
+        received += count;
+    }
+
+    assert_eq!(received, TOTAL);
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_pipe_broken() {
+    serial_print!("test_pipe_broken... ");
+
+    let (reader, writer) = pipe();
+
+    drop(reader);
+
+    assert_eq!(writer.write(b"lost"), Err(Errno::EPIPE));
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,453 @@
+//! Synchronous message passing through ports.
+//!
+//! A port is a bounded queue of small messages, copied in and out of the
+//! kernel. Processes reach ports through handles, indices in their own handle
+//! table holding a capability: the right to receive from a port (given to its
+//! creator), to send to it (given by a name lookup), or to answer a `call`
+//! once (given with the message that needs an answer).
+//!
+//! Senders hand the processor straight to a receiver blocked on the port, and
+//! a reply straight to the blocked caller, so a call/reply round trip does not
+//! wait behind the other ready tasks.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::cmp::min;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::process::{self, Pid, MAX_FILES};
+use crate::schedule::WaitQueue;
+use crate::syscall::Errno;
+
+pub const MAX_MESSAGE_SIZE: usize = 256;
+
+/// Messages a port holds before senders block
+pub const PORT_QUEUE_LEN: usize = 16;
+
+pub const MAX_PORT_NAME_LEN: u64 = 64;
+
+pub struct Message {
+    data: [u8; MAX_MESSAGE_SIZE],
+    len: usize,
+    pub sender: Pid,
+    /// Set when the sender waits for an answer
+    pub reply: Option<ReplyHandle>,
+}
+
+impl Message {
+    /// Copies `data` into a message from the current process
+    pub fn new(data: &[u8]) -> Result<Self, Errno> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(Errno::E2BIG);
+        }
+
+        let mut message = Self {
+            data: [0; MAX_MESSAGE_SIZE],
+            len: data.len(),
+            sender: process::current_pid(),
+            reply: None,
+        };
+
+        message.data[..data.len()].copy_from_slice(data);
+
+        Ok(message)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Copies the message into `buf` and returns its full length, the end is
+    /// lost if `buf` is too small
+    pub fn copy_to(&self, buf: &mut [u8]) -> usize {
+        let count = min(buf.len(), self.len);
+
+        buf[..count].copy_from_slice(&self.data[..count]);
+
+        self.len
+    }
+}
+
+struct PortState {
+    messages: VecDeque<Message>,
+    open: bool,
+}
+
+/// The state is only locked with interrupts disabled, it is checked by the
+/// wait queue conditions
+pub struct Port {
+    name: Option<String>,
+    state: Mutex<PortState>,
+    receivers: WaitQueue,
+    senders: WaitQueue,
+}
+
+impl Port {
+    fn with_state<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut PortState) -> R,
+    {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Queues `message`, blocking while the port is full.
+    /// Fails with `EPIPE` once the port is closed.
+    pub fn send(&self, message: Message) -> Result<(), Errno> {
+        let mut message = Some(message);
+        let mut open = true;
+
+        self.senders.wait_until(|| {
+            self.with_state(|state| {
+                open = state.open;
+
+                if open && state.messages.len() < PORT_QUEUE_LEN {
+                    // Never grows past the capacity reserved at creation
+                    state.messages.push_back(message.take().unwrap());
+                }
+
+                message.is_none() || !open
+            })
+        });
+
+        if !open {
+            return Err(Errno::EPIPE);
+        }
+
+        self.receivers.hand_off();
+
+        Ok(())
+    }
+
+    /// Sends `message` and waits for the answer.
+    /// Fails with `EPIPE` if the message is dropped without an answer.
+    pub fn call(&self, mut message: Message) -> Result<Message, Errno> {
+        let slot = Arc::new(ReplySlot {
+            state: Mutex::new(ReplyState::Waiting),
+            done: WaitQueue::new(),
+        });
+
+        message.reply = Some(ReplyHandle { slot: slot.clone() });
+
+        self.send(message)?;
+
+        let mut answer = None;
+
+        slot.done.wait_until(|| {
+            let mut state = slot.state.lock();
+
+            if let ReplyState::Waiting = *state {
+                return false;
+            }
+
+            // Nothing is expected on the slot anymore
+            answer = Some(core::mem::replace(&mut *state, ReplyState::Abandoned));
+
+            true
+        });
+
+        match answer {
+            Some(ReplyState::Replied(reply)) => Ok(reply),
+            _ => Err(Errno::EPIPE),
+        }
+    }
+
+    fn receive(&self) -> Result<Message, Errno> {
+        let mut message = None;
+
+        self.receivers.wait_until(|| {
+            self.with_state(|state| {
+                message = state.messages.pop_front();
+
+                message.is_some() || !state.open
+            })
+        });
+
+        let message = message.ok_or(Errno::EPIPE)?;
+
+        self.senders.wake_one();
+
+        Ok(message)
+    }
+
+    fn close(&self) {
+        let messages = self.with_state(|state| {
+            state.open = false;
+
+            core::mem::replace(&mut state.messages, VecDeque::new())
+        });
+
+        // Fails the pending calls
+        drop(messages);
+
+        if let Some(name) = &self.name {
+            with_names(|names| {
+                let registered = names
+                    .get(name)
+                    .and_then(Weak::upgrade)
+                    .map_or(true, |port| core::ptr::eq(&*port, self));
+
+                if registered {
+                    names.remove(name);
+                }
+            });
+        }
+
+        self.receivers.wake_all();
+        self.senders.wake_all();
+    }
+}
+
+/// Right to receive from a port, the port closes when it is dropped
+pub struct ReceiveRight {
+    port: Arc<Port>,
+}
+
+impl ReceiveRight {
+    /// Takes the next message, blocking until there is one
+    pub fn receive(&self) -> Result<Message, Errno> {
+        self.port.receive()
+    }
+
+    pub fn port(&self) -> &Arc<Port> {
+        &self.port
+    }
+}
+
+impl Drop for ReceiveRight {
+    fn drop(&mut self) {
+        self.port.close();
+    }
+}
+
+enum ReplyState {
+    Waiting,
+    Replied(Message),
+    Abandoned,
+}
+
+struct ReplySlot {
+    state: Mutex<ReplyState>,
+    done: WaitQueue,
+}
+
+/// Right to answer a call once. Dropping it unanswered fails the call.
+pub struct ReplyHandle {
+    slot: Arc<ReplySlot>,
+}
+
+impl ReplyHandle {
+    pub fn reply(&self, message: Message) -> Result<(), Errno> {
+        let answered = interrupts::without_interrupts(|| {
+            let mut state = self.slot.state.lock();
+
+            match *state {
+                ReplyState::Waiting => {
+                    *state = ReplyState::Replied(message);
+
+                    true
+                }
+                _ => false,
+            }
+        });
+
+        if !answered {
+            return Err(Errno::EINVAL);
+        }
+
+        self.slot.done.hand_off();
+
+        Ok(())
+    }
+}
+
+impl Drop for ReplyHandle {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.slot.state.lock();
+
+            if let ReplyState::Waiting = *state {
+                *state = ReplyState::Abandoned;
+            }
+        });
+
+        self.slot.done.wake_all();
+    }
+}
+
+lazy_static! {
+    /// Ports that can be looked up by name, until they close
+    static ref NAMES: Mutex<BTreeMap<String, Weak<Port>>> = { Mutex::new(BTreeMap::new()) };
+}
+
+fn with_names<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BTreeMap<String, Weak<Port>>) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut NAMES.lock()))
+}
+
+/// Creates a port, registered under `name` if there is one.
+/// Fails with `EEXIST` if the name is taken by an open port.
+pub fn create(name: Option<&str>) -> Result<ReceiveRight, Errno> {
+    let port = Arc::new(Port {
+        name: name.map(String::from),
+        state: Mutex::new(PortState {
+            messages: VecDeque::with_capacity(PORT_QUEUE_LEN),
+            open: true,
+        }),
+        receivers: WaitQueue::new(),
+        senders: WaitQueue::new(),
+    });
+
+    if let Some(name) = name {
+        with_names(|names| {
+            if names.get(name).and_then(Weak::upgrade).is_some() {
+                return Err(Errno::EEXIST);
+            }
+
+            names.insert(String::from(name), Arc::downgrade(&port));
+
+            Ok(())
+        })?;
+    }
+
+    Ok(ReceiveRight { port })
+}
+
+/// Finds the open port registered under `name`
+pub fn lookup(name: &str) -> Result<Arc<Port>, Errno> {
+    with_names(|names| names.get(name).and_then(Weak::upgrade)).ok_or(Errno::ENOENT)
+}
+
+#[derive(Clone)]
+pub enum Capability {
+    Receive(Arc<ReceiveRight>),
+    Send(Arc<Port>),
+    Reply(Arc<ReplyHandle>),
+}
+
+impl Capability {
+    /// Port messages can be sent to, the receive right includes sending
+    pub fn send_port(&self) -> Result<&Arc<Port>, Errno> {
+        match self {
+            Capability::Receive(right) => Ok(right.port()),
+            Capability::Send(port) => Ok(port),
+            Capability::Reply(_) => Err(Errno::EBADF),
+        }
+    }
+}
+
+/// Capabilities of a process, indexed by handle
+#[derive(Clone)]
+pub struct HandleTable {
+    handles: Vec<Option<Capability>>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self { handles: vec![] }
+    }
+
+    pub fn get(&self, handle: u64) -> Result<Capability, Errno> {
+        self.handles
+            .get(handle as usize)
+            .and_then(|capability| capability.clone())
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Number of handles in use
+    pub fn open_count(&self) -> usize {
+        self.handles.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Stores `capability` in the lowest free handle. Handles are limited
+    /// like the file descriptors, by `RLIMIT_NOFILE` up to `MAX_FILES`.
+    pub fn insert(&mut self, capability: Capability) -> Result<u64, Errno> {
+        if let Some(handle) = self.handles.iter().position(|slot| slot.is_none()) {
+            self.handles[handle] = Some(capability);
+
+            return Ok(handle as u64);
+        }
+
+        if self.handles.len() >= MAX_FILES {
+            return Err(Errno::EMFILE);
+        }
+
+        self.handles.push(Some(capability));
+
+        Ok((self.handles.len() - 1) as u64)
+    }
+
+    pub fn remove(&mut self, handle: u64) -> Result<Capability, Errno> {
+        self.handles
+            .get_mut(handle as usize)
+            .and_then(|capability| capability.take())
+            .ok_or(Errno::EBADF)
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_port_call_and_reply() {
+    use crate::schedule;
+
+    serial_print!("test_port_call_and_reply... ");
+
+    let server = create(Some("test.upper")).unwrap();
+
+    assert_eq!(create(Some("test.upper")).err(), Some(Errno::EEXIST));
+
+    const CALLS: usize = PORT_QUEUE_LEN * 2;
+
+    schedule::spawn(move || {
+        for _ in 0..CALLS {
+            let request = server.receive().unwrap();
+            let answer = Message::new(&request.data().to_ascii_uppercase()).unwrap();
+
+            request.reply.unwrap().reply(answer).unwrap();
+        }
+    });
+
+    let port = lookup("test.upper").unwrap();
+
+    for _ in 0..CALLS {
+        let answer = port.call(Message::new(b"ping").unwrap()).unwrap();
+
+        assert_eq!(answer.data(), b"PING");
+    }
+
+    assert_eq!(
+        Message::new(&[0; MAX_MESSAGE_SIZE + 1]).err(),
+        Some(Errno::E2BIG)
+    );
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_port_closed() {
+    serial_print!("test_port_closed... ");
+
+    let right = create(Some("test.closed")).unwrap();
+    let port = lookup("test.closed").unwrap();
+
+    port.send(Message::new(b"queued").unwrap()).unwrap();
+
+    drop(right);
+
+    assert_eq!(lookup("test.closed").err(), Some(Errno::ENOENT));
+    assert_eq!(port.send(Message::new(b"late").unwrap()), Err(Errno::EPIPE));
+
+    serial_println!("[ok]");
+}
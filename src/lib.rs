@@ -18,11 +18,21 @@ use core::panic::PanicInfo;
 #[no_mangle]
 pub extern "C" fn eh_personality() {}
 
+pub mod acpi;
+pub mod executor;
 pub mod gdt;
 pub mod interrupts;
+pub mod ipc;
+pub mod loader;
 pub mod memory;
+pub mod process;
 pub mod schedule;
 pub mod serial;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod time;
+pub mod usermode;
 pub mod vga_buffer;
 
 use memory::paging::page_tables::ActivePageTable;
@@ -44,16 +54,24 @@ pub fn hlt_loop() -> ! {
 pub fn init(multiboot_information_address: usize) {
     serial_println!("Kernel init");
 
+    serial_println!("Init CPU area");
+    smp::cpu::init();
+
     serial_println!("Init GDT:");
     gdt::init();
 
+    serial_println!("Init Syscalls:");
+    syscall::init();
+
     serial_println!("Init IDT:");
     interrupts::init_idt();
+    executor::keyboard::init();
 
     serial_println!("Enabling interrupts");
     x86_64::instructions::interrupts::enable();
 
     serial_println!("Init Paging");
+    memory::paging::enable_nxe_bit();
     let mut active_page_table = unsafe { ActivePageTable::new(multiboot_information_address) };
 
     serial_println!("Remap Kernel");
@@ -61,12 +79,28 @@ pub fn init(multiboot_information_address: usize) {
         &mut active_page_table,
         multiboot_information_address,
     );
+    memory::paging::init_active_table(active_page_table);
 
     serial_println!("Init Kernel Heap");
     memory::allocator::init_heap().expect("heap initialization failed");
 
+    serial_println!("Init ACPI:");
+    acpi::init();
+
+    serial_println!("Init APIC:");
+    interrupts::init_apic();
+
+    serial_println!("Init Timer:");
+    time::init();
+
+    serial_println!("Init initramfs:");
+    loader::initramfs::init(multiboot_information_address);
+
     serial_println!("Starting Schduler");
     schedule::init();
+
+    serial_println!("Init SMP:");
+    smp::init();
 }
 
 // tests
@@ -191,6 +225,14 @@ pub extern "C" fn _start(multiboot_information_address: usize) -> ! {
 
     init(multiboot_information_address);
 
+    // The first module is the init program, already loaded by the bootloader
+    if let Some(module) = boot_info.module_tags().next() {
+        match loader::spawn_module(module) {
+            Ok(pid) => serial_println!("Started module {} as process {}", module.name(), pid),
+            Err(err) => serial_println!("Cannot start module {}: {:?}", module.name(), err),
+        }
+    }
+
     use x86_64::{structures::paging::MapperAllSizes, VirtAddr};
 
     let addresses = [
@@ -218,6 +260,15 @@ pub extern "C" fn _start(multiboot_information_address: usize) -> ! {
 
     serial_println!("Kernel started.");
 
+    // Takes the scancode stream, the tests read it themselves
+    #[cfg(not(test))]
+    schedule::spawn(|| {
+        let mut executor = executor::Executor::new();
+
+        executor.spawn(executor::keyboard::print_keypresses());
+        executor.run();
+    });
+
     // use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 
     // // allocate a number on the heap
@@ -0,0 +1,179 @@
+//! Minimal ELF64 parser, only what is needed to load static executables
+
+use super::LoadError;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const HEADER_SIZE: usize = 64;
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    let mut buf = [0; 2];
+    buf.copy_from_slice(&bytes[offset..offset + 2]);
+
+    u16::from_le_bytes(buf)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+
+    u64::from_le_bytes(buf)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl ProgramHeader {
+    fn parse(bytes: &[u8]) -> Self {
+        Self {
+            kind: read_u32(bytes, 0),
+            flags: read_u32(bytes, 4),
+            offset: read_u64(bytes, 8),
+            vaddr: read_u64(bytes, 16),
+            file_size: read_u64(bytes, 32),
+            mem_size: read_u64(bytes, 40),
+        }
+    }
+}
+
+pub struct ElfFile<'a> {
+    pub data: &'a [u8],
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub program_header_count: u16,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the ELF header: a little endian x86_64 static executable
+    pub fn parse(data: &'a [u8]) -> Result<Self, LoadError> {
+        if data.len() < HEADER_SIZE || data[0..4] != ELF_MAGIC {
+            return Err(LoadError::NotElf);
+        }
+
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err(LoadError::Unsupported("not a little endian ELF64 file"));
+        }
+
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(LoadError::Unsupported("not an executable"));
+        }
+
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(LoadError::Unsupported("not an x86_64 file"));
+        }
+
+        let program_header_offset = read_u64(data, 32);
+        let program_header_size = read_u16(data, 54) as usize;
+        let program_header_count = read_u16(data, 56);
+
+        if program_header_size != PROGRAM_HEADER_SIZE {
+            return Err(LoadError::Malformed("bad program header size"));
+        }
+
+        let table_size = program_header_count as u64 * PROGRAM_HEADER_SIZE as u64;
+
+        match program_header_offset.checked_add(table_size) {
+            Some(end) if end <= data.len() as u64 => (),
+            _ => return Err(LoadError::Malformed("program headers out of file")),
+        }
+
+        Ok(Self {
+            data,
+            entry: read_u64(data, 24),
+            program_header_offset,
+            program_header_count,
+        })
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        let start = self.program_header_offset as usize;
+
+        (0..self.program_header_count as usize).map(move |index| {
+            let offset = start + index * PROGRAM_HEADER_SIZE;
+
+            ProgramHeader::parse(&self.data[offset..offset + PROGRAM_HEADER_SIZE])
+        })
+    }
+
+    /// Bytes of a segment present in the file
+    pub fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], LoadError> {
+        let end = header
+            .offset
+            .checked_add(header.file_size)
+            .ok_or(LoadError::Malformed("segment out of file"))?;
+
+        if end > self.data.len() as u64 {
+            return Err(LoadError::Malformed("segment out of file"));
+        }
+
+        Ok(&self.data[header.offset as usize..end as usize])
+    }
+
+    /// Virtual address of the program headers once loaded, if a segment
+    /// contains them
+    pub fn program_headers_vaddr(&self) -> Option<u64> {
+        let table_start = self.program_header_offset;
+        let table_end = table_start + self.program_header_count as u64 * PROGRAM_HEADER_SIZE as u64;
+
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+            .find(|header| {
+                header.offset <= table_start
+                    && table_end <= header.offset.saturating_add(header.file_size)
+            })
+            .map(|header| header.vaddr + (table_start - header.offset))
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_elf_rejects_bad_headers() {
+    serial_print!("test_elf_rejects_bad_headers... ");
+
+    let mut header = [0u8; HEADER_SIZE];
+
+    assert!(match ElfFile::parse(&header) {
+        Err(LoadError::NotElf) => true,
+        _ => false,
+    });
+
+    header[0..4].copy_from_slice(&ELF_MAGIC);
+    header[4] = 1;
+
+    assert!(match ElfFile::parse(&header) {
+        Err(LoadError::Unsupported(_)) => true,
+        _ => false,
+    });
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,40 @@
+//! Files handed to the kernel as multiboot modules, found by the command
+//! line given to the module in the GRUB configuration.
+
+use alloc::{collections::BTreeMap, string::String};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::module_data;
+use crate::serial_println;
+
+lazy_static! {
+    static ref FILES: Mutex<BTreeMap<String, &'static [u8]>> = { Mutex::new(BTreeMap::new()) };
+}
+
+/// Paths are looked up with or without a leading slash
+fn normalize(path: &str) -> &str {
+    path.trim_start_matches('/')
+}
+
+pub fn init(multiboot_information_address: usize) {
+    let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
+
+    for module in boot_info.module_tags() {
+        serial_println!(
+            "   Module {}: {} bytes",
+            module.name(),
+            module_data(module).len()
+        );
+
+        insert(module.name(), module_data(module));
+    }
+}
+
+pub fn insert(path: &str, data: &'static [u8]) {
+    FILES.lock().insert(String::from(normalize(path)), data);
+}
+
+pub fn find(path: &str) -> Option<&'static [u8]> {
+    FILES.lock().get(normalize(path)).cloned()
+}
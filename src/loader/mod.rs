@@ -0,0 +1,388 @@
+//! Loads static ELF64 executables in a new user address space
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::cmp::{max, min};
+use multiboot2::ModuleTag;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+use crate::memory::paging::{
+    address_space, helpers, AddressSpace, PAGE_SIZE, USER_SPACE_END, USER_SPACE_START,
+};
+use crate::process::{limits, Pid};
+use crate::syscall::Errno;
+use crate::usermode;
+
+pub mod elf;
+pub mod initramfs;
+mod stack;
+
+use elf::{ElfFile, ProgramHeader, PF_W, PF_X, PROGRAM_HEADER_SIZE, PT_LOAD};
+use stack::InitialStack;
+pub use stack::USER_STACK_TOP;
+
+#[derive(Debug)]
+pub enum LoadError {
+    NotElf,
+    Unsupported(&'static str),
+    Malformed(&'static str),
+    /// A segment or the entry point lies outside user space
+    BadAddress(u64),
+    TooManyArguments,
+    /// The image needs more pages than the resident page limit
+    TooManyPages,
+    /// No frame left to load the image
+    OutOfMemory,
+    /// The process could not be created
+    Spawn(Errno),
+}
+
+pub struct LoadedImage {
+    pub address_space: Arc<AddressSpace>,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/// Frames backing the user pages being loaded, indexed by page address
+type PageMap = BTreeMap<u64, (PhysFrame, PageTableFlags)>;
+
+fn check_user_range(start: u64, size: u64) -> Result<(), LoadError> {
+    match start.checked_add(size) {
+        Some(end) if start >= USER_SPACE_START && end <= USER_SPACE_END => Ok(()),
+        _ => Err(LoadError::BadAddress(start)),
+    }
+}
+
+fn segment_flags(header: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    if header.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+
+    if header.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    flags
+}
+
+/// Two segments sharing a page: the page gets the most permissive rights
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> PageTableFlags {
+    let executable =
+        !a.contains(PageTableFlags::NO_EXECUTE) || !b.contains(PageTableFlags::NO_EXECUTE);
+    let merged = a | b;
+
+    if executable {
+        merged - PageTableFlags::NO_EXECUTE
+    } else {
+        merged
+    }
+}
+
+/// Number of distinct pages covered by the regions `(vaddr, size)`
+fn page_count(regions: &[(u64, u64)]) -> u64 {
+    let mut ranges: Vec<(u64, u64)> = regions
+        .iter()
+        .filter(|&&(_, size)| size > 0)
+        .map(|&(vaddr, size)| {
+            let start = vaddr & !(PAGE_SIZE - 1);
+            let end = (vaddr + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+            (start, end)
+        })
+        .collect();
+
+    ranges.sort();
+
+    let mut count = 0;
+    let mut counted_end = 0;
+
+    for (start, end) in ranges {
+        let start = max(start, counted_end);
+
+        if end > start {
+            count += (end - start) / PAGE_SIZE;
+            counted_end = end;
+        }
+    }
+
+    count
+}
+
+/// Copies `bytes` at `vaddr` in the frames of `pages`, allocating the missing
+/// ones. The region is zero filled up to `mem_size`.
+fn load_region(
+    pages: &mut PageMap,
+    vaddr: u64,
+    mem_size: u64,
+    bytes: &[u8],
+    flags: PageTableFlags,
+) -> Result<(), LoadError> {
+    if mem_size == 0 {
+        return Ok(());
+    }
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(vaddr));
+    let last = Page::containing_address(VirtAddr::new(vaddr + mem_size - 1));
+
+    for page in Page::range_inclusive(first, last) {
+        let page_start = page.start_address().as_u64();
+
+        let copy = |data: &mut [u8; 4096]| {
+            let from = max(page_start, vaddr);
+            let to = min(page_start + PAGE_SIZE, vaddr + bytes.len() as u64);
+
+            if from < to {
+                data[(from - page_start) as usize..(to - page_start) as usize]
+                    .copy_from_slice(&bytes[(from - vaddr) as usize..(to - vaddr) as usize]);
+            }
+        };
+
+        let entry = pages.get(&page_start).cloned();
+
+        match entry {
+            Some((frame, old_flags)) => {
+                address_space::write_frame(frame, copy);
+
+                pages.insert(page_start, (frame, merge_flags(old_flags, flags)));
+            }
+            None => {
+                let frame = address_space::alloc_frame_with(copy).ok_or(LoadError::OutOfMemory)?;
+
+                pages.insert(page_start, (frame, flags));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the segments and the initial stack in the frames of `pages`
+fn load_pages(
+    pages: &mut PageMap,
+    segments: &[(ProgramHeader, &[u8])],
+    stack: &InitialStack,
+) -> Result<(), LoadError> {
+    for (header, data) in segments {
+        load_region(
+            pages,
+            header.vaddr,
+            header.mem_size,
+            data,
+            segment_flags(header),
+        )?;
+    }
+
+    load_region(
+        pages,
+        stack.stack_pointer,
+        stack.data.len() as u64,
+        &stack.data,
+        PageTableFlags::PRESENT
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE,
+    )
+}
+
+/// Loads the PT_LOAD segments of `image` and an initial stack in a new
+/// address space, of at most `max_pages` user pages. The image is checked
+/// before any frame is allocated.
+pub fn load(
+    image: &[u8],
+    args: &[&str],
+    env: &[&str],
+    max_pages: u64,
+) -> Result<LoadedImage, LoadError> {
+    let elf = ElfFile::parse(image)?;
+
+    check_user_range(elf.entry, 1)?;
+
+    let mut segments = Vec::new();
+
+    for header in elf
+        .program_headers()
+        .filter(|header| header.kind == PT_LOAD)
+    {
+        if header.file_size > header.mem_size {
+            return Err(LoadError::Malformed(
+                "segment file size bigger than memory size",
+            ));
+        }
+
+        check_user_range(header.vaddr, header.mem_size)?;
+
+        segments.push((header, elf.segment_data(&header)?));
+    }
+
+    let mut auxv = Vec::new();
+
+    if let Some(program_headers) = elf.program_headers_vaddr() {
+        auxv.push((stack::AT_PHDR, program_headers));
+    }
+
+    auxv.push((stack::AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxv.push((stack::AT_PHNUM, u64::from(elf.program_header_count)));
+    auxv.push((stack::AT_PAGESZ, PAGE_SIZE));
+    auxv.push((stack::AT_ENTRY, elf.entry));
+
+    let stack = InitialStack::new(args, env, &auxv)?;
+
+    let mut regions: Vec<(u64, u64)> = segments
+        .iter()
+        .map(|(header, _)| (header.vaddr, header.mem_size))
+        .collect();
+
+    regions.push((stack.stack_pointer, stack.data.len() as u64));
+
+    if page_count(&regions) > max_pages {
+        return Err(LoadError::TooManyPages);
+    }
+
+    let mut pages = PageMap::new();
+
+    let loaded = load_pages(&mut pages, &segments, &stack);
+
+    if let Err(error) = loaded {
+        // Nothing maps the frames yet
+        for &(frame, _) in pages.values() {
+            helpers::free_frame(frame);
+        }
+
+        return Err(error);
+    }
+
+    let address_space = AddressSpace::new();
+
+    let mappings: Vec<_> = pages
+        .iter()
+        .map(|(&page_start, &(frame, flags))| {
+            (
+                Page::containing_address(VirtAddr::new(page_start)),
+                frame,
+                flags,
+            )
+        })
+        .collect();
+
+    address_space.map_user_pages(&mappings);
+
+    Ok(LoadedImage {
+        address_space: Arc::new(address_space),
+        entry: VirtAddr::new(elf.entry),
+        stack_pointer: VirtAddr::new(stack.stack_pointer),
+    })
+}
+
+/// Content of a boot module, identity mapped by `remap_kernel`
+pub fn module_data(module: &ModuleTag) -> &'static [u8] {
+    let start = module.start_address() as usize;
+    let end = module.end_address() as usize;
+
+    unsafe { core::slice::from_raw_parts(start as *const u8, end - start) }
+}
+
+/// Loads a boot module and starts it in ring 3, its command line is argv[0]
+pub fn spawn_module(module: &ModuleTag) -> Result<Pid, LoadError> {
+    // The children of the kernel start with the default limits
+    let max_pages = limits::Limits::new().max(limits::RLIMIT_RSS);
+    let image = load(module_data(module), &[module.name()], &[], max_pages)?;
+
+    usermode::spawn_in(image.address_space, image.entry, image.stack_pointer)
+        .map_err(LoadError::Spawn)
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Smallest static executable: one segment exiting with code 7
+#[cfg(test)]
+pub fn test_image() -> Vec<u8> {
+    // mov eax, SYS_EXIT ; mov edi, 7 ; syscall
+    test_image_with(&[
+        0xb8, 0x00, 0x00, 0x00, 0x00, 0xbf, 0x07, 0x00, 0x00, 0x00, 0x0f, 0x05,
+    ])
+}
+
+/// Static executable with a single read-only segment holding `code`
+#[cfg(test)]
+pub fn test_image_with(code: &[u8]) -> Vec<u8> {
+    let base = USER_SPACE_START + 0x40_0000;
+    let code_offset = 64 + PROGRAM_HEADER_SIZE as u64;
+
+    let size = code_offset + code.len() as u64;
+
+    let mut image = Vec::new();
+
+    // ELF header
+    image.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    image.extend_from_slice(&[0; 8]);
+    image.extend_from_slice(&2u16.to_le_bytes());
+    image.extend_from_slice(&0x3eu16.to_le_bytes());
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(base + code_offset).to_le_bytes());
+    image.extend_from_slice(&64u64.to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&64u16.to_le_bytes());
+    image.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&1u16.to_le_bytes());
+    image.extend_from_slice(&[0; 6]);
+
+    // Program header
+    image.extend_from_slice(&PT_LOAD.to_le_bytes());
+    image.extend_from_slice(&(elf::PF_R | PF_X).to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&base.to_le_bytes());
+    image.extend_from_slice(&base.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&size.to_le_bytes());
+    image.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+
+    image.extend_from_slice(code);
+
+    image
+}
+
+#[test_case]
+fn test_load_and_run_elf() {
+    use crate::process;
+
+    serial_print!("test_load_and_run_elf... ");
+
+    let image = load(
+        &test_image(),
+        &["test"],
+        &["KEY=value"],
+        limits::RLIM_INFINITY,
+    )
+    .expect("load failed");
+
+    assert_eq!(image.stack_pointer.as_u64() % 16, 0);
+
+    let pid = usermode::spawn_in(image.address_space, image.entry, image.stack_pointer).unwrap();
+
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 7)));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_load_page_limit() {
+    serial_print!("test_load_page_limit... ");
+
+    // One page of code and one of stack
+    match load(&test_image(), &["test"], &[], 1) {
+        Err(LoadError::TooManyPages) => (),
+        _ => panic!("image over the page limit loaded"),
+    }
+
+    assert!(load(&test_image(), &["test"], &[], 2).is_ok());
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,88 @@
+use alloc::{vec, vec::Vec};
+
+use super::LoadError;
+use crate::memory::paging::{PAGE_SIZE, USER_SPACE_END};
+
+/// The page right below the end of user space is left unmapped
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE;
+
+// Upper bound of the arguments, environment and auxiliary vector
+const MAX_INITIAL_STACK_SIZE: usize = 16 * PAGE_SIZE as usize;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+/// Content of the top of a new user stack, as expected by the System V ABI:
+///
+/// ```text
+/// stack_pointer -> argc
+///                  argv[0] .. argv[argc - 1], NULL
+///                  envp[0] .. envp[n - 1], NULL
+///                  auxv pairs, AT_NULL
+///                  strings
+/// USER_STACK_TOP
+/// ```
+pub struct InitialStack {
+    pub stack_pointer: u64,
+    pub data: Vec<u8>,
+}
+
+impl InitialStack {
+    pub fn new(args: &[&str], env: &[&str], auxv: &[(u64, u64)]) -> Result<Self, LoadError> {
+        let strings_size: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+        let words = 1 + (args.len() + 1) + (env.len() + 1) + 2 * (auxv.len() + 1);
+
+        // Keeps the stack pointer 16 bytes aligned
+        let size = (words * 8 + strings_size + 15) & !15;
+
+        if size > MAX_INITIAL_STACK_SIZE {
+            return Err(LoadError::TooManyArguments);
+        }
+
+        let stack_pointer = USER_STACK_TOP - size as u64;
+        let mut data = vec![0; size];
+
+        let mut string_offset = size - strings_size;
+        let mut string_addrs = Vec::with_capacity(args.len() + env.len());
+
+        for string in args.iter().chain(env) {
+            string_addrs.push(stack_pointer + string_offset as u64);
+
+            data[string_offset..string_offset + string.len()].copy_from_slice(string.as_bytes());
+
+            // The NUL terminator is already there
+            string_offset += string.len() + 1;
+        }
+
+        let (arg_addrs, env_addrs) = string_addrs.split_at(args.len());
+
+        let mut values = Vec::with_capacity(words);
+
+        values.push(args.len() as u64);
+        values.extend_from_slice(arg_addrs);
+        values.push(0);
+        values.extend_from_slice(env_addrs);
+        values.push(0);
+
+        for &(key, value) in auxv {
+            values.push(key);
+            values.push(value);
+        }
+
+        values.push(AT_NULL);
+        values.push(0);
+
+        for (index, value) in values.iter().enumerate() {
+            data[index * 8..index * 8 + 8].copy_from_slice(&value.to_le_bytes());
+        }
+
+        Ok(Self {
+            stack_pointer,
+            data,
+        })
+    }
+}
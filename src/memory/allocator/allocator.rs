@@ -1,5 +1,7 @@
 use crate::memory::allocator::BootInfoFrameAllocator;
 use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::vec;
 use lazy_static::lazy_static;
 use linked_list_allocator::LockedHeap;
 use spin::Mutex;
@@ -15,13 +17,27 @@ lazy_static! {
 }
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB, pages are mapped on first access
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelHeap = KernelHeap(LockedHeap::empty());
+
+/// Keeps the interrupts disabled while the heap is locked, so that a task
+/// is never preempted with the heap lock held.
+struct KernelHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        x86_64::instructions::interrupts::without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        x86_64::instructions::interrupts::without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
 
 #[alloc_error_handler]
-fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+fn alloc_error_handler(layout: Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
@@ -29,8 +45,16 @@ pub fn init_heap() -> Result<(), MapToError> {
     super::super::paging::helpers::alloc_page(VirtAddr::new(HEAP_START as u64));
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE);
     }
 
+    let size =
+        super::super::paging::helpers::use_global_allocator(|falloc| falloc.free_list_size());
+
+    // Filled now so that its heap pages are mapped before it is used
+    let free_frames = vec![0u64; size].into_boxed_slice();
+
+    super::super::paging::helpers::use_global_allocator(|falloc| falloc.set_free_list(free_frames));
+
     Ok(())
 }
@@ -1,11 +1,23 @@
+use alloc::boxed::Box;
+use core::cmp::min;
 use x86_64::{
     structures::paging::{FrameAllocator, PhysFrame, Size4KiB, UnusedPhysFrame},
     PhysAddr,
 };
 
+use crate::serial_println;
+
+const FRAME_SIZE: u64 = 4096;
+
 pub struct BootInfoFrameAllocator {
     multiboot_information_address: usize,
     next: usize,
+    /// Frames given back, one bit per frame of the memory map, reused before
+    /// taking new ones from it. Installed once the heap exists.
+    free_frames: Option<Box<[u64]>>,
+    free_count: usize,
+    /// No frame is free in the words of `free_frames` before this one
+    free_hint: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -13,12 +25,86 @@ impl BootInfoFrameAllocator {
         Self {
             multiboot_information_address,
             next: 0,
+            free_frames: None,
+            free_count: 0,
+            free_hint: 0,
+        }
+    }
+
+    /// Words of the free frame bitmap, to cover every frame of the memory map
+    pub fn free_list_size(&self) -> usize {
+        let boot_info = unsafe { multiboot2::load(self.multiboot_information_address) };
+
+        let end = boot_info
+            .memory_map_tag()
+            .unwrap()
+            .memory_areas()
+            .map(|area| area.end_address())
+            .max()
+            .unwrap_or(0);
+
+        let frames = (end / FRAME_SIZE) as usize;
+
+        (frames + 63) / 64
+    }
+
+    /// `bitmap` must already be backed by memory: freeing a frame must not
+    /// fault on a heap page, the fault handler would need this allocator.
+    pub fn set_free_list(&mut self, bitmap: Box<[u64]>) {
+        self.free_frames = Some(bitmap);
+        self.free_count = 0;
+        self.free_hint = 0;
+    }
+
+    /// Gives back a frame that is not mapped anywhere anymore
+    pub fn free_frame(&mut self, frame: PhysFrame) {
+        let number = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+
+        match self.free_frames {
+            Some(ref mut bitmap) => {
+                let (word, bit) = (number / 64, number % 64);
+
+                assert_eq!(bitmap[word] & 1 << bit, 0, "{:?} freed twice", frame);
+
+                bitmap[word] |= 1 << bit;
+                self.free_count += 1;
+                self.free_hint = min(self.free_hint, word);
+            }
+            None => serial_println!("No free frame list yet, leaking {:?}", frame),
         }
     }
+
+    /// Takes a frame from the free list
+    fn reuse_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_count == 0 {
+            return None;
+        }
+
+        let bitmap = self.free_frames.as_mut().unwrap();
+
+        let word = (self.free_hint..bitmap.len())
+            .find(|&word| bitmap[word] != 0)
+            .expect("free frame count out of sync");
+        let bit = bitmap[word].trailing_zeros() as usize;
+
+        bitmap[word] &= !(1 << bit);
+        self.free_count -= 1;
+        self.free_hint = word;
+
+        let number = (word * 64 + bit) as u64;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            number * FRAME_SIZE,
+        )))
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
+        if let Some(frame) = self.reuse_frame() {
+            return Some(unsafe { UnusedPhysFrame::new(frame) });
+        }
+
         let boot_info = unsafe { multiboot2::load(self.multiboot_information_address) };
 
         let elf_sections_tag = boot_info
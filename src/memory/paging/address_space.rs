@@ -0,0 +1,288 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::{
+        Page, PageTable, PageTableEntry, PageTableFlags, PhysFrame, RecursivePageTable,
+        UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::page_tables::InactivePageTable;
+use super::{cow, helpers, tlb, with_active_table, P4, USER_SPACE_END, USER_SPACE_START};
+
+/// P4 entries shared by every address space: all but the user part and the
+/// recursive entry
+fn is_kernel_p4_index(index: usize) -> bool {
+    let user_start = (USER_SPACE_START >> 39) as usize;
+    let user_end = (USER_SPACE_END >> 39) as usize;
+
+    (index < user_start || index >= user_end) && index != 511
+}
+
+/// Page table of a user task.
+/// The kernel part points to the same P3 tables as the active table, the
+/// user part starts empty.
+pub struct AddressSpace {
+    table: InactivePageTable,
+    /// Held while a page is demand paged, two threads may fault on the same
+    /// page
+    fault_lock: Mutex<()>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        let kernel_entries: Vec<(usize, PhysAddr, PageTableFlags)> = {
+            let p4 = unsafe { &*P4 };
+
+            p4.iter()
+                .enumerate()
+                .filter(|(index, entry)| is_kernel_p4_index(*index) && !entry.is_unused())
+                .map(|(index, entry)| (index, entry.addr(), entry.flags()))
+                .collect()
+        };
+
+        let frame = helpers::alloc_frame().expect("no more frames");
+
+        let table = with_active_table(|active_table, temporary_page| {
+            let table = InactivePageTable::new(
+                PhysFrame::from_start_address(frame.start_address()).unwrap(),
+                active_table,
+                temporary_page,
+            );
+
+            // Filled through the scratch page, the table cannot be loaded
+            // without the kernel
+            let p4 = temporary_page.map_table_frame(
+                unsafe { UnusedPhysFrame::new(table.p4_frame) },
+                active_table,
+            );
+
+            for &(index, addr, flags) in &kernel_entries {
+                p4[index].set_addr(addr, flags);
+            }
+
+            temporary_page.unmap(active_table);
+
+            table
+        });
+
+        Self {
+            table,
+            fault_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.table.p4_frame
+    }
+
+    /// Runs `f` with this address space loaded on the current processor, the
+    /// recursive mapping points to it. The tables loaded by the other
+    /// processors are left alone.
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut RecursivePageTable<'static>) -> R,
+    {
+        with_active_table(|active_table, _| {
+            let backup = Cr3::read().0;
+
+            tlb::load_table(self.p4_frame());
+
+            let result = f(&mut active_table.page_directory);
+
+            tlb::load_table(backup);
+
+            result
+        })
+    }
+
+    /// Runs `f` with the demand paging of this address space serialized
+    pub fn with_fault_lock<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        interrupts::without_interrupts(|| {
+            let _fault = self.fault_lock.lock();
+
+            f()
+        })
+    }
+
+    /// Maps user pages to already filled frames
+    pub fn map_user_pages(&self, mappings: &[(Page, PhysFrame, PageTableFlags)]) {
+        self.with(|mapper| {
+            for &(page, frame, flags) in mappings {
+                let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+                helpers::map_to_with(
+                    page.start_address(),
+                    unsafe { UnusedPhysFrame::new(frame) },
+                    flags,
+                    mapper,
+                );
+
+                helpers::set_parent_flags(page, flags);
+            }
+        });
+    }
+
+    /// Number of user pages mapped. Must not be the loaded address space.
+    pub fn user_page_count(&self) -> usize {
+        let mut count = 0;
+
+        self.with(|_| for_each_user_entry(|_, _| count += 1));
+
+        count
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the user pages nobody else maps and the tables of the user
+    /// part. The kernel tables are shared and stay.
+    fn drop(&mut self) {
+        assert_ne!(
+            Cr3::read().0,
+            self.p4_frame(),
+            "dropping the loaded address space"
+        );
+
+        self.with(|_| {
+            for_each_user_entry(|_, entry| {
+                let frame = entry.frame().expect("user page mapped to a huge frame");
+
+                if cow::release_frame(frame) {
+                    helpers::free_frame(frame);
+                }
+            });
+
+            for_each_user_table(helpers::free_frame);
+        });
+
+        helpers::free_frame(self.p4_frame());
+    }
+}
+
+pub(super) fn table(addr: VirtAddr) -> &'static mut PageTable {
+    unsafe { super::get_page4_virt_ptr(addr) }
+}
+
+fn present_entries(table: &mut PageTable) -> impl Iterator<Item = (usize, &mut PageTableEntry)> {
+    table
+        .iter_mut()
+        .enumerate()
+        .filter(|(_, entry)| entry.flags().contains(PageTableFlags::PRESENT))
+}
+
+/// Entries of the current P4 covering user space
+fn user_p4_entries() -> impl Iterator<Item = (usize, &'static mut PageTableEntry)> {
+    let user_indices = (USER_SPACE_START >> 39) as usize..(USER_SPACE_END >> 39) as usize;
+
+    present_entries(table(helpers::recursive_table_addr(511, 511, 511)))
+        .filter(move |(index, _)| user_indices.contains(index))
+}
+
+/// Runs `f` on the P1 entry of every user page mapped in the current table
+pub(super) fn for_each_user_entry<F>(mut f: F)
+where
+    F: FnMut(Page, &mut PageTableEntry),
+{
+    for (p4_index, _) in user_p4_entries() {
+        let p3 = table(helpers::recursive_table_addr(511, 511, p4_index));
+
+        for (p3_index, p3_entry) in present_entries(p3) {
+            if p3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                continue;
+            }
+
+            let p2 = table(helpers::recursive_table_addr(511, p4_index, p3_index));
+
+            for (p2_index, p2_entry) in present_entries(p2) {
+                if p2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    continue;
+                }
+
+                let p1 = table(helpers::recursive_table_addr(p4_index, p3_index, p2_index));
+
+                for (p1_index, entry) in present_entries(p1) {
+                    let addr = ((p4_index as u64) << 39)
+                        | ((p3_index as u64) << 30)
+                        | ((p2_index as u64) << 21)
+                        | ((p1_index as u64) << 12);
+
+                    f(Page::containing_address(VirtAddr::new(addr)), entry);
+                }
+            }
+        }
+    }
+}
+
+/// Runs `f` on the frame of every P3, P2 and P1 table of the user part of
+/// the current table, children first
+fn for_each_user_table<F>(mut f: F)
+where
+    F: FnMut(PhysFrame),
+{
+    for (p4_index, p4_entry) in user_p4_entries() {
+        let p3 = table(helpers::recursive_table_addr(511, 511, p4_index));
+
+        for (p3_index, p3_entry) in present_entries(p3) {
+            if p3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                continue;
+            }
+
+            let p2 = table(helpers::recursive_table_addr(511, p4_index, p3_index));
+
+            for (_, p2_entry) in present_entries(p2) {
+                if let Ok(frame) = p2_entry.frame() {
+                    f(frame);
+                }
+            }
+
+            f(p3_entry.frame().unwrap());
+        }
+
+        f(p4_entry.frame().unwrap());
+    }
+}
+
+/// Allocates a zeroed frame and lets `fill` write its content, `None` if no
+/// frame is left
+pub fn alloc_frame_with<F>(fill: F) -> Option<PhysFrame>
+where
+    F: FnOnce(&mut [u8; 4096]),
+{
+    let frame = helpers::alloc_frame()?;
+
+    let frame = PhysFrame::containing_address(frame.start_address());
+
+    write_frame(frame, |data| {
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+
+        fill(data);
+    });
+
+    Some(frame)
+}
+
+/// Gives access to the content of a frame that is not mapped in the current
+/// address space. The frame is only mapped for the duration of `f`.
+pub fn write_frame<F, R>(frame: PhysFrame, f: F) -> R
+where
+    F: FnOnce(&mut [u8; 4096]) -> R,
+{
+    with_active_table(|active_table, temporary_page| {
+        let data =
+            temporary_page.map_frame_data(unsafe { UnusedPhysFrame::new(frame) }, active_table);
+
+        let result = f(data);
+
+        temporary_page.unmap(active_table);
+
+        result
+    })
+}
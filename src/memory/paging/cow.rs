@@ -0,0 +1,158 @@
+//! Copy-on-write sharing of user pages, used by `fork`.
+//!
+//! Both address spaces map the same frames read-only with `COPY_ON_WRITE`
+//! set. The first write faults and gets a private copy, or the page back
+//! writable if nobody else uses the frame anymore.
+//!
+//! The tasks of a process share its address space and may run on other
+//! processors: the entries they keep in their TLBs are shot down.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{Page, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use super::{address_space, helpers, tlb::TlbBatch};
+
+/// Available bit marking a read-only entry that becomes writable on a copy
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+lazy_static! {
+    /// Number of mappings of the frames used more than once. A frame that is
+    /// not in the map has a single owner.
+    static ref SHARED_FRAMES: Mutex<BTreeMap<PhysFrame, usize>> = { Mutex::new(BTreeMap::new()) };
+}
+
+/// Held while a write fault is handled, two tasks of the address space may
+/// fault on the same page
+static COPY_LOCK: Mutex<()> = Mutex::new(());
+
+fn with_shared_frames<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BTreeMap<PhysFrame, usize>) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut SHARED_FRAMES.lock()))
+}
+
+/// Adds a mapping to `frame`
+pub fn share_frame(frame: PhysFrame) {
+    with_shared_frames(|frames| *frames.entry(frame).or_insert(1) += 1);
+}
+
+/// Removes a mapping to `frame`. Returns true if it was the last one and the
+/// frame can be freed.
+pub fn release_frame(frame: PhysFrame) -> bool {
+    with_shared_frames(|frames| match frames.get_mut(&frame) {
+        Some(count) if *count > 2 => {
+            *count -= 1;
+
+            false
+        }
+        Some(_) => {
+            frames.remove(&frame);
+
+            false
+        }
+        None => true,
+    })
+}
+
+pub fn is_shared(frame: PhysFrame) -> bool {
+    with_shared_frames(|frames| frames.contains_key(&frame))
+}
+
+/// Turns the writable user pages of the current table into copy-on-write
+/// ones and returns every user mapping, to be installed in a copy of the
+/// address space. Each frame gets one more reference.
+pub fn share_user_pages() -> Vec<(Page, PhysFrame, PageTableFlags)> {
+    let mut mappings = Vec::new();
+    let mut batch = TlbBatch::new();
+
+    interrupts::without_interrupts(|| {
+        address_space::for_each_user_entry(|page, entry| {
+            let mut flags = entry.flags();
+
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+
+                entry.set_flags(flags);
+                batch.add(page);
+            }
+
+            let frame = entry.frame().expect("user page mapped to a huge frame");
+
+            share_frame(frame);
+            mappings.push((page, frame, flags));
+        });
+    });
+
+    // Another task of the process would keep writing to the shared frames
+    batch.flush();
+
+    mappings
+}
+
+/// Handles a write to `addr` in the current address space.
+/// Returns false if the page is not a copy-on-write one, or if no frame is
+/// left for the copy.
+pub fn copy_on_write(addr: VirtAddr) -> bool {
+    let page: Page = Page::containing_address(addr);
+    let mut batch = TlbBatch::new();
+
+    let handled = interrupts::without_interrupts(|| {
+        let _copy = COPY_LOCK.lock();
+
+        let flags = match helpers::page_flags(page) {
+            Some(flags) if flags.contains(COPY_ON_WRITE) => flags,
+            // Another task of the process handled the fault first, the
+            // entry that faulted is gone from the TLB
+            Some(flags)
+                if flags.contains(PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE) =>
+            {
+                return true
+            }
+            _ => return false,
+        };
+
+        let p1 = address_space::table(helpers::recursive_table_addr(
+            usize::from(page.p4_index()),
+            usize::from(page.p3_index()),
+            usize::from(page.p2_index()),
+        ));
+        let entry = &mut p1[usize::from(page.p1_index())];
+
+        let frame = entry.frame().expect("user page mapped to a huge frame");
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        if is_shared(frame) {
+            let copy = match address_space::alloc_frame_with(|data| {
+                data.copy_from_slice(unsafe { &*page.start_address().as_ptr::<[u8; 4096]>() })
+            }) {
+                Some(copy) => copy,
+                None => return false,
+            };
+
+            entry.set_addr(copy.start_address(), flags);
+            release_frame(frame);
+
+            // The other processors must not read the shared frame anymore,
+            // its last owner is going to write to it
+            batch.add(page);
+        } else {
+            // The other address spaces already made their own copy
+            entry.set_flags(flags);
+        }
+
+        helpers::set_parent_flags(page, flags);
+
+        true
+    });
+
+    batch.flush();
+
+    handled
+}
@@ -1,26 +1,118 @@
 use crate::serial_println;
+use core::cell::RefCell;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, page::PageSize, FrameAllocator, Mapper, Page, PageTableFlags,
-        RecursivePageTable, Size4KiB, UnusedPhysFrame,
+        PhysFrame, RecursivePageTable, Size4KiB, UnusedPhysFrame,
     },
     PhysAddr, VirtAddr,
 };
 
+use super::cow;
+use super::tlb::TlbBatch;
 use crate::memory::allocator::{BootInfoFrameAllocator, FRAME_ALLOCATOR, MAPPER};
+use crate::percpu;
+
+const FRAME_CACHE_SIZE: usize = 32;
+/// Frames moved at once between a cache and the global allocator
+const FRAME_CACHE_BATCH: usize = 16;
+
+/// Pages unmapped between two TLB flushes by `unmap_pages()`
+const UNMAP_CHUNK: usize = 16;
+
+/// Frames kept by a processor, so that most allocations and frees do not
+/// take the lock of the global allocator. Frames left in the cache of a
+/// processor are not available to the others.
+struct FrameCache {
+    frames: [u64; FRAME_CACHE_SIZE],
+    count: usize,
+}
+
+impl FrameCache {
+    fn push(&mut self, frame: PhysFrame) {
+        self.frames[self.count] = frame.start_address().as_u64();
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<PhysFrame> {
+        if self.count == 0 {
+            return None;
+        }
+
+        self.count -= 1;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            self.frames[self.count],
+        )))
+    }
+}
+
+percpu! {
+    static FRAME_CACHE: RefCell<FrameCache> = RefCell::new(FrameCache {
+        frames: [0; FRAME_CACHE_SIZE],
+        count: 0,
+    });
+}
+
+/// Takes a frame from the cache of the current processor, refilled from the
+/// global allocator when empty
+pub fn alloc_frame() -> Option<UnusedPhysFrame> {
+    // The page fault handler allocates frames as well
+    interrupts::without_interrupts(|| {
+        let mut cache = FRAME_CACHE.get().borrow_mut();
+
+        if cache.count == 0 {
+            use_global_allocator(|falloc| {
+                while cache.count < FRAME_CACHE_BATCH {
+                    match falloc.allocate_frame() {
+                        Some(frame) => {
+                            cache.push(PhysFrame::containing_address(frame.start_address()))
+                        }
+                        None => break,
+                    }
+                }
+            });
+        }
+
+        cache
+            .pop()
+            .map(|frame| unsafe { UnusedPhysFrame::new(frame) })
+    })
+}
 
 pub fn alloc_page(page_addr: VirtAddr) -> PhysAddr {
-    let page_addr: Page<Size4KiB> = Page::containing_address(page_addr);
+    alloc_page_with_flags(
+        page_addr,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    )
+}
 
-    // TODO: Check if page is already used
-    use_global_allocator(|falloc| {
-        let frame = falloc
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)
-            .unwrap();
+pub fn alloc_user_page(page_addr: VirtAddr) -> PhysAddr {
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
 
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let phys = alloc_page_with_flags(page_addr, flags);
 
+    set_parent_flags(Page::containing_address(page_addr), flags);
+
+    // Frames are reused, the previous content must not leak to userland
+    let page: Page<Size4KiB> = Page::containing_address(page_addr);
+
+    unsafe { core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, 4096) };
+
+    phys
+}
+
+pub fn alloc_page_with_flags(page_addr: VirtAddr, flags: PageTableFlags) -> PhysAddr {
+    let page_addr: Page<Size4KiB> = Page::containing_address(page_addr);
+
+    let frame = alloc_frame()
+        .ok_or(MapToError::FrameAllocationFailed)
+        .unwrap();
+    let start_addr = frame.start_address();
+
+    let mapped = use_global_allocator(|falloc| {
         // serial_println!(
         //     "Alloc page {:#?} -> {:#?}",
         //     page_addr,
@@ -28,18 +120,101 @@ pub fn alloc_page(page_addr: VirtAddr) -> PhysAddr {
         // );
 
         if let Some(ref mut mapper) = *MAPPER.lock() {
-            let start_addr = frame.start_address();
-
-            mapper
-                .map_to(page_addr, frame, flags, falloc)
-                .unwrap()
-                .flush();
+            match mapper.map_to(page_addr, frame, flags, falloc) {
+                Ok(flush) => {
+                    flush.flush();
 
-            return start_addr;
+                    None
+                }
+                // Another processor faulted on the page first
+                Err(MapToError::PageAlreadyMapped) => {
+                    Some(mapper.translate_page(page_addr).unwrap().start_address())
+                }
+                Err(error) => panic!("alloc_page(): {:?}", error),
+            }
         } else {
             panic!("alloc_page(): Cannot access MAPPER");
         }
-    })
+    });
+
+    match mapped {
+        Some(addr) => {
+            free_frame(PhysFrame::containing_address(start_addr));
+
+            addr
+        }
+        None => start_addr,
+    }
+}
+
+/// Adds the USER_ACCESSIBLE and WRITABLE bits of `flags` to the P4, P3 and
+/// P2 entries leading to `page`, the CPU checks them at every level.
+/// Works on the table currently reachable through the recursive entry.
+pub fn set_parent_flags(page: Page, flags: PageTableFlags) {
+    let flags = flags & (PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE);
+
+    let p4_index = usize::from(page.p4_index());
+    let p3_index = usize::from(page.p3_index());
+    let p2_index = usize::from(page.p2_index());
+
+    let tables = [
+        (recursive_table_addr(511, 511, 511), p4_index),
+        (recursive_table_addr(511, 511, p4_index), p3_index),
+        (recursive_table_addr(511, p4_index, p3_index), p2_index),
+    ];
+
+    for &(table_addr, index) in tables.iter() {
+        let table = unsafe { super::get_page4_virt_ptr(table_addr) };
+        let entry = &mut table[index];
+
+        entry.set_flags(entry.flags() | flags);
+    }
+
+    x86_64::instructions::tlb::flush(page.start_address());
+}
+
+/// Virtual address of a page table through the recursive mapping of P4[511].
+/// The P4 index is always 511, the other indices of the address are given.
+/// e.g. `(511, 511, 511)` is the P4 itself, `(511, 511, i)` the P3 of P4[i]
+pub fn recursive_table_addr(p3_index: usize, p2_index: usize, p1_index: usize) -> VirtAddr {
+    let addr = 0xffff_0000_0000_0000
+        | (511 << 39)
+        | ((p3_index as u64) << 30)
+        | ((p2_index as u64) << 21)
+        | ((p1_index as u64) << 12);
+
+    VirtAddr::new(addr)
+}
+
+/// Flags of the entry mapping `page` in the current table, `None` if it is
+/// not mapped. Huge pages return the flags of their P3 or P2 entry.
+pub fn page_flags(page: Page) -> Option<PageTableFlags> {
+    let p4_index = usize::from(page.p4_index());
+    let p3_index = usize::from(page.p3_index());
+    let p2_index = usize::from(page.p2_index());
+    let p1_index = usize::from(page.p1_index());
+
+    let walk = [
+        (recursive_table_addr(511, 511, 511), p4_index),
+        (recursive_table_addr(511, 511, p4_index), p3_index),
+        (recursive_table_addr(511, p4_index, p3_index), p2_index),
+        (recursive_table_addr(p4_index, p3_index, p2_index), p1_index),
+    ];
+
+    for (level, &(table_addr, index)) in walk.iter().enumerate() {
+        let table = unsafe { super::get_page4_virt_ptr(table_addr) };
+        let flags = table[index].flags();
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        if level == walk.len() - 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Some(flags);
+        }
+    }
+
+    None
 }
 
 pub fn translate_addr(virt: VirtAddr) -> PhysAddr {
@@ -126,6 +301,102 @@ where
     });
 }
 
+/// Identity maps the frames holding `size` bytes from `start` that are not
+/// mapped yet, to reach firmware tables and device registers
+pub fn identity_map_range(start: PhysAddr, size: u64, flags: PageTableFlags) {
+    let first = PhysFrame::<Size4KiB>::containing_address(start);
+    let last = PhysFrame::containing_address(start + size.max(1) - 1u64);
+
+    if let Some(ref mut mapper) = *MAPPER.lock() {
+        for frame in PhysFrame::range_inclusive(first, last) {
+            let page =
+                Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+
+            match mapper.translate_page(page) {
+                Ok(mapped) => assert_eq!(mapped, frame, "{:?} is used for another frame", page),
+                Err(_) => identity_map_with(unsafe { UnusedPhysFrame::new(frame) }, flags, mapper),
+            }
+        }
+    } else {
+        panic!("identity_map_range(): Cannot get MAPPER");
+    }
+}
+
+/// Identity maps device registers, uncached
+pub fn identity_map_device(start: PhysAddr, size: u64) {
+    identity_map_range(
+        start,
+        size,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::NO_EXECUTE,
+    );
+}
+
+/// Unmaps `count` pages from `start` in the current table, the pages that
+/// are not mapped are skipped. The frames are freed once no TLB holds them
+/// anymore, unless another address space still maps them.
+/// No lock taken with interrupts disabled must be held, see `smp::ipi`.
+pub fn unmap_pages(start: VirtAddr, count: u64) {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let mut left = count;
+
+    // The frames are kept on the stack, the heap may fault and take the
+    // mapper
+    while left > 0 {
+        let chunk = left.min(UNMAP_CHUNK as u64);
+        let chunk_start = first + (count - left);
+        let mut batch = TlbBatch::new();
+        let mut frames = [None; UNMAP_CHUNK];
+
+        interrupts::without_interrupts(|| {
+            if let Some(ref mut mapper) = *MAPPER.lock() {
+                for (index, page) in Page::range(chunk_start, chunk_start + chunk).enumerate() {
+                    if let Ok((frame, flush)) = mapper.unmap(page) {
+                        // The batch flushes it on every processor
+                        flush.ignore();
+
+                        batch.add(page);
+                        frames[index] = Some(frame);
+                    }
+                }
+            } else {
+                panic!("unmap_pages(): Cannot get MAPPER");
+            }
+        });
+
+        batch.flush();
+
+        for &frame in frames.iter().flatten() {
+            if cow::release_frame(frame) {
+                free_frame(frame);
+            }
+        }
+
+        left -= chunk;
+    }
+}
+
+/// Returns a frame that is not mapped anymore to the cache of the current
+/// processor, half of a full cache goes back to the global allocator
+pub fn free_frame(frame: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut cache = FRAME_CACHE.get().borrow_mut();
+
+        if cache.count == FRAME_CACHE_SIZE {
+            use_global_allocator(|falloc| {
+                for _ in 0..FRAME_CACHE_BATCH {
+                    falloc.free_frame(cache.pop().unwrap());
+                }
+            });
+        }
+
+        cache.push(frame);
+    });
+}
+
 pub fn use_global_allocator<F, R>(f: F) -> R
 where
     F: FnOnce(&mut BootInfoFrameAllocator) -> R,
@@ -136,3 +407,26 @@ where
         panic!("map_to(): Cannot get FRAME_ALLOCATOR");
     }
 }
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_alloc_page_already_mapped() {
+    serial_print!("test_alloc_page_already_mapped... ");
+
+    // Unused by the rest of the kernel, below the scratch pages
+    let addr = VirtAddr::new(0xcaf9_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let first = alloc_page_with_flags(addr, flags);
+
+    // A second fault on the page keeps the frame of the first one
+    assert_eq!(alloc_page_with_flags(addr, flags), first);
+
+    unmap_pages(addr, 1);
+
+    serial_println!("[ok]");
+}
@@ -1,15 +1,80 @@
+use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{PageTable, PageTableFlags},
+    registers::model_specific::Msr,
+    structures::paging::{Page, PageTable, PageTableFlags},
     VirtAddr,
 };
 
+pub mod address_space;
+pub mod cow;
 pub mod helpers;
 pub mod page_tables;
 pub mod remap_kernel;
+pub mod tlb;
+
+pub use address_space::AddressSpace;
+
+use page_tables::{ActivePageTable, TemporaryPage};
 
 pub const PAGE_SIZE: u64 = 4096;
 pub const P4: *mut PageTable = 0xffffffff_fffff000 as *mut _;
 
+// User space spans the P4 entries 1 to 127. Entry 0 holds the identity mapped
+// kernel and the kernel heap lives above.
+pub const USER_SPACE_START: u64 = 0x0000_0080_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+pub fn is_user_addr(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64();
+
+    addr >= USER_SPACE_START && addr < USER_SPACE_END
+}
+
+// Scratch page used to reach frames and inactive tables after boot
+const TEMPORARY_PAGE_ADDR: u64 = 0xcafe_d000;
+
+const IA32_EFER: u32 = 0xc000_0080;
+const EFER_NO_EXECUTE_ENABLE: u64 = 1 << 11;
+
+lazy_static! {
+    static ref ACTIVE_TABLE: Mutex<Option<(ActivePageTable, TemporaryPage)>> = { Mutex::new(None) };
+}
+
+/// Keeps the active table once the kernel has been remapped
+pub fn init_active_table(active_table: ActivePageTable) {
+    let temporary_page =
+        TemporaryPage::new(Page::containing_address(VirtAddr::new(TEMPORARY_PAGE_ADDR)));
+
+    *ACTIVE_TABLE.lock() = Some((active_table, temporary_page));
+}
+
+/// Runs `f` with the active table and the scratch page.
+/// Interrupts are disabled, `f` may redirect the recursive mapping and no
+/// other task must run meanwhile.
+pub fn with_active_table<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut ActivePageTable, &mut TemporaryPage) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some((ref mut active_table, ref mut temporary_page)) = *ACTIVE_TABLE.lock() {
+            f(active_table, temporary_page)
+        } else {
+            panic!("with_active_table(): Cannot get ACTIVE_TABLE");
+        }
+    })
+}
+
+/// Allows the NO_EXECUTE flag in page table entries
+pub fn enable_nxe_bit() {
+    unsafe {
+        let mut efer = Msr::new(IA32_EFER);
+        let value = efer.read();
+
+        efer.write(value | EFER_NO_EXECUTE_ENABLE);
+    }
+}
+
 pub unsafe fn get_page4_virt_ptr(virt_adr: VirtAddr) -> &'static mut PageTable {
     &mut *virt_adr.as_mut_ptr()
 }
@@ -44,15 +44,19 @@ impl ActivePageTable {
         PhysFrame::from_start_address(phys).unwrap()
     }
 
-    pub fn with<F>(
+    /// Runs `f` with the recursive mapping pointing to `inactive_page_table`.
+    /// Repoints the recursive entry of the loaded table: only while the boot
+    /// processor runs alone, other processors may load the same table.
+    pub fn with<F, R>(
         &mut self,
         inactive_page_table: &mut InactivePageTable,
         temporary_page: &mut TemporaryPage,
         f: F,
-    ) where
-        F: FnOnce(&mut RecursivePageTable<'static>),
+    ) -> R
+    where
+        F: FnOnce(&mut RecursivePageTable<'static>) -> R,
     {
-        {
+        let result = {
             let backup = PhysFrame::containing_address(
                 x86_64::registers::control::Cr3::read().0.start_address(),
             );
@@ -70,7 +74,7 @@ impl ActivePageTable {
 
             x86_64::instructions::tlb::flush_all();
 
-            f(self);
+            let result = f(self);
 
             p4_table[511].set_addr(
                 backup.start_address(),
@@ -78,9 +82,13 @@ impl ActivePageTable {
             );
 
             x86_64::instructions::tlb::flush_all();
-        }
+
+            result
+        };
 
         temporary_page.unmap(self);
+
+        result
     }
 
     pub fn switch(&mut self, new_page_table: &mut InactivePageTable) {
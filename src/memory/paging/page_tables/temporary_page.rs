@@ -41,7 +41,22 @@ impl TemporaryPage {
     }
 
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page);
+        // The page is mapped again to other frames, the stale TLB entry has
+        // to go
+        active_table
+            .unmap(self.page)
+            .expect("temporary page is not mapped")
+            .1
+            .flush();
+    }
+
+    /// Maps `frame` and gives access to its content
+    pub fn map_frame_data(
+        &mut self,
+        frame: UnusedPhysFrame,
+        active_table: &mut ActivePageTable,
+    ) -> &mut [u8; 4096] {
+        unsafe { &mut *(self.map(frame, active_table).as_mut_ptr()) }
     }
 
     pub fn map_table_frame(
@@ -1,5 +1,5 @@
 use x86_64::{
-    structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame},
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame},
     PhysAddr, VirtAddr,
 };
 
@@ -74,6 +74,30 @@ pub fn remap_kernel(active: &mut ActivePageTable, multiboot_information_address:
 
             super::helpers::identity_map_with(phys_frame, flags, mapper);
         }
+
+        // Remap boot modules, read only. The ELF loader reads them in place.
+        for module in boot_info.module_tags() {
+            let module_start = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
+                module.start_address() as u64,
+            ));
+            let module_end =
+                PhysFrame::containing_address(PhysAddr::new(module.end_address() as u64 - 1));
+
+            for frame in PhysFrame::range_inclusive(module_start, module_end) {
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+                    frame.start_address().as_u64(),
+                ));
+
+                // Modules are not always page aligned and may share a page
+                if mapper.translate_page(page).is_ok() {
+                    continue;
+                }
+
+                let phys_frame = unsafe { UnusedPhysFrame::new(frame) };
+
+                super::helpers::identity_map_with(phys_frame, PageTableFlags::PRESENT, mapper);
+            }
+        }
     });
 
     active.switch(&mut new_page_table_4);
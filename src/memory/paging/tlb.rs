@@ -0,0 +1,156 @@
+//! TLB shootdowns: a processor changing a mapping flushes it from the TLBs
+//! of the others.
+//!
+//! The pages are gathered in a `TlbBatch` while the tables are changed, then
+//! flushed everywhere at once, with the locks released: the other processors
+//! flush in an interrupt handler. Kernel pages are flushed on every
+//! processor, user pages on the ones running the same table.
+//!
+//! A frame that was mapped can only be freed after the flush.
+
+use core::sync::atomic::{self, AtomicU64, Ordering};
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{Page, PhysFrame},
+    VirtAddr,
+};
+
+use crate::percpu;
+use crate::smp::{self, ipi};
+
+pub use x86_64::instructions::tlb::{flush, flush_all};
+
+/// Pages flushed one by one, a bigger batch flushes the whole TLB
+const BATCH_PAGES: usize = 32;
+
+percpu! {
+    /// Table loaded by the processor, read by the others
+    static LOADED_TABLE: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Loads the table `p4_frame` on the current processor
+pub fn load_table(p4_frame: PhysFrame) {
+    // Set first: a shootdown missing the processor happens before it reads
+    // the table
+    LOADED_TABLE
+        .get()
+        .store(p4_frame.start_address().as_u64(), Ordering::SeqCst);
+
+    unsafe { Cr3::write(p4_frame, Cr3Flags::empty()) };
+}
+
+/// Processors running the table `p4_frame`, the current one included
+fn cpus_running(p4_frame: PhysFrame) -> u64 {
+    let table = p4_frame.start_address().as_u64();
+
+    (0..smp::cpu_count())
+        .filter(|&cpu| unsafe { LOADED_TABLE.get_for(cpu) }.load(Ordering::SeqCst) == table)
+        .fold(0, |mask, cpu| mask | 1 << cpu)
+}
+
+/// Pages whose mapping changed in the current table, to flush from every
+/// TLB that may hold them
+#[must_use = "the pages stay in the TLBs until the batch is flushed"]
+pub struct TlbBatch {
+    pages: [u64; BATCH_PAGES],
+    /// Pages added, may be more than `BATCH_PAGES`
+    count: usize,
+    kernel: bool,
+}
+
+impl TlbBatch {
+    pub const fn new() -> Self {
+        Self {
+            pages: [0; BATCH_PAGES],
+            count: 0,
+            kernel: false,
+        }
+    }
+
+    pub fn add(&mut self, page: Page) {
+        if let Some(slot) = self.pages.get_mut(self.count) {
+            *slot = page.start_address().as_u64();
+        }
+
+        self.count += 1;
+        self.kernel |= !super::is_user_addr(page.start_address());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Flushes the pages from the TLB of the current processor
+    fn flush_local(&self) {
+        if self.count > BATCH_PAGES {
+            flush_all();
+        } else {
+            for &addr in &self.pages[..self.count] {
+                flush(VirtAddr::new(addr));
+            }
+        }
+    }
+
+    /// Flushes the pages from every TLB that may hold them. No lock taken
+    /// with interrupts disabled must be held, see `smp::ipi`.
+    pub fn flush(self) {
+        if self.is_empty() {
+            return;
+        }
+
+        self.flush_local();
+
+        // The table changes must be visible before the loaded tables are read
+        atomic::fence(Ordering::SeqCst);
+
+        let targets = if self.kernel {
+            smp::online_mask()
+        } else {
+            cpus_running(Cr3::read().0)
+        };
+
+        ipi::call_function_mask(targets, &|| self.flush_local());
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_tlb_batch() {
+    use super::helpers;
+    use x86_64::structures::paging::PageTableFlags;
+
+    serial_print!("test_tlb_batch... ");
+
+    // Unused by the rest of the kernel, below the scratch pages
+    let addr = VirtAddr::new(0xcafa_0000);
+    let page: Page = Page::containing_address(addr);
+
+    helpers::alloc_page_with_flags(addr, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
+    // Every processor reads the page, its mapping is in their TLBs
+    ipi::smp_call_function(&|| unsafe {
+        core::ptr::read_volatile(addr.as_ptr::<u64>());
+    });
+
+    helpers::unmap_pages(addr, 1);
+
+    assert!(helpers::page_flags(page).is_none());
+
+    let mut batch = TlbBatch::new();
+
+    for index in 0..BATCH_PAGES as u64 + 1 {
+        batch.add(page + index);
+    }
+
+    // Too many pages, flushes the whole TLBs
+    assert_eq!(batch.count, BATCH_PAGES + 1);
+    assert!(batch.kernel);
+
+    batch.flush();
+
+    serial_println!("[ok]");
+}
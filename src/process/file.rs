@@ -0,0 +1,113 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use crate::syscall::Errno;
+use crate::{print, serial_print};
+
+// Highest number of resources a process can have open at once
+pub const MAX_FILES: usize = 64;
+
+/// Resource reachable from userland through a file descriptor.
+/// Operations a resource does not support fail with `EBADF`.
+pub trait File: Send + Sync {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+}
+
+/// Output to both the serial port and the VGA buffer
+pub struct Console;
+
+impl File for Console {
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let text = core::str::from_utf8(buf).map_err(|_| Errno::EINVAL)?;
+
+        serial_print!("{}", text);
+        print!("{}", text);
+
+        Ok(buf.len())
+    }
+}
+
+/// Open resources of a process, indexed by file descriptor
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        Self { files: vec![] }
+    }
+
+    /// Table with stdin, stdout and stderr open on the console
+    pub fn with_console() -> Self {
+        let console: Arc<dyn File> = Arc::new(Console);
+
+        Self {
+            files: vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Result<Arc<dyn File>, Errno> {
+        self.files
+            .get(fd as usize)
+            .and_then(|file| file.clone())
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Number of descriptors in use
+    pub fn open_count(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    pub fn is_open(&self, fd: u64) -> bool {
+        self.files.get(fd as usize).map_or(false, Option::is_some)
+    }
+
+    /// Stores `file` in the lowest free descriptor
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Result<u64, Errno> {
+        if let Some(fd) = self.files.iter().position(|file| file.is_none()) {
+            self.files[fd] = Some(file);
+
+            return Ok(fd as u64);
+        }
+
+        if self.files.len() >= MAX_FILES {
+            return Err(Errno::EMFILE);
+        }
+
+        self.files.push(Some(file));
+
+        Ok((self.files.len() - 1) as u64)
+    }
+
+    /// Stores `file` in `fd`, returning the resource it replaces
+    pub fn insert_at(
+        &mut self,
+        fd: u64,
+        file: Arc<dyn File>,
+    ) -> Result<Option<Arc<dyn File>>, Errno> {
+        let fd = fd as usize;
+
+        if fd >= MAX_FILES {
+            return Err(Errno::EBADF);
+        }
+
+        if fd >= self.files.len() {
+            self.files.resize(fd + 1, None);
+        }
+
+        Ok(self.files[fd].replace(file))
+    }
+
+    pub fn remove(&mut self, fd: u64) -> Result<Arc<dyn File>, Errno> {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(|file| file.take())
+            .ok_or(Errno::EBADF)
+    }
+}
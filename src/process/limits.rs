@@ -0,0 +1,96 @@
+//! Resource limits of a process.
+//!
+//! A child inherits the limits of its parent, except the children of the
+//! kernel which start with the defaults. A process can lower its limits but
+//! never raise them again.
+
+use super::MAX_FILES;
+use crate::syscall::Errno;
+
+/// User pages mapped in the address space
+pub const RLIMIT_RSS: u64 = 0;
+/// Open file descriptors, and separately open port handles
+pub const RLIMIT_NOFILE: u64 = 1;
+/// Children not collected by `wait` yet
+pub const RLIMIT_NPROC: u64 = 2;
+/// CPU time in seconds, SIGXCPU is sent past it and SIGKILL a second later
+pub const RLIMIT_CPU: u64 = 3;
+
+const RLIMIT_COUNT: usize = 4;
+
+pub const RLIM_INFINITY: u64 = core::u64::MAX;
+
+// 32 MiB of user memory
+const DEFAULT_MAX_RESIDENT_PAGES: u64 = 8192;
+const DEFAULT_MAX_CHILDREN: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    values: [u64; RLIMIT_COUNT],
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        let mut values = [RLIM_INFINITY; RLIMIT_COUNT];
+
+        values[RLIMIT_RSS as usize] = DEFAULT_MAX_RESIDENT_PAGES;
+        values[RLIMIT_NOFILE as usize] = MAX_FILES as u64;
+        values[RLIMIT_NPROC as usize] = DEFAULT_MAX_CHILDREN;
+
+        Self { values }
+    }
+
+    /// Limits of the kernel process
+    pub fn unlimited() -> Self {
+        Self {
+            values: [RLIM_INFINITY; RLIMIT_COUNT],
+        }
+    }
+
+    pub fn get(&self, resource: u64) -> Result<u64, Errno> {
+        self.values
+            .get(resource as usize)
+            .copied()
+            .ok_or(Errno::EINVAL)
+    }
+
+    /// Sets a limit, fails with `EPERM` if it would go up
+    pub fn lower(&mut self, resource: u64, value: u64) -> Result<(), Errno> {
+        let current = self
+            .values
+            .get_mut(resource as usize)
+            .ok_or(Errno::EINVAL)?;
+
+        if value > *current {
+            return Err(Errno::EPERM);
+        }
+
+        *current = value;
+
+        Ok(())
+    }
+
+    /// Limit of `resource`, which must be valid
+    pub fn max(&self, resource: u64) -> u64 {
+        self.values[resource as usize]
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_limits_only_go_down() {
+    serial_print!("test_limits_only_go_down... ");
+
+    let mut limits = Limits::new();
+
+    assert_eq!(limits.lower(RLIMIT_CPU, 10), Ok(()));
+    assert_eq!(limits.get(RLIMIT_CPU), Ok(10));
+    assert_eq!(limits.lower(RLIMIT_CPU, 11), Err(Errno::EPERM));
+    assert_eq!(limits.lower(RLIMIT_COUNT as u64, 0), Err(Errno::EINVAL));
+
+    serial_println!("[ok]");
+}
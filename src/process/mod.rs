@@ -0,0 +1,881 @@
+//! Processes: the owner of an address space and of open resources.
+//!
+//! Every task belongs to a process, kernel tasks to the kernel process. When
+//! a process exits it stays in the table as a zombie holding its exit code
+//! until its parent collects it with `wait`. Children of an exiting process
+//! are handed over to the kernel process.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{instructions::interrupts, structures::paging::Page, VirtAddr};
+
+use crate::ipc::port::{Capability, HandleTable};
+use crate::loader::{self, initramfs, LoadError};
+use crate::memory::paging::{cow, helpers, AddressSpace};
+use crate::schedule::{self, TaskId, WaitQueue};
+use crate::syscall::{Errno, SyscallFrame};
+use crate::{serial_println, usermode};
+
+mod file;
+pub mod limits;
+pub mod signal;
+
+pub use file::{Console, File, FileTable, MAX_FILES};
+pub use limits::Limits;
+pub use signal::Signals;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u64);
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Process of the boot task and of every kernel task, it never exits
+pub const KERNEL_PID: Pid = Pid(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Alive,
+    /// Exited, waiting for its parent to collect the exit code
+    Zombie(i32),
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub children: Vec<Pid>,
+    pub state: ProcessState,
+    /// Given to the kernel when its parent exited, nobody waits for it
+    orphan: bool,
+    /// Loaded when switching to one of its tasks, `None` for the kernel
+    pub address_space: Option<Arc<AddressSpace>>,
+    pub files: FileTable,
+    /// Capabilities to IPC ports
+    pub handles: HandleTable,
+    pub signals: Signals,
+    /// Tasks running for the process, it exits with the last one
+    pub threads: usize,
+    /// Set once the whole process is exiting
+    exit_code: Option<i32>,
+    pub limits: Limits,
+    /// User pages mapped in its address space
+    pub resident_pages: u64,
+    /// Timer ticks spent running its threads
+    pub cpu_ticks: u64,
+    /// Woken up when a child exits
+    child_exit: Arc<WaitQueue>,
+}
+
+impl Process {
+    /// Stores `file` in the lowest free descriptor, within the limit
+    pub fn open_file(&mut self, file: Arc<dyn File>) -> Result<u64, Errno> {
+        if self.files.open_count() as u64 >= self.limits.max(limits::RLIMIT_NOFILE) {
+            return Err(Errno::EMFILE);
+        }
+
+        self.files.insert(file)
+    }
+
+    /// Stores `file` in `fd` like `FileTable::insert_at`, within the limit
+    pub fn open_file_at(
+        &mut self,
+        fd: u64,
+        file: Arc<dyn File>,
+    ) -> Result<Option<Arc<dyn File>>, Errno> {
+        if !self.files.is_open(fd)
+            && self.files.open_count() as u64 >= self.limits.max(limits::RLIMIT_NOFILE)
+        {
+            return Err(Errno::EMFILE);
+        }
+
+        self.files.insert_at(fd, file)
+    }
+
+    /// Stores `capability` in the lowest free handle, within the limit
+    pub fn open_handle(&mut self, capability: Capability) -> Result<u64, Errno> {
+        if self.handles.open_count() as u64 >= self.limits.max(limits::RLIMIT_NOFILE) {
+            return Err(Errno::EMFILE);
+        }
+
+        self.handles.insert(capability)
+    }
+
+    fn new(
+        pid: Pid,
+        parent: Pid,
+        address_space: Option<Arc<AddressSpace>>,
+        files: FileTable,
+        handles: HandleTable,
+        signals: Signals,
+        limits: Limits,
+    ) -> Self {
+        Self {
+            pid,
+            parent,
+            children: vec![],
+            state: ProcessState::Alive,
+            orphan: false,
+            address_space,
+            files,
+            handles,
+            signals,
+            threads: 1,
+            exit_code: None,
+            limits,
+            resident_pages: 0,
+            cpu_ticks: 0,
+            child_exit: Arc::new(WaitQueue::new()),
+        }
+    }
+}
+
+/// What an exiting process leaves behind, released once the table is
+/// unlocked
+struct Exited {
+    address_space: Option<Arc<AddressSpace>>,
+    files: FileTable,
+    handles: HandleTable,
+    to_wake: Vec<Arc<WaitQueue>>,
+}
+
+lazy_static! {
+    static ref PROCESSES: Mutex<ProcessTable> = { Mutex::new(ProcessTable::new()) };
+}
+
+/// Runs `f` with the process table locked, interrupts disabled
+pub fn with_processes<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut ProcessTable) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut PROCESSES.lock()))
+}
+
+pub struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    next_pid: u64,
+}
+
+impl ProcessTable {
+    fn new() -> Self {
+        let mut processes = BTreeMap::new();
+
+        processes.insert(
+            KERNEL_PID,
+            Process::new(
+                KERNEL_PID,
+                KERNEL_PID,
+                None,
+                FileTable::with_console(),
+                HandleTable::new(),
+                Signals::new(),
+                Limits::unlimited(),
+            ),
+        );
+
+        Self {
+            processes,
+            next_pid: KERNEL_PID.0 + 1,
+        }
+    }
+
+    pub fn get(&self, pid: Pid) -> Option<&Process> {
+        self.processes.get(&pid)
+    }
+
+    pub fn get_mut(&mut self, pid: Pid) -> Option<&mut Process> {
+        self.processes.get_mut(&pid)
+    }
+
+    /// Fails with `EAGAIN` once `parent` reached its limit of children
+    fn check_child_limit(&self, parent: Pid) -> Result<(), Errno> {
+        let process = self.get(parent).expect("parent process does not exist");
+
+        if process.children.len() as u64 >= process.limits.max(limits::RLIMIT_NPROC) {
+            return Err(Errno::EAGAIN);
+        }
+
+        Ok(())
+    }
+
+    fn insert_child(
+        &mut self,
+        parent: Pid,
+        address_space: Option<Arc<AddressSpace>>,
+        files: FileTable,
+        handles: HandleTable,
+        signals: Signals,
+    ) -> Result<Pid, Errno> {
+        self.check_child_limit(parent)?;
+
+        let pid = Pid(self.next_pid);
+
+        self.next_pid += 1;
+
+        let limits = match parent {
+            KERNEL_PID => Limits::new(),
+            parent => {
+                self.get(parent)
+                    .expect("parent process does not exist")
+                    .limits
+            }
+        };
+
+        self.processes.insert(
+            pid,
+            Process::new(pid, parent, address_space, files, handles, signals, limits),
+        );
+        self.get_mut(parent)
+            .expect("parent process does not exist")
+            .children
+            .push(pid);
+
+        Ok(pid)
+    }
+
+    /// Turns `pid` into a zombie and gives its children to the kernel.
+    /// Nobody collects the orphans: the zombies among them are removed
+    /// right away, the others as soon as they exit.
+    fn exit(&mut self, pid: Pid, code: i32) -> Exited {
+        let (parent, orphan, children, address_space, files, handles) = {
+            let process = self.get_mut(pid).expect("exiting process does not exist");
+
+            process.state = ProcessState::Zombie(code);
+
+            (
+                process.parent,
+                process.orphan,
+                core::mem::replace(&mut process.children, vec![]),
+                process.address_space.take(),
+                core::mem::replace(&mut process.files, FileTable::new()),
+                core::mem::replace(&mut process.handles, HandleTable::new()),
+            )
+        };
+
+        for child in children {
+            let process = self.get_mut(child).unwrap();
+
+            if process.state != ProcessState::Alive {
+                self.processes.remove(&child);
+
+                continue;
+            }
+
+            process.parent = KERNEL_PID;
+            process.orphan = true;
+
+            self.get_mut(KERNEL_PID).unwrap().children.push(child);
+        }
+
+        let mut to_wake = vec![];
+
+        if orphan {
+            self.get_mut(KERNEL_PID)
+                .unwrap()
+                .children
+                .retain(|&child| child != pid);
+            self.processes.remove(&pid);
+        } else {
+            let parent_process = self.get_mut(parent).unwrap();
+
+            if parent != KERNEL_PID {
+                parent_process.signals.pending |= signal::mask(signal::SIGCHLD);
+            }
+
+            to_wake.push(parent_process.child_exit.clone());
+        }
+
+        Exited {
+            address_space,
+            files,
+            handles,
+            to_wake,
+        }
+    }
+
+    /// Removes an exited child of `parent` matching `target`.
+    /// Returns `None` if there are matching children but none exited yet.
+    fn reap_child(
+        &mut self,
+        parent: Pid,
+        target: Option<Pid>,
+    ) -> Option<Result<(Pid, i32), Errno>> {
+        let children = &self.get(parent).unwrap().children;
+
+        let mut matching = children
+            .iter()
+            .filter(|&&child| target.map_or(true, |target| target == child))
+            .peekable();
+
+        if matching.peek().is_none() {
+            return Some(Err(Errno::ECHILD));
+        }
+
+        let (pid, code) = matching.find_map(|&child| match self.get(child).unwrap().state {
+            ProcessState::Zombie(code) => Some((child, code)),
+            ProcessState::Alive => None,
+        })?;
+
+        self.get_mut(parent)
+            .unwrap()
+            .children
+            .retain(|&child| child != pid);
+        self.processes.remove(&pid);
+
+        Some(Ok((pid, code)))
+    }
+}
+
+pub fn current_pid() -> Pid {
+    schedule::current_process()
+}
+
+pub fn parent_pid(pid: Pid) -> Option<Pid> {
+    with_processes(|table| table.get(pid).map(|process| process.parent))
+}
+
+/// Creates a child of the current process with a single task running `f`.
+/// Fails with `EAGAIN` past the limit of children.
+pub fn create<F>(address_space: Option<Arc<AddressSpace>>, f: F) -> Result<Pid, Errno>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_child(
+        address_space,
+        FileTable::with_console(),
+        HandleTable::new(),
+        Signals::new(),
+        f,
+    )
+}
+
+fn spawn_child<F>(
+    address_space: Option<Arc<AddressSpace>>,
+    files: FileTable,
+    handles: HandleTable,
+    signals: Signals,
+    f: F,
+) -> Result<Pid, Errno>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let parent = current_pid();
+    let resident_pages = address_space
+        .as_ref()
+        .map_or(0, |address_space| address_space.user_page_count());
+
+    let pid = with_processes(|table| {
+        let pid = table.insert_child(parent, address_space.clone(), files, handles, signals)?;
+
+        table.get_mut(pid).unwrap().resident_pages = resident_pages as u64;
+
+        Ok(pid)
+    })?;
+
+    schedule::spawn_in(pid, address_space, f);
+
+    Ok(pid)
+}
+
+/// Duplicates the current process. The child shares the user memory
+/// copy-on-write and the open resources, and resumes userland from `frame`
+/// with 0 returned. The parent gets the pid of the child. Only the calling
+/// thread is duplicated. Fails with `EAGAIN` past the limit of children.
+pub fn fork(frame: &SyscallFrame) -> Result<Pid, Errno> {
+    let pid = current_pid();
+    let (files, handles, signals) = with_processes(|table| {
+        // Checked again when the child is added, before copying anything
+        table.check_child_limit(pid)?;
+
+        let process = table.get(pid).unwrap();
+
+        Ok((
+            process.files.clone(),
+            process.handles.clone(),
+            process.signals.inherit(),
+        ))
+    })?;
+
+    let address_space = AddressSpace::new();
+
+    address_space.map_user_pages(&cow::share_user_pages());
+
+    let mut child_frame = *frame;
+    let fs_base = schedule::fs_base();
+
+    child_frame.rax = 0;
+
+    spawn_child(
+        Some(Arc::new(address_space)),
+        files,
+        handles,
+        signals,
+        move || {
+            schedule::set_fs_base(fs_base);
+
+            unsafe { usermode::resume(&child_frame) }
+        },
+    )
+}
+
+/// Replaces the image of the current process with the executable at `path`
+/// in the initramfs, keeping its pid and open resources.
+/// Returns the entry point and the stack pointer to enter userland with, the
+/// old address space is already gone. On error the process is untouched,
+/// it fails with `EBUSY` while the process has other threads.
+pub fn exec(path: &str, args: &[String], env: &[String]) -> Result<(VirtAddr, VirtAddr), Errno> {
+    let data = initramfs::find(path).ok_or(Errno::ENOENT)?;
+
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let env: Vec<&str> = env.iter().map(|var| var.as_str()).collect();
+
+    let pid = current_pid();
+    let max_pages = with_processes(|table| table.get(pid).unwrap().limits.max(limits::RLIMIT_RSS));
+
+    let image = loader::load(data, &args, &env, max_pages).map_err(|err| match err {
+        LoadError::TooManyArguments => Errno::E2BIG,
+        LoadError::TooManyPages | LoadError::OutOfMemory => Errno::ENOMEM,
+        _ => Errno::ENOEXEC,
+    })?;
+
+    // The other threads would keep running the old image
+    if with_processes(|table| table.get(pid).unwrap().threads) > 1 {
+        return Err(Errno::EBUSY);
+    }
+
+    schedule::set_fs_base(0);
+
+    let resident_pages = image.address_space.user_page_count() as u64;
+
+    let old = interrupts::without_interrupts(|| {
+        let process = with_processes(|table| {
+            let process = table.get_mut(pid).unwrap();
+
+            process.signals.reset_handlers();
+            process.resident_pages = resident_pages;
+            process.address_space.replace(image.address_space.clone())
+        });
+        let task = schedule::replace_address_space(image.address_space.clone());
+
+        (process, task)
+    });
+
+    // Not loaded anymore, its frames go back to the allocator
+    drop(old);
+
+    Ok((image.entry, image.stack_pointer))
+}
+
+/// Looks up an open resource of the current process
+pub fn current_file(fd: u64) -> Result<Arc<dyn File>, Errno> {
+    let pid = current_pid();
+
+    with_processes(|table| table.get(pid).unwrap().files.get(fd))
+}
+
+/// Counts a new user page of the current process.
+/// Fails with `ENOMEM` once it reached its limit.
+pub fn charge_resident_page() -> Result<(), Errno> {
+    let pid = current_pid();
+
+    if pid == KERNEL_PID {
+        return Ok(());
+    }
+
+    with_processes(|table| {
+        let process = table.get_mut(pid).unwrap();
+
+        if process.resident_pages >= process.limits.max(limits::RLIMIT_RSS) {
+            return Err(Errno::ENOMEM);
+        }
+
+        process.resident_pages += 1;
+
+        Ok(())
+    })
+}
+
+/// Maps a zeroed user page at `addr` on first access, counted as a new page
+/// of the current process. Two threads may fault on the same page at once:
+/// the page is looked up again with the address space locked, the second
+/// one finds it mapped. Fails with `ENOMEM` once the process reached its
+/// limit.
+pub fn demand_page(addr: VirtAddr) -> Result<(), Errno> {
+    let map = || {
+        if helpers::page_flags(Page::containing_address(addr)).is_some() {
+            return Ok(());
+        }
+
+        charge_resident_page()?;
+        helpers::alloc_user_page(addr);
+
+        Ok(())
+    };
+
+    match schedule::current_address_space() {
+        Some(address_space) => address_space.with_fault_lock(map),
+        None => map(),
+    }
+}
+
+/// Charges a timer tick to the current process, from the timer interrupt.
+/// Past its CPU time limit it gets SIGXCPU, then SIGKILL a second later.
+pub fn account_tick() {
+    let pid = current_pid();
+
+    if pid == KERNEL_PID {
+        return;
+    }
+
+    with_processes(|table| {
+        // The last thread may be exiting
+        let process = match table.get_mut(pid) {
+            Some(process) => process,
+            None => return,
+        };
+
+        process.cpu_ticks += 1;
+
+        let limit = process
+            .limits
+            .max(limits::RLIMIT_CPU)
+            .saturating_mul(schedule::TICKS_PER_SECOND);
+
+        if process.cpu_ticks == limit.saturating_add(1) {
+            process.signals.pending |= signal::mask(signal::SIGXCPU);
+        } else if process.cpu_ticks == limit.saturating_add(1 + schedule::TICKS_PER_SECOND) {
+            process.signals.pending |= signal::mask(signal::SIGKILL);
+        }
+    })
+}
+
+/// Looks up a capability of the current process
+pub fn current_handle(handle: u64) -> Result<Capability, Errno> {
+    let pid = current_pid();
+
+    with_processes(|table| table.get(pid).unwrap().handles.get(handle))
+}
+
+/// Ends the current process with `code`. The other threads end when they
+/// next go back to userland, the parent is woken up once the last one is
+/// gone. A kernel task only ends itself, the kernel process never exits.
+pub fn exit_current(code: i32) -> ! {
+    let pid = current_pid();
+
+    if pid != KERNEL_PID {
+        with_processes(|table| {
+            table.get_mut(pid).unwrap().exit_code.get_or_insert(code);
+        });
+    }
+
+    exit_thread(code);
+}
+
+/// Ends the current thread. The process exits with its last thread, with
+/// `code` unless the whole process is already exiting.
+pub fn exit_thread(code: i32) -> ! {
+    let pid = current_pid();
+
+    if pid != KERNEL_PID {
+        let exited = with_processes(|table| {
+            let process = table.get_mut(pid).unwrap();
+
+            process.threads -= 1;
+
+            if process.threads > 0 {
+                return None;
+            }
+
+            let code = process.exit_code.unwrap_or(code);
+
+            Some((code, table.exit(pid, code)))
+        });
+
+        if let Some((code, exited)) = exited {
+            serial_println!("Process {} exited with code {}", pid, code);
+
+            // The task keeps its own reference on the address space, it stays
+            // loaded until the task is reaped
+            drop(exited.address_space);
+            drop(exited.files);
+            drop(exited.handles);
+
+            for queue in exited.to_wake {
+                queue.wake_all();
+            }
+        }
+    }
+
+    schedule::exit_current();
+}
+
+/// Ends the current thread if its process is exiting, on the way back to
+/// userland. Threads sleeping in the kernel only notice when they wake up.
+pub fn exit_thread_if_exiting() {
+    let pid = current_pid();
+
+    if pid == KERNEL_PID {
+        return;
+    }
+
+    if let Some(code) = with_processes(|table| table.get(pid).unwrap().exit_code) {
+        exit_thread(code);
+    }
+}
+
+/// Starts a thread of the current process in userland at `entry` with
+/// `stack_top`, `arg` in rdi and `tls` as FS base. It shares everything with
+/// the other threads but its registers. Returns its thread id.
+pub fn create_thread(entry: u64, stack_top: u64, arg: u64, tls: u64) -> Result<TaskId, Errno> {
+    let pid = current_pid();
+
+    let address_space = with_processes(|table| {
+        let process = table.get_mut(pid).unwrap();
+
+        if process.exit_code.is_some() {
+            return Err(Errno::ESRCH);
+        }
+
+        process.threads += 1;
+
+        Ok(process.address_space.clone())
+    })?;
+
+    let frame = SyscallFrame {
+        rip: entry,
+        rsp: stack_top,
+        rdi: arg,
+        ..SyscallFrame::default()
+    };
+
+    Ok(schedule::spawn_in(pid, address_space, move || {
+        schedule::set_fs_base(tls);
+
+        unsafe { usermode::resume(&frame) }
+    }))
+}
+
+/// Waits for a child of the current process to exit, any child if `target`
+/// is `None`. The zombie is removed and its pid and exit code returned.
+pub fn wait(target: Option<Pid>) -> Result<(Pid, i32), Errno> {
+    let pid = current_pid();
+    let child_exit = with_processes(|table| table.get(pid).unwrap().child_exit.clone());
+
+    let mut result = None;
+
+    child_exit.wait_until(|| {
+        result = with_processes(|table| table.reap_child(pid, target));
+
+        result.is_some()
+    });
+
+    result.unwrap()
+}
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_wait_for_children() {
+    serial_print!("test_wait_for_children... ");
+
+    let mut pids = vec![];
+
+    for _ in 0..2 {
+        let image = loader::load(&loader::test_image(), &["test"], &[], limits::RLIM_INFINITY)
+            .expect("load failed");
+
+        pids.push(
+            usermode::spawn_in(image.address_space, image.entry, image.stack_pointer).unwrap(),
+        );
+    }
+
+    for &pid in &pids {
+        assert_eq!(wait(Some(pid)), Ok((pid, 7)));
+        assert!(with_processes(|table| table.get(pid).is_none()));
+    }
+
+    assert_eq!(wait(Some(pids[0])), Err(Errno::ECHILD));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_fork_copy_on_write() {
+    serial_print!("test_fork_copy_on_write... ");
+
+    // The child overwrites a stack slot and exits with it, the parent waits
+    // and exits with its own copy * 10 + the status of the child:
+    //     push 1 ; mov eax, SYS_FORK ; syscall ; test rax, rax ; jnz parent
+    //     mov qword [rsp], 2 ; mov edi, [rsp] ; xor eax, eax ; syscall
+    // parent:
+    //     sub rsp, 8 ; mov rdi, rax ; mov rsi, rsp ; mov eax, SYS_WAIT ; syscall
+    //     mov edi, [rsp] ; mov rax, [rsp + 8] ; imul eax, eax, 10 ; add edi, eax
+    //     xor eax, eax ; syscall
+    let code = [
+        0x6a, 0x01, 0xb8, 0x09, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48, 0x85, 0xc0, 0x75, 0x0f, 0x48,
+        0xc7, 0x04, 0x24, 0x02, 0x00, 0x00, 0x00, 0x8b, 0x3c, 0x24, 0x31, 0xc0, 0x0f, 0x05, 0x48,
+        0x83, 0xec, 0x08, 0x48, 0x89, 0xc7, 0x48, 0x89, 0xe6, 0xb8, 0x08, 0x00, 0x00, 0x00, 0x0f,
+        0x05, 0x8b, 0x3c, 0x24, 0x48, 0x8b, 0x44, 0x24, 0x08, 0x6b, 0xc0, 0x0a, 0x01, 0xc7, 0x31,
+        0xc0, 0x0f, 0x05,
+    ];
+
+    let image = loader::load(
+        &loader::test_image_with(&code),
+        &["fork"],
+        &[],
+        limits::RLIM_INFINITY,
+    )
+    .expect("load failed");
+    let pid = usermode::spawn_in(image.address_space, image.entry, image.stack_pointer).unwrap();
+
+    assert_eq!(wait(Some(pid)), Ok((pid, 12)));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_exec_keeps_pid() {
+    use alloc::boxed::Box;
+
+    serial_print!("test_exec_keeps_pid... ");
+
+    initramfs::insert(
+        "/bin/exit7",
+        Box::leak(loader::test_image().into_boxed_slice()),
+    );
+
+    // lea rdi, [rip + path] ; xor esi, esi ; xor edx, edx ; mov eax, SYS_EXECVE
+    // syscall ; mov edi, 1 ; xor eax, eax ; syscall
+    // path: "/bin/exit7"
+    let code = [
+        0x48, 0x8d, 0x3d, 0x14, 0x00, 0x00, 0x00, 0x31, 0xf6, 0x31, 0xd2, 0xb8, 0x0a, 0x00, 0x00,
+        0x00, 0x0f, 0x05, 0xbf, 0x01, 0x00, 0x00, 0x00, 0x31, 0xc0, 0x0f, 0x05, b'/', b'b', b'i',
+        b'n', b'/', b'e', b'x', b'i', b't', b'7', 0x00,
+    ];
+
+    let image = loader::load(
+        &loader::test_image_with(&code),
+        &["exec"],
+        &[],
+        limits::RLIM_INFINITY,
+    )
+    .expect("load failed");
+    let pid = usermode::spawn_in(image.address_space, image.entry, image.stack_pointer).unwrap();
+
+    assert_eq!(wait(Some(pid)), Ok((pid, 7)));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_threads() {
+    serial_print!("test_threads... ");
+
+    // The main thread starts a thread and ends, the process lives on until
+    // the thread exits with its argument plus the word at its TLS base:
+    //
+    //     movabs rbx, USER_SPACE_START + 0x100_0000 ; mov dword [rbx], 30
+    //     lea rdi, [rip + thread] ; lea rsi, [rbx + 0x2000] ; mov edx, 12
+    //     mov r10, rbx ; mov eax, SYS_THREAD_CREATE ; syscall
+    //     xor edi, edi ; mov eax, SYS_THREAD_EXIT ; syscall
+    // thread:
+    //     mov r12, rdi ; mov eax, SYS_YIELD ; syscall
+    //     mov edi, fs:[0] ; add edi, r12d ; mov eax, SYS_THREAD_EXIT ; syscall
+    let code = [
+        0x48, 0xbb, 0x00, 0x00, 0x00, 0x01, 0x80, 0x00, 0x00, 0x00, 0xc7, 0x03, 0x1e, 0x00, 0x00,
+        0x00, 0x48, 0x8d, 0x3d, 0x1f, 0x00, 0x00, 0x00, 0x48, 0x8d, 0xb3, 0x00, 0x20, 0x00, 0x00,
+        0xba, 0x0c, 0x00, 0x00, 0x00, 0x49, 0x89, 0xda, 0xb8, 0x1b, 0x00, 0x00, 0x00, 0x0f, 0x05,
+        0x31, 0xff, 0xb8, 0x1c, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x49, 0x89, 0xfc, 0xb8, 0x02, 0x00,
+        0x00, 0x00, 0x0f, 0x05, 0x64, 0x8b, 0x3c, 0x25, 0x00, 0x00, 0x00, 0x00, 0x44, 0x01, 0xe7,
+        0xb8, 0x1c, 0x00, 0x00, 0x00, 0x0f, 0x05,
+    ];
+
+    let image = loader::load(
+        &loader::test_image_with(&code),
+        &["threads"],
+        &[],
+        limits::RLIM_INFINITY,
+    )
+    .expect("load failed");
+    let pid = usermode::spawn_in(image.address_space, image.entry, image.stack_pointer).unwrap();
+
+    assert_eq!(wait(Some(pid)), Ok((pid, 42)));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_resident_page_limit() {
+    serial_print!("test_resident_page_limit... ");
+
+    // setrlimit(RLIMIT_RSS, 4) then touch one new page after the other:
+    //
+    //     xor edi, edi ; mov esi, 4 ; mov eax, SYS_SETRLIMIT ; syscall
+    //     movabs rbx, USER_SPACE_START + 0x100_0000
+    // 1:  mov byte [rbx], 1 ; add rbx, 0x1000 ; jmp 1b
+    let code = [
+        0x31, 0xff, 0xbe, 0x04, 0x00, 0x00, 0x00, 0xb8, 0x20, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48,
+        0xbb, 0x00, 0x00, 0x00, 0x01, 0x80, 0x00, 0x00, 0x00, 0xc6, 0x03, 0x01, 0x48, 0x81, 0xc3,
+        0x00, 0x10, 0x00, 0x00, 0xeb, 0xf4,
+    ];
+
+    let image = loader::load(
+        &loader::test_image_with(&code),
+        &["rss"],
+        &[],
+        limits::RLIM_INFINITY,
+    )
+    .expect("load failed");
+    let pid = usermode::spawn_in(image.address_space, image.entry, image.stack_pointer).unwrap();
+
+    assert_eq!(wait(Some(pid)), Ok((pid, -(signal::SIGSEGV as i32))));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_orphans_are_removed() {
+    serial_print!("test_orphans_are_removed... ");
+
+    let mut table = ProcessTable::new();
+    let spawn = |table: &mut ProcessTable, parent: Pid| -> Pid {
+        table
+            .insert_child(
+                parent,
+                None,
+                FileTable::new(),
+                HandleTable::new(),
+                Signals::new(),
+            )
+            .unwrap()
+    };
+
+    let parent = spawn(&mut table, KERNEL_PID);
+    let zombie = spawn(&mut table, parent);
+    let alive = spawn(&mut table, parent);
+
+    drop(table.exit(zombie, 1));
+    drop(table.exit(parent, 2));
+
+    // The parent waits for its own parent, the orphan zombie is gone
+    assert_eq!(
+        table.get(parent).map(|process| process.state),
+        Some(ProcessState::Zombie(2))
+    );
+    assert!(table.get(zombie).is_none());
+    assert_eq!(table.get(alive).unwrap().parent, KERNEL_PID);
+
+    drop(table.exit(alive, 3));
+
+    assert!(table.get(alive).is_none());
+    assert!(!table.get(KERNEL_PID).unwrap().children.contains(&alive));
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,549 @@
+//! POSIX style signals.
+//!
+//! Signals are kept pending in the process and acted upon when one of its
+//! tasks goes back to userland:
+//! - at the end of every syscall, where all the actions are possible,
+//! - after a timer interrupt in ring 3, where only the default actions are
+//!   taken: the registers of the interrupted code are not saved there, the
+//!   signals with a handler wait for the next syscall,
+//! - right away for the exceptions raised by userland.
+//!
+//! A task sleeping in the kernel is not interrupted, the signal is acted
+//! upon once the syscall completes.
+//!
+//! A user handler is called with the signal number in rdi and a
+//! `SignalFrame` on the user stack. It returns to the restorer given to
+//! `sigaction`, which must call `sigreturn`.
+
+use alloc::sync::Arc;
+use core::mem::size_of;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{current_pid, exit_current, with_processes, Pid, ProcessState, KERNEL_PID};
+use crate::interrupts::TrapFrame;
+use crate::memory::paging;
+use crate::schedule::WaitQueue;
+use crate::syscall::{user_ptr, Errno, SyscallFrame};
+use crate::{serial_println, usermode};
+
+pub type Signal = u32;
+
+pub const NSIG: usize = 32;
+
+pub const SIGHUP: Signal = 1;
+pub const SIGINT: Signal = 2;
+pub const SIGQUIT: Signal = 3;
+pub const SIGILL: Signal = 4;
+pub const SIGTRAP: Signal = 5;
+pub const SIGABRT: Signal = 6;
+pub const SIGBUS: Signal = 7;
+pub const SIGFPE: Signal = 8;
+pub const SIGKILL: Signal = 9;
+pub const SIGUSR1: Signal = 10;
+pub const SIGSEGV: Signal = 11;
+pub const SIGUSR2: Signal = 12;
+pub const SIGPIPE: Signal = 13;
+pub const SIGALRM: Signal = 14;
+pub const SIGTERM: Signal = 15;
+pub const SIGCHLD: Signal = 17;
+pub const SIGCONT: Signal = 18;
+pub const SIGSTOP: Signal = 19;
+pub const SIGTSTP: Signal = 20;
+pub const SIGXCPU: Signal = 24;
+
+// Handler values of `sigaction`
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// Operations of `sigprocmask`
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+// Bytes below the user stack pointer the interrupted code may be using
+const RED_ZONE: u64 = 128;
+
+// Flags a handler may change through the saved frame: CF, PF, AF, ZF, SF,
+// DF and OF
+const USER_RFLAGS: u64 = 0xcd5;
+const RFLAGS_RESERVED: u64 = 0x2;
+const RFLAGS_INTERRUPT_FLAG: u64 = 0x200;
+
+pub fn mask(signal: Signal) -> u64 {
+    1 << signal
+}
+
+/// Neither blocked, ignored nor handled
+const UNCATCHABLE: u64 = (1 << SIGKILL) | (1 << SIGSTOP);
+
+const STOP_SIGNALS: u64 = (1 << SIGSTOP) | (1 << SIGTSTP);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    /// Terminate after dumping the registers on the serial port
+    CoreDump,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+pub fn default_action(signal: Signal) -> DefaultAction {
+    match signal {
+        SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU => {
+            DefaultAction::CoreDump
+        }
+        SIGCHLD => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP => DefaultAction::Stop,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigHandler {
+    Default,
+    Ignore,
+    User {
+        handler: u64,
+        /// Signals blocked while the handler runs, on top of the signal itself
+        mask: u64,
+        restorer: u64,
+    },
+}
+
+/// Signal state of a process
+pub struct Signals {
+    pub pending: u64,
+    pub blocked: u64,
+    handlers: [SigHandler; NSIG],
+    stopped: bool,
+    /// Woken up when the process is continued
+    continued: Arc<WaitQueue>,
+}
+
+impl Signals {
+    pub fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            handlers: [SigHandler::Default; NSIG],
+            stopped: false,
+            continued: Arc::new(WaitQueue::new()),
+        }
+    }
+
+    /// State of a forked child: same handlers and mask, nothing pending
+    pub fn inherit(&self) -> Self {
+        Self {
+            blocked: self.blocked,
+            handlers: self.handlers,
+            ..Self::new()
+        }
+    }
+
+    /// The handlers are gone with the old image, ignored signals stay ignored
+    pub fn reset_handlers(&mut self) {
+        for handler in self.handlers.iter_mut() {
+            if let SigHandler::User { .. } = handler {
+                *handler = SigHandler::Default;
+            }
+        }
+    }
+
+    pub fn handler(&self, signal: Signal) -> SigHandler {
+        self.handlers[signal as usize]
+    }
+
+    /// Takes the lowest pending signal that is not blocked. With
+    /// `with_handlers` false, the signals caught by a handler are left
+    /// pending.
+    fn take_next(&mut self, with_handlers: bool) -> Option<(Signal, SigHandler)> {
+        let deliverable = self.pending & (!self.blocked | UNCATCHABLE);
+
+        let signal = (1..NSIG as Signal)
+            .filter(|&signal| deliverable & mask(signal) != 0)
+            .find(|&signal| {
+                with_handlers
+                    || match self.handler(signal) {
+                        SigHandler::User { .. } => false,
+                        _ => true,
+                    }
+            })?;
+
+        self.pending &= !mask(signal);
+
+        Some((signal, self.handler(signal)))
+    }
+}
+
+/// Saved on the user stack while a handler runs
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SignalFrame {
+    /// Return address of the handler
+    pub restorer: u64,
+    pub signal: u64,
+    /// Mask restored by `sigreturn`
+    pub blocked: u64,
+    pub registers: SyscallFrame,
+}
+
+fn valid_signal(signal: u64) -> Result<Signal, Errno> {
+    if signal == 0 || signal >= NSIG as u64 {
+        return Err(Errno::EINVAL);
+    }
+
+    Ok(signal as Signal)
+}
+
+/// Makes `signal` pending in `pid`. A signal 0 only checks that the process
+/// exists.
+pub fn send(pid: Pid, signal: u64) -> Result<(), Errno> {
+    if pid == KERNEL_PID {
+        return Err(Errno::EPERM);
+    }
+
+    let continued = with_processes(|table| {
+        let process = table.get_mut(pid).ok_or(Errno::ESRCH)?;
+
+        if process.state != ProcessState::Alive {
+            return Err(Errno::ESRCH);
+        }
+
+        if signal == 0 {
+            return Ok(None);
+        }
+
+        let signal = valid_signal(signal)?;
+        let signals = &mut process.signals;
+
+        signals.pending |= mask(signal);
+
+        if STOP_SIGNALS & mask(signal) != 0 {
+            signals.pending &= !mask(SIGCONT);
+        }
+
+        if signal == SIGCONT || signal == SIGKILL {
+            signals.pending &= !STOP_SIGNALS;
+            signals.stopped = false;
+
+            return Ok(Some(signals.continued.clone()));
+        }
+
+        Ok(None)
+    })?;
+
+    if let Some(continued) = continued {
+        continued.wake_all();
+    }
+
+    Ok(())
+}
+
+/// Installs the handler of `signal` for the current process and returns the
+/// previous one
+pub fn set_handler(signal: u64, handler: SigHandler) -> Result<SigHandler, Errno> {
+    let signal = valid_signal(signal)?;
+
+    if UNCATCHABLE & mask(signal) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let pid = current_pid();
+
+    with_processes(|table| {
+        let signals = &mut table.get_mut(pid).unwrap().signals;
+        let old = signals.handler(signal);
+
+        signals.handlers[signal as usize] = handler;
+
+        // A pending signal that is now ignored is discarded
+        if handler == SigHandler::Ignore {
+            signals.pending &= !mask(signal);
+        }
+
+        Ok(old)
+    })
+}
+
+/// Changes the blocked mask of the current process, returns the old one
+pub fn set_blocked(how: u64, set: u64) -> Result<u64, Errno> {
+    let pid = current_pid();
+
+    with_processes(|table| {
+        let signals = &mut table.get_mut(pid).unwrap().signals;
+        let old = signals.blocked;
+
+        signals.blocked = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(Errno::EINVAL),
+        } & !UNCATCHABLE;
+
+        Ok(old)
+    })
+}
+
+fn take_next(with_handlers: bool) -> Option<(Signal, SigHandler)> {
+    let pid = current_pid();
+
+    if pid == KERNEL_PID {
+        return None;
+    }
+
+    with_processes(|table| table.get_mut(pid).unwrap().signals.take_next(with_handlers))
+}
+
+/// Sleeps until the current process gets SIGCONT or SIGKILL
+fn stop_current() {
+    let pid = current_pid();
+
+    let continued = with_processes(|table| {
+        let signals = &mut table.get_mut(pid).unwrap().signals;
+
+        signals.stopped = true;
+        signals.continued.clone()
+    });
+
+    serial_println!("Process {} stopped", pid);
+
+    continued.wait_until(|| with_processes(|table| !table.get(pid).unwrap().signals.stopped));
+}
+
+fn default_action_current(signal: Signal, frame: &SyscallFrame) {
+    match default_action(signal) {
+        DefaultAction::Terminate => exit_current(-(signal as i32)),
+        DefaultAction::CoreDump => {
+            serial_println!(
+                "Process {} got signal {}, core dumped: {:#x?}",
+                current_pid(),
+                signal,
+                frame
+            );
+
+            exit_current(-(signal as i32));
+        }
+        DefaultAction::Stop => stop_current(),
+        DefaultAction::Ignore | DefaultAction::Continue => (),
+    }
+}
+
+/// Pushes a `SignalFrame` on the user stack and makes `frame` return to
+/// the handler
+fn setup_handler(
+    frame: &mut SyscallFrame,
+    signal: Signal,
+    handler: u64,
+    handler_mask: u64,
+    restorer: u64,
+) {
+    let pid = current_pid();
+    let blocked = with_processes(|table| table.get(pid).unwrap().signals.blocked);
+
+    let signal_frame = SignalFrame {
+        restorer,
+        signal: u64::from(signal),
+        blocked,
+        registers: *frame,
+    };
+
+    // Aligned like after a call: the handler sees rsp + 8 on 16 bytes
+    let stack_pointer = frame
+        .rsp
+        .checked_sub(RED_ZONE + size_of::<SignalFrame>() as u64)
+        .map(|sp| (sp & !0xf) - 8);
+
+    let written = stack_pointer
+        .ok_or(Errno::EFAULT)
+        .and_then(|sp| user_ptr::write_user(sp, signal_frame));
+
+    if written.is_err() {
+        serial_println!(
+            "Process {}: cannot push the frame of signal {}",
+            pid,
+            signal
+        );
+
+        exit_current(-(SIGSEGV as i32));
+    }
+
+    with_processes(|table| {
+        table.get_mut(pid).unwrap().signals.blocked |= (handler_mask | mask(signal)) & !UNCATCHABLE;
+    });
+
+    frame.rip = handler;
+    frame.rsp = stack_pointer.unwrap();
+    frame.rdi = u64::from(signal);
+}
+
+/// Acts on the pending signals of the current process before it goes back
+/// to userland with `frame`. Returns once a handler has been set up or when
+/// nothing is left to deliver.
+pub fn deliver(frame: &mut SyscallFrame) {
+    super::exit_thread_if_exiting();
+
+    while let Some((signal, handler)) = take_next(true) {
+        match handler {
+            SigHandler::Ignore => (),
+            SigHandler::Default => default_action_current(signal, frame),
+            SigHandler::User {
+                handler,
+                mask,
+                restorer,
+            } => {
+                setup_handler(frame, signal, handler, mask, restorer);
+
+                return;
+            }
+        }
+    }
+}
+
+/// Takes the default actions of the pending signals, from an interrupt of
+/// userland
+pub fn deliver_default_actions(stack_frame: &InterruptStackFrame) {
+    super::exit_thread_if_exiting();
+
+    let frame = SyscallFrame {
+        rip: stack_frame.instruction_pointer.as_u64(),
+        rsp: stack_frame.stack_pointer.as_u64(),
+        rflags: stack_frame.cpu_flags,
+        ..SyscallFrame::default()
+    };
+
+    while let Some((signal, handler)) = take_next(false) {
+        if handler == SigHandler::Default {
+            default_action_current(signal, &frame);
+        }
+    }
+}
+
+/// Sends `signal` for the faulting instruction of userland at `trap_frame`
+/// and goes back to userland through its handler, or exits.
+/// The signal cannot be blocked or ignored, the instruction would fault
+/// again. Like after a syscall, rcx and r11 are not restored.
+pub fn raise_exception(trap_frame: &TrapFrame, signal: Signal) -> ! {
+    let pid = current_pid();
+
+    with_processes(|table| {
+        let signals = &mut table.get_mut(pid).unwrap().signals;
+
+        if signals.blocked & mask(signal) != 0 || signals.handler(signal) == SigHandler::Ignore {
+            signals.blocked &= !mask(signal);
+            signals.handlers[signal as usize] = SigHandler::Default;
+        }
+
+        signals.pending |= mask(signal);
+    });
+
+    let mut frame = SyscallFrame::from(trap_frame);
+
+    deliver(&mut frame);
+
+    // The kernel stack of the exception is dropped
+    unsafe { usermode::resume(&frame) }
+}
+
+/// Restores the registers and the mask saved before calling a handler.
+/// Returns the restored rax, stored back by the syscall dispatcher.
+pub fn sigreturn(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let pid = current_pid();
+
+    // The handler returned to the restorer, popping its address
+    let addr = frame.rsp.wrapping_sub(size_of::<u64>() as u64);
+
+    let signal_frame: SignalFrame = match user_ptr::read_user(addr) {
+        Ok(signal_frame) => signal_frame,
+        Err(_) => {
+            serial_println!("Process {}: bad signal frame at {:#x}", pid, addr);
+
+            exit_current(-(SIGSEGV as i32));
+        }
+    };
+
+    let mut registers = signal_frame.registers;
+
+    // Returning with SYSRET to a non canonical address would fault in ring 0
+    if registers.rip < paging::USER_SPACE_START || registers.rip >= paging::USER_SPACE_END {
+        serial_println!("Process {}: bad return address in signal frame", pid);
+
+        exit_current(-(SIGSEGV as i32));
+    }
+
+    registers.rflags = (registers.rflags & USER_RFLAGS) | RFLAGS_RESERVED | RFLAGS_INTERRUPT_FLAG;
+
+    with_processes(|table| {
+        table.get_mut(pid).unwrap().signals.blocked = signal_frame.blocked & !UNCATCHABLE;
+    });
+
+    *frame = registers;
+
+    Ok(frame.rax)
+}
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_signal_handler_and_sigreturn() {
+    use crate::loader;
+
+    serial_print!("test_signal_handler_and_sigreturn... ");
+
+    // Installs a handler for SIGUSR1 and sends it to itself. The handler
+    // stores the signal number at [r12], sigreturn restores rax (0 from
+    // kill) and the process exits with [r12] + rax * 100:
+    //     sigaction(SIGUSR1, handler, 0, restorer)
+    //     push 0 ; mov r12, rsp ; kill(getpid(), SIGUSR1)
+    //     imul eax, eax, 100 ; mov edi, [rsp] ; add edi, eax ; exit
+    // handler:  mov [r12], rdi ; ret
+    // restorer: mov eax, SYS_SIGRETURN ; syscall
+    let code = [
+        0xbf, 0x0a, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x35, 0x36, 0x00, 0x00, 0x00, 0x31, 0xd2, 0x4c,
+        0x8d, 0x15, 0x32, 0x00, 0x00, 0x00, 0xb8, 0x0c, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x6a, 0x00,
+        0x49, 0x89, 0xe4, 0xb8, 0x06, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x89, 0xc7, 0xbe, 0x0a, 0x00,
+        0x00, 0x00, 0xb8, 0x0b, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x6b, 0xc0, 0x64, 0x8b, 0x3c, 0x24,
+        0x01, 0xc7, 0x31, 0xc0, 0x0f, 0x05, 0x49, 0x89, 0x3c, 0x24, 0xc3, 0xb8, 0x0e, 0x00, 0x00,
+        0x00, 0x0f, 0x05,
+    ];
+
+    let image = loader::load(
+        &loader::test_image_with(&code),
+        &["signal"],
+        &[],
+        super::limits::RLIM_INFINITY,
+    )
+    .expect("load failed");
+    let pid = usermode::spawn_in(image.address_space, image.entry, image.stack_pointer).unwrap();
+
+    assert_eq!(super::wait(Some(pid)), Ok((pid, SIGUSR1 as i32)));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_kill_looping_process() {
+    use crate::loader;
+
+    serial_print!("test_kill_looping_process... ");
+
+    // jmp $
+    let image = loader::load(
+        &loader::test_image_with(&[0xeb, 0xfe]),
+        &["loop"],
+        &[],
+        super::limits::RLIM_INFINITY,
+    )
+    .expect("load failed");
+    let pid = usermode::spawn_in(image.address_space, image.entry, image.stack_pointer).unwrap();
+
+    assert_eq!(send(pid, u64::from(SIGKILL)), Ok(()));
+    assert_eq!(super::wait(Some(pid)), Ok((pid, -(SIGKILL as i32))));
+    assert_eq!(send(pid, u64::from(SIGKILL)), Err(Errno::ESRCH));
+
+    serial_println!("[ok]");
+}
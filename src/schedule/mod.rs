@@ -0,0 +1,915 @@
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::{control::Cr3, model_specific::Msr},
+    structures::paging::PhysFrame,
+    PhysAddr, VirtAddr,
+};
+
+use crate::memory::paging::{tlb, AddressSpace};
+use crate::percpu;
+use crate::process::{Pid, KERNEL_PID};
+use crate::serial_println;
+use crate::smp::{cpu, ipi};
+
+mod task;
+mod wait_queue;
+
+pub use task::{Task, TaskId, TaskInfo, TaskState, TaskStats, KERNEL_STACK_SIZE};
+pub use wait_queue::WaitQueue;
+
+// Number of timer ticks a task can run before being preempted
+pub const TIME_SLICE: u64 = 2;
+
+/// Timer interrupts per second
+pub const TICKS_PER_SECOND: u64 = crate::time::HZ;
+
+const IA32_FS_BASE: u32 = 0xc000_0100;
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn enable_and_hlt();
+}
+
+// The timer starts firing before the heap exists, ticks are ignored until the
+// scheduler is set up
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Timer ticks counted by the boot processor, the others catch up with the
+/// clock while its tick is stopped
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Processors with a queue, added in the order of their ids
+static CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Table loaded for the tasks without an address space of their own
+static KERNEL_TABLE: AtomicU64 = AtomicU64::new(0);
+
+percpu! {
+    /// Task running on the processor, read without the queue lock
+    static CURRENT_TASK: Cell<TaskId> = Cell::new(TaskId(0));
+
+    /// Tasks of the processor. Only locked by the processor itself, but to
+    /// queue a task woken up by another one. The heap is used with the lock
+    /// held, to add tasks and timers.
+    static QUEUE: Mutex<Option<CpuQueue>> = Mutex::new(None);
+
+    /// Length of the run queue, read without the lock to place new tasks
+    static READY: AtomicUsize = AtomicUsize::new(0);
+}
+
+lazy_static! {
+    /// Processor of every task, to find the queue of a task to wake up.
+    /// Only locked with interrupts disabled, never with a queue locked.
+    static ref TASK_CPUS: Mutex<BTreeMap<TaskId, usize>> = { Mutex::new(BTreeMap::new()) };
+}
+
+/// Runs `f` with the queue of the current processor locked.
+/// Interrupts are disabled for the duration so the timer cannot try to
+/// reschedule while we hold the lock.
+fn with_queue<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut CpuQueue) -> R,
+{
+    interrupts::without_interrupts(|| {
+        f(QUEUE
+            .get()
+            .lock()
+            .as_mut()
+            .expect("scheduler not set up on this processor"))
+    })
+}
+
+/// Runs `f` with the queue of the processor `cpu` locked
+fn with_queue_of<F, R>(cpu: usize, f: F) -> R
+where
+    F: FnOnce(&mut CpuQueue) -> R,
+{
+    interrupts::without_interrupts(|| {
+        // Behind its lock, see `QUEUE`
+        let queue = unsafe { QUEUE.get_for(cpu) };
+
+        f(queue
+            .lock()
+            .as_mut()
+            .expect("scheduler not set up on the processor"))
+    })
+}
+
+/// Processor running `id`, `None` once it has been reaped
+fn task_cpu(id: TaskId) -> Option<usize> {
+    interrupts::without_interrupts(|| TASK_CPUS.lock().get(&id).copied())
+}
+
+/// Scheduling state of one processor. A task stays on the processor it was
+/// given, so that it never runs on two at once: its queue owns it.
+struct CpuQueue {
+    cpu: usize,
+    tasks: BTreeMap<TaskId, Box<Task>>,
+    run_queue: VecDeque<TaskId>,
+    idle: TaskId,
+    slice_start: u64,
+    dead: Vec<TaskId>,
+    /// Tasks to wake up once the tick count reaches the deadline
+    timers: Vec<(u64, TaskId)>,
+}
+
+impl CpuQueue {
+    fn new(cpu: usize, idle: TaskId) -> Self {
+        Self {
+            cpu,
+            tasks: BTreeMap::new(),
+            run_queue: VecDeque::new(),
+            idle,
+            slice_start: ticks(),
+            dead: vec![],
+            timers: vec![],
+        }
+    }
+
+    /// Task running on the processor
+    fn current(&self) -> TaskId {
+        CURRENT_TASK.get().get()
+    }
+
+    fn task(&self, id: TaskId) -> Option<&Task> {
+        self.tasks.get(&id).map(|task| &**task)
+    }
+
+    fn task_mut(&mut self, id: TaskId) -> Option<&mut Task> {
+        self.tasks.get_mut(&id).map(|task| &mut **task)
+    }
+
+    fn current_task_mut(&mut self) -> &mut Task {
+        let current = self.current();
+
+        self.task_mut(current)
+            .expect("current task is not in the task list")
+    }
+
+    /// Queues a ready task, at the front to run it next
+    fn push(&mut self, id: TaskId, first: bool) {
+        if first {
+            self.run_queue.push_front(id);
+        } else {
+            self.run_queue.push_back(id);
+        }
+
+        self.publish_ready();
+    }
+
+    fn pop(&mut self) -> Option<TaskId> {
+        let id = self.run_queue.pop_front();
+
+        self.publish_ready();
+
+        id
+    }
+
+    fn publish_ready(&self) {
+        let ready = unsafe { READY.get_for(self.cpu) };
+
+        ready.store(self.run_queue.len(), Ordering::Relaxed);
+    }
+
+    /// Marks a blocked task as ready to run, ahead of the run queue if
+    /// `first`. Returns true if it was blocked.
+    /// Waking a task that is not blocked yet makes its next block a no-op.
+    fn unblock(&mut self, id: TaskId, first: bool) -> bool {
+        let ticks = ticks();
+
+        let task = match self.tasks.get_mut(&id) {
+            Some(task) => task,
+            None => return false,
+        };
+
+        match task.state {
+            TaskState::Blocked => {
+                task.state = TaskState::Ready;
+                task.woken_at = Some(ticks);
+
+                self.push(id, first);
+
+                true
+            }
+            TaskState::Ready | TaskState::Running => {
+                task.wakeup_pending = true;
+
+                false
+            }
+            TaskState::Dead => false,
+        }
+    }
+
+    /// Earliest timer deadline of the tasks of the processor
+    fn next_deadline(&self) -> Option<u64> {
+        self.timers.iter().map(|&(deadline, _)| deadline).min()
+    }
+
+    /// Wakes up the tasks whose deadline passed
+    fn fire_timers(&mut self, ticks: u64) {
+        let mut index = 0;
+
+        // No allocation, this runs in the timer interrupt
+        while index < self.timers.len() {
+            if self.timers[index].0 <= ticks {
+                let (_, id) = self.timers.swap_remove(index);
+
+                self.unblock(id, false);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    /// Picks the next task to run and updates the bookkeeping.
+    /// Returns the location where the current context has to be saved and
+    /// the context to load, or `None` if the current task keeps running.
+    /// `preempted` tells whether the current task ran out of time.
+    fn switch_next(&mut self, preempted: bool) -> Option<(*mut u64, u64)> {
+        let current = self.current();
+        let idle = self.idle;
+        let ticks = ticks();
+
+        {
+            let task = self.current_task_mut();
+
+            if task.state == TaskState::Running {
+                task.state = TaskState::Ready;
+
+                if current != idle {
+                    self.push(current, false);
+                }
+            }
+        }
+
+        let next = self.pop().unwrap_or(idle);
+
+        {
+            let task = self.task_mut(next).expect("next task does not exist");
+
+            task.state = TaskState::Running;
+
+            if let Some(woken_at) = task.woken_at.take() {
+                let latency = ticks.saturating_sub(woken_at);
+
+                task.stats.wakeups += 1;
+                task.stats.wake_latency += latency;
+                task.stats.max_wake_latency = task.stats.max_wake_latency.max(latency);
+            }
+        }
+
+        self.slice_start = ticks;
+
+        if next == current {
+            return None;
+        }
+
+        {
+            let stats = &mut self.current_task_mut().stats;
+
+            stats.switches += 1;
+
+            if preempted {
+                stats.involuntary += 1;
+            } else {
+                stats.voluntary += 1;
+            }
+        }
+
+        CURRENT_TASK.get().set(next);
+
+        let next_task = self.task(next).unwrap();
+
+        if let Some(stack) = &next_task.kernel_stack {
+            let stack_top = VirtAddr::new(stack.top());
+
+            crate::gdt::set_kernel_stack(stack_top);
+            crate::syscall::set_kernel_stack(stack_top);
+        }
+
+        // Kernel tasks get the kernel table back, the address space of an
+        // exited task may be freed while they run
+        let p4_frame = next_task.address_space.as_ref().map_or_else(
+            || PhysFrame::containing_address(PhysAddr::new(KERNEL_TABLE.load(Ordering::Relaxed))),
+            |address_space| address_space.p4_frame(),
+        );
+
+        if Cr3::read().0 != p4_frame {
+            tlb::load_table(p4_frame);
+        }
+
+        // Only ever changed through `set_fs_base()`, the saved value is the
+        // one in the register
+        let fs_base = next_task.fs_base;
+
+        if self.task(current).unwrap().fs_base != fs_base {
+            unsafe { Msr::new(IA32_FS_BASE).write(fs_base) };
+        }
+
+        let old_rsp = &mut self.task_mut(current).unwrap().context.rsp as *mut u64;
+        let new_rsp = self.task(next).unwrap().context.rsp;
+
+        Some((old_rsp, new_rsp))
+    }
+
+    /// Removes the dead tasks, except the current one which is still running
+    /// on its stack
+    fn take_dead(&mut self) -> Vec<Box<Task>> {
+        let current = self.current();
+        let mut reaped = vec![];
+
+        let dead = core::mem::replace(&mut self.dead, vec![]);
+
+        for id in dead {
+            if id == current {
+                self.dead.push(id);
+            } else if let Some(task) = self.tasks.remove(&id) {
+                reaped.push(task);
+            }
+        }
+
+        reaped
+    }
+}
+
+fn next_task_id() -> TaskId {
+    TaskId(NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst))
+}
+
+/// Gives the current processor its queue, `current` is the task running the
+/// caller
+fn install(queue: CpuQueue, current: TaskId) {
+    let cpu = queue.cpu;
+
+    assert_eq!(
+        cpu,
+        CPUS.load(Ordering::SeqCst),
+        "processors added out of order"
+    );
+
+    interrupts::without_interrupts(|| {
+        let mut task_cpus = TASK_CPUS.lock();
+
+        for &id in queue.tasks.keys() {
+            task_cpus.insert(id, cpu);
+        }
+    });
+
+    CURRENT_TASK.get().set(current);
+    interrupts::without_interrupts(|| *QUEUE.get().lock() = Some(queue));
+    CPUS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Sleeps until an interrupt wakes up a task, without the periodic tick.
+/// A task woken by another processor comes with a reschedule IPI.
+pub fn idle_loop() {
+    loop {
+        reap_dead_tasks();
+
+        interrupts::disable();
+
+        // A task woken since the last check runs right away
+        let deadline = with_queue(|queue| {
+            if queue.run_queue.is_empty() {
+                Some(queue.next_deadline())
+            } else {
+                None
+            }
+        });
+
+        if let Some(deadline) = deadline {
+            crate::time::stop_tick(deadline);
+
+            unsafe { enable_and_hlt() };
+
+            crate::time::restart_tick();
+        }
+
+        interrupts::enable();
+        schedule();
+    }
+}
+
+/// First Rust code executed by a new task, called by `task_trampoline`
+#[no_mangle]
+extern "C" fn task_entry(arg: u64) -> ! {
+    interrupts::enable();
+
+    let f = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>) };
+
+    f();
+
+    exit_current();
+}
+
+pub fn init() {
+    KERNEL_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::SeqCst);
+
+    // The code that called `init()` becomes the first task
+    let boot_id = next_task_id();
+
+    // Runs when nothing else is ready, never put in the run queue
+    let idle_id = next_task_id();
+    let idle_arg = Box::into_raw(Box::new(Box::new(idle_loop) as Box<dyn FnOnce() + Send>));
+
+    let mut queue = CpuQueue::new(cpu::id(), idle_id);
+
+    queue.tasks.insert(boot_id, Box::new(Task::boot(boot_id)));
+    queue
+        .tasks
+        .insert(idle_id, Box::new(Task::new(idle_id, idle_arg as u64)));
+
+    install(queue, boot_id);
+
+    STARTED.store(true, Ordering::SeqCst);
+}
+
+/// Adds the application processor running the caller, which then has to
+/// call `idle_loop()`. The code running on it becomes its idle task.
+pub fn init_ap() {
+    let cpu = cpu::id();
+    let idle_id = next_task_id();
+    let mut idle = Task::boot(idle_id);
+
+    idle.cpu = cpu;
+
+    let mut queue = CpuQueue::new(cpu, idle_id);
+
+    queue.tasks.insert(idle_id, Box::new(idle));
+
+    install(queue, idle_id);
+}
+
+/// Creates a new kernel task running `f`
+pub fn spawn<F>(f: F) -> TaskId
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_in(KERNEL_PID, None, f)
+}
+
+/// Creates a task of `process` running `f` with `address_space` loaded
+pub fn spawn_in<F>(process: Pid, address_space: Option<Arc<AddressSpace>>, f: F) -> TaskId
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap_dead_tasks();
+
+    let arg = Box::into_raw(Box::new(Box::new(f) as Box<dyn FnOnce() + Send>));
+
+    let mut task = Task::new(next_task_id(), arg as u64);
+
+    task.process = process;
+    task.address_space = address_space;
+
+    add_task(task)
+}
+
+/// Queues `task` on the processor with the fewest ready tasks, the current
+/// one on a tie
+fn add_task(mut task: Task) -> TaskId {
+    let id = task.id;
+    let current = cpu::id();
+
+    task.cpu = (0..CPUS.load(Ordering::SeqCst))
+        .min_by_key(|&cpu| {
+            let ready = unsafe { READY.get_for(cpu) }.load(Ordering::Relaxed);
+
+            (ready, cpu != current)
+        })
+        .unwrap_or(current);
+
+    let cpu = task.cpu;
+
+    interrupts::without_interrupts(|| TASK_CPUS.lock().insert(id, cpu));
+
+    with_queue_of(cpu, |queue| {
+        // Keep one free slot per task so that pushing in the run queue from
+        // an interrupt handler never needs to allocate
+        queue.run_queue.reserve(1);
+        queue.tasks.insert(id, Box::new(task));
+        queue.push(id, false);
+    });
+
+    wake_cpu(cpu);
+
+    id
+}
+
+/// Makes another processor notice the task just queued on it
+fn wake_cpu(cpu: usize) {
+    if cpu != cpu::id() {
+        ipi::send_reschedule(cpu);
+    }
+}
+
+pub fn current_task_id() -> TaskId {
+    CURRENT_TASK.get().get()
+}
+
+/// Process of the current task
+pub fn current_process() -> Pid {
+    with_queue(|queue| queue.current_task_mut().process)
+}
+
+/// Address space of the current task, `None` for a kernel task
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    with_queue(|queue| queue.current_task_mut().address_space.clone())
+}
+
+/// State of a task, `None` once it has exited and been reaped
+pub fn task_state(id: TaskId) -> Option<TaskState> {
+    let cpu = task_cpu(id)?;
+
+    with_queue_of(cpu, |queue| queue.task(id).map(|task| task.state))
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Adds `step` to the tick count and catches up with the clock. Returns the
+/// new count.
+fn advance_ticks(step: u64) -> u64 {
+    let mut ticks = TICKS.load(Ordering::SeqCst);
+
+    loop {
+        let new = (ticks + step).max(crate::time::ticks());
+
+        match TICKS.compare_exchange_weak(ticks, new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return new,
+            Err(current) => ticks = current,
+        }
+    }
+}
+
+/// Number of ticks covering at least `ms` milliseconds
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(TICKS_PER_SECOND).saturating_add(999) / 1000
+}
+
+/// Wakes up the current task at tick `deadline`, unless it cancels it first
+pub fn set_timer(deadline: u64) {
+    with_queue(|queue| {
+        let current = queue.current();
+
+        queue.timers.push((deadline, current));
+    });
+}
+
+pub fn cancel_timer() {
+    with_queue(|queue| {
+        let current = queue.current();
+
+        queue.timers.retain(|&(_, id)| id != current);
+    });
+}
+
+/// Sets the FS base of the current task, userland finds its thread local
+/// storage there. The address must be canonical.
+pub fn set_fs_base(fs_base: u64) {
+    with_queue(|queue| {
+        queue.current_task_mut().fs_base = fs_base;
+
+        unsafe { Msr::new(IA32_FS_BASE).write(fs_base) };
+    });
+}
+
+pub fn fs_base() -> u64 {
+    with_queue(|queue| queue.current_task_mut().fs_base)
+}
+
+/// Snapshot of every task, the queues are locked one after the other
+pub fn tasks() -> Vec<TaskInfo> {
+    let mut tasks = vec![];
+
+    for cpu in 0..CPUS.load(Ordering::SeqCst) {
+        with_queue_of(cpu, |queue| {
+            tasks.extend(queue.tasks.values().map(|task| task.info()))
+        });
+    }
+
+    tasks.sort_by_key(|task| task.id);
+
+    tasks
+}
+
+/// Prints the task list on the serial port, like `ps`
+pub fn print_tasks() {
+    let tasks = tasks();
+
+    serial_println!(
+        "{:>5} {:>5} {:<8} {:>8} {:>8} {:>8} {:>6} {:>6} {:>7} {:>7}",
+        "TID",
+        "PID",
+        "STATE",
+        "USER",
+        "KERNEL",
+        "SWITCHES",
+        "VOL",
+        "INVOL",
+        "AVG_LAT",
+        "MAX_LAT"
+    );
+
+    for task in tasks {
+        let stats = task.stats;
+        let average_latency = stats.wake_latency.checked_div(stats.wakeups).unwrap_or(0);
+        let state = match task.state {
+            0 => "ready",
+            1 => "running",
+            2 => "blocked",
+            _ => "dead",
+        };
+
+        serial_println!(
+            "{:>5} {:>5} {:<8} {:>8} {:>8} {:>8} {:>6} {:>6} {:>7} {:>7}",
+            task.id,
+            task.process,
+            state,
+            stats.user_ticks,
+            stats.kernel_ticks,
+            stats.switches,
+            stats.voluntary,
+            stats.involuntary,
+            average_latency,
+            stats.max_wake_latency
+        );
+    }
+}
+
+/// Gives the current task a new address space and loads it.
+/// Returns the previous one, it can be freed once the caller drops it.
+pub fn replace_address_space(address_space: Arc<AddressSpace>) -> Option<Arc<AddressSpace>> {
+    with_queue(|queue| {
+        let p4_frame = address_space.p4_frame();
+        let old = queue
+            .current_task_mut()
+            .address_space
+            .replace(address_space);
+
+        tlb::load_table(p4_frame);
+
+        old
+    })
+}
+
+/// Switches to the next ready task, if any
+pub fn schedule() {
+    switch(false);
+}
+
+fn switch(preempted: bool) {
+    interrupts::without_interrupts(|| {
+        // The lock must be released before switching, the next task is going
+        // to need it
+        let switch = with_queue(|queue| queue.switch_next(preempted));
+
+        if let Some((old_rsp, new_rsp)) = switch {
+            unsafe { switch_context(old_rsp, new_rsp) };
+        }
+    });
+}
+
+pub fn yield_now() {
+    schedule();
+}
+
+/// Puts the current task to sleep until someone calls `unblock()` on it
+pub fn block_current() {
+    interrupts::without_interrupts(|| {
+        let must_switch = with_queue(|queue| {
+            let task = queue.current_task_mut();
+
+            if task.wakeup_pending {
+                task.wakeup_pending = false;
+
+                return false;
+            }
+
+            task.state = TaskState::Blocked;
+
+            true
+        });
+
+        if must_switch {
+            schedule();
+        }
+    });
+}
+
+/// Wakes up `id` in the queue of its processor, ahead of the run queue if
+/// `first`. Returns true if it was blocked.
+fn wake(id: TaskId, first: bool) -> bool {
+    let cpu = match task_cpu(id) {
+        Some(cpu) => cpu,
+        None => return false,
+    };
+
+    let woken = with_queue_of(cpu, |queue| queue.unblock(id, first));
+
+    if woken {
+        wake_cpu(cpu);
+    }
+
+    woken
+}
+
+/// Marks a blocked task as ready to run.
+/// Waking a task that is not blocked yet makes its next block a no-op.
+pub fn unblock(id: TaskId) {
+    wake(id, false);
+}
+
+/// Wakes up `id` and switches to it right away if it was blocked, the
+/// current task stays ready. A task of another processor is only woken up,
+/// giving up the current processor would not run it sooner. Not usable from
+/// interrupt handlers.
+pub fn switch_to(id: TaskId) {
+    if task_cpu(id) != Some(cpu::id()) {
+        unblock(id);
+
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        if wake(id, true) {
+            schedule();
+        }
+    });
+}
+
+pub fn exit_current() -> ! {
+    interrupts::disable();
+
+    with_queue(|queue| {
+        let current = queue.current();
+
+        queue.current_task_mut().state = TaskState::Dead;
+        queue.dead.push(current);
+    });
+
+    schedule();
+
+    unreachable!("dead task was scheduled again");
+}
+
+/// Frees the stacks of the tasks that exited on the current processor.
+/// The tasks are dropped once the queue is unlocked, with interrupts
+/// enabled: a task may hold the last reference to an address space.
+pub fn reap_dead_tasks() {
+    let dead = with_queue(|queue| queue.take_dead());
+
+    if !dead.is_empty() {
+        interrupts::without_interrupts(|| {
+            let mut task_cpus = TASK_CPUS.lock();
+
+            for task in &dead {
+                task_cpus.remove(&task.id);
+            }
+        });
+    }
+
+    drop(dead);
+}
+
+/// Called on every timer interrupt, after the end of interrupt has been sent.
+/// The tick is charged to the current task as user time if it interrupted
+/// userland. The boot processor counts the ticks, the ones skipped while
+/// its tick was stopped are caught up. Only the queue of the current
+/// processor is locked.
+pub fn tick(user: bool) {
+    if !STARTED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let ticks = advance_ticks(if cpu::is_bsp() { 1 } else { 0 });
+
+    let preempt = with_queue(|queue| {
+        queue.fire_timers(ticks);
+
+        let stats = &mut queue.current_task_mut().stats;
+
+        if user {
+            stats.user_ticks += 1;
+        } else {
+            stats.kernel_ticks += 1;
+        }
+
+        ticks.saturating_sub(queue.slice_start) >= TIME_SLICE
+    });
+
+    if preempt {
+        switch(true);
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_spawn_and_yield() {
+    use alloc::sync::Arc;
+
+    serial_print!("test_spawn_and_yield... ");
+
+    let done = Arc::new(AtomicBool::new(false));
+    let done2 = done.clone();
+
+    spawn(move || done2.store(true, Ordering::SeqCst));
+
+    while !done.load(Ordering::SeqCst) {
+        yield_now();
+    }
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_task_stats() {
+    use alloc::sync::Arc;
+
+    serial_print!("test_task_stats... ");
+
+    let done = Arc::new(AtomicBool::new(false));
+    let done2 = done.clone();
+
+    let other = spawn(move || {
+        while !done2.load(Ordering::SeqCst) {
+            yield_now();
+        }
+    });
+
+    let current = current_task_id();
+    let stats_of = |id: TaskId| {
+        tasks()
+            .into_iter()
+            .find(|task| task.id == id.0)
+            .map(|task| task.stats)
+            .unwrap()
+    };
+
+    let before = stats_of(current);
+
+    for _ in 0..3 {
+        yield_now();
+    }
+
+    let after = stats_of(current);
+
+    assert!(after.voluntary >= before.voluntary + 3);
+    assert!(after.switches >= before.switches + 3);
+    assert!(stats_of(other).switches >= 3);
+
+    done.store(true, Ordering::SeqCst);
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_wake_on_other_cpus() {
+    use alloc::sync::Arc;
+
+    serial_print!("test_wake_on_other_cpus... ");
+
+    let count = crate::smp::cpu_count() * 2;
+    let done = Arc::new(AtomicUsize::new(0));
+
+    // Spread over the processors, each task sleeps in its own queue
+    let ids: Vec<TaskId> = (0..count)
+        .map(|_| {
+            let done = done.clone();
+
+            spawn(move || {
+                block_current();
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+
+    // A task not blocked yet keeps the wakeup for its block
+    for &id in &ids {
+        unblock(id);
+    }
+
+    while done.load(Ordering::SeqCst) < count {
+        yield_now();
+    }
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,167 @@
+use alloc::{boxed::Box, sync::Arc, vec};
+use core::fmt;
+
+use crate::memory::paging::AddressSpace;
+use crate::process::{Pid, KERNEL_PID};
+
+pub const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+// Initial rflags of a new task: reserved bit 1 only, interrupts are enabled
+// by `task_entry` once the task is running
+const INITIAL_RFLAGS: u64 = 0x2;
+
+extern "C" {
+    fn task_trampoline();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(pub u64);
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    Blocked,
+    Dead,
+}
+
+/// CPU usage and scheduling history of a task, times in timer ticks
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct TaskStats {
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
+    /// Times the task was switched out
+    pub switches: u64,
+    /// Switches because the task blocked or yielded
+    pub voluntary: u64,
+    /// Switches because its time slice ran out
+    pub involuntary: u64,
+    pub wakeups: u64,
+    /// Sum of the delays between a wake-up and the task running
+    pub wake_latency: u64,
+    pub max_wake_latency: u64,
+}
+
+/// Line of the task list, as copied to userland
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub process: u64,
+    /// `TaskState` as an integer, in declaration order
+    pub state: u64,
+    pub stats: TaskStats,
+}
+
+/// Callee-saved state of a task that is not running.
+/// Everything else lives on its kernel stack, pushed by `switch_context`.
+#[derive(Default)]
+pub struct Context {
+    pub rsp: u64,
+}
+
+pub struct KernelStack {
+    memory: Box<[u8]>,
+}
+
+impl KernelStack {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; KERNEL_STACK_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// 16 bytes aligned top of the stack
+    pub fn top(&self) -> u64 {
+        let end = self.memory.as_ptr() as u64 + self.memory.len() as u64;
+
+        end & !0xf
+    }
+}
+
+pub struct Task {
+    pub id: TaskId,
+    pub state: TaskState,
+    pub context: Context,
+    pub process: Pid,
+    pub kernel_stack: Option<KernelStack>,
+    /// Processor whose run queue holds the task
+    pub cpu: usize,
+    /// Page table loaded when switching to the task. Kernel tasks have none
+    /// and run on whichever table is loaded, its kernel part is the same.
+    pub address_space: Option<Arc<AddressSpace>>,
+    /// FS base of the user thread, its TLS pointer
+    pub fs_base: u64,
+    pub stats: TaskStats,
+    /// Tick of the last wake-up, until the task runs again
+    pub woken_at: Option<u64>,
+    /// Set when the task is woken up before it had time to block,
+    /// the next `block_current()` then returns immediately.
+    pub wakeup_pending: bool,
+}
+
+impl Task {
+    pub fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id.0,
+            process: self.process.0,
+            state: self.state as u64,
+            stats: self.stats,
+        }
+    }
+
+    /// The task that is already running when the scheduler starts,
+    /// it keeps using the boot stack.
+    pub fn boot(id: TaskId) -> Self {
+        Self {
+            id,
+            state: TaskState::Running,
+            context: Context::default(),
+            process: KERNEL_PID,
+            kernel_stack: None,
+            cpu: 0,
+            address_space: None,
+            fs_base: 0,
+            stats: TaskStats::default(),
+            woken_at: None,
+            wakeup_pending: false,
+        }
+    }
+
+    /// Creates a kernel task that will call `task_entry(arg)` on its first run
+    pub fn new(id: TaskId, arg: u64) -> Self {
+        let kernel_stack = KernelStack::new();
+        let top = kernel_stack.top();
+
+        // Frame popped by `switch_context`: r15, r14, r13, r12, rbx, rbp,
+        // rflags and the return address
+        let frame: [u64; 8] = [0, 0, 0, arg, 0, 0, INITIAL_RFLAGS, task_trampoline as u64];
+
+        let rsp = top - (frame.len() * 8) as u64;
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+        }
+
+        Self {
+            id,
+            state: TaskState::Ready,
+            context: Context { rsp },
+            process: KERNEL_PID,
+            kernel_stack: Some(kernel_stack),
+            cpu: 0,
+            address_space: None,
+            fs_base: 0,
+            stats: TaskStats::default(),
+            woken_at: None,
+            wakeup_pending: false,
+        }
+    }
+}
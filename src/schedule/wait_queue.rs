@@ -0,0 +1,152 @@
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::TaskId;
+
+/// List of tasks sleeping until some event happens.
+///
+/// The waiters list is only ever locked with interrupts disabled, so wake-ups
+/// can be issued from interrupt handlers.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Sleeps until `condition` returns true.
+    /// The current task is queued before the condition is checked: a wake-up
+    /// issued by another processor right after the check finds it, and the
+    /// sleep returns at once.
+    pub fn wait_until<F>(&self, mut condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        loop {
+            let done = self.sleep_unless(|| if condition() { Some(()) } else { None });
+
+            if done.is_some() {
+                return;
+            }
+        }
+    }
+
+    /// Like `wait_until()`, but gives up once the tick count reaches
+    /// `deadline`. Returns false on timeout.
+    pub fn wait_until_deadline<F>(&self, mut condition: F, deadline: u64) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        super::set_timer(deadline);
+
+        let result = loop {
+            let done = self.sleep_unless(|| {
+                if condition() {
+                    Some(true)
+                } else if super::ticks() >= deadline {
+                    Some(false)
+                } else {
+                    None
+                }
+            });
+
+            if let Some(result) = done {
+                break result;
+            }
+        };
+
+        super::cancel_timer();
+
+        result
+    }
+
+    /// Enqueues the current task, runs `before_sleep` and blocks.
+    /// Used to release a lock atomically with going to sleep.
+    pub fn sleep_after<F>(&self, before_sleep: F)
+    where
+        F: FnOnce(),
+    {
+        self.sleep_unless(|| {
+            before_sleep();
+
+            None::<()>
+        });
+    }
+
+    /// Enqueues the current task, then blocks unless `check` returns a
+    /// result. `check` runs with interrupts disabled.
+    fn sleep_unless<F, R>(&self, check: F) -> Option<R>
+    where
+        F: FnOnce() -> Option<R>,
+    {
+        interrupts::without_interrupts(|| {
+            let current = super::current_task_id();
+
+            self.waiters.lock().push_back(current);
+
+            let result = check();
+
+            if result.is_none() {
+                super::block_current();
+            }
+
+            // We may have been woken up by someone else than this queue, or
+            // not have slept at all
+            self.waiters.lock().retain(|&id| id != current);
+
+            result
+        })
+    }
+
+    pub fn wake_one(&self) -> bool {
+        let waiter = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+
+        match waiter {
+            Some(id) => {
+                super::unblock(id);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes up the first waiter and runs it immediately if it belongs to
+    /// the current processor, for a handover that should not wait for the
+    /// waiter's turn
+    pub fn hand_off(&self) -> bool {
+        let waiter = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+
+        match waiter {
+            Some(id) => {
+                super::switch_to(id);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn wake_all(&self) -> usize {
+        let waiters = interrupts::without_interrupts(|| {
+            core::mem::replace(&mut *self.waiters.lock(), VecDeque::new())
+        });
+
+        let count = waiters.len();
+
+        for id in waiters {
+            super::unblock(id);
+        }
+
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.waiters.lock().is_empty())
+    }
+}
@@ -0,0 +1,143 @@
+//! Per-processor area, reached through the GS base.
+//!
+//! Each processor points its GS base at a `Cpu` of its own, whose first field
+//! holds its address: reading `gs:0` gives the area back. The area leads to
+//! the copy of the `percpu!` variables, and holds the stack pointers the
+//! system call entry reads at fixed offsets.
+//!
+//! Userland has a GS base of its own: every entry from ring 3 swaps it with
+//! the kernel one with `swapgs`, every return to ring 3 swaps them back.
+
+use core::cell::Cell;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::interrupts;
+use crate::percpu;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// Processors the kernel can run on, the others are left halted
+pub const MAX_CPUS: usize = 16;
+
+// Offset of `percpu_offset`, asm/syscall.S reads the stack pointers right
+// after it
+const PERCPU_OFFSET: u64 = 8;
+
+extern "C" {
+    fn read_gs_qword(offset: u64) -> u64;
+    fn swapgs();
+}
+
+#[repr(C)]
+pub struct Cpu {
+    /// Address of the area, read through GS
+    this: *const Cpu,
+    /// Offset of the copy of the `percpu!` variables from their template
+    percpu_offset: u64,
+    /// Top of the kernel stack of the running task, for SYSCALL
+    kernel_rsp: Cell<u64>,
+    /// User stack pointer, saved by the SYSCALL entry
+    user_rsp: Cell<u64>,
+}
+
+percpu! {
+    /// Index of the processor, the boot processor is 0
+    static ID: Cell<usize> = Cell::new(0);
+}
+
+/// Set up before anything else, the boot processor needs no heap
+static mut BOOT_CPU: Cpu = Cpu::new(0);
+
+impl Cpu {
+    pub const fn new(percpu_offset: u64) -> Self {
+        Self {
+            this: core::ptr::null(),
+            percpu_offset,
+            kernel_rsp: Cell::new(0),
+            user_rsp: Cell::new(0),
+        }
+    }
+
+    pub fn set_kernel_rsp(&self, rsp: u64) {
+        self.kernel_rsp.set(rsp);
+    }
+}
+
+/// Points the GS base of the current processor at `cpu`
+///
+/// # Safety
+///
+/// `cpu` must not be loaded on another processor.
+pub unsafe fn load(cpu: &'static mut Cpu) {
+    cpu.this = cpu as *const Cpu;
+
+    Msr::new(IA32_GS_BASE).write(cpu.this as u64);
+}
+
+/// Loads the area of the boot processor, before the GDT
+pub fn init() {
+    unsafe {
+        BOOT_CPU.percpu_offset = super::percpu::init_boot();
+
+        load(&mut BOOT_CPU);
+    }
+}
+
+/// Creates the area of the processor `id`, before it starts
+pub fn create(id: usize) -> Cpu {
+    let offset = super::percpu::alloc_copy(id);
+
+    unsafe { ID.get_for(id).set(id) };
+
+    Cpu::new(offset)
+}
+
+/// Area of the processor running the caller
+pub fn current() -> &'static Cpu {
+    unsafe { &*(read_gs_qword(0) as *const Cpu) }
+}
+
+pub fn percpu_offset() -> u64 {
+    unsafe { read_gs_qword(PERCPU_OFFSET) }
+}
+
+/// Index of the current processor
+pub fn id() -> usize {
+    ID.get().get()
+}
+
+pub fn is_bsp() -> bool {
+    id() == 0
+}
+
+/// Loads the kernel GS base for the duration of an interrupt handler written
+/// in Rust, if the interrupt arrived in userland. Created before anything
+/// reads the area of the processor.
+///
+/// Only for interrupts that are masked around every swapgs, the saved CS
+/// tells which GS base is loaded. NMIs, machine checks and double faults go
+/// through the paranoid entry of asm/exceptions.S instead.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let swapped = interrupts::from_user(stack_frame);
+
+        if swapped {
+            unsafe { swapgs() };
+        }
+
+        Self { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { swapgs() };
+        }
+    }
+}
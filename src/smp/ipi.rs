@@ -0,0 +1,153 @@
+//! Interrupts sent from one processor to others: remote function calls and
+//! reschedule requests.
+//!
+//! A remote call runs a function on a set of processors, in an interrupt
+//! handler, and waits for all of them to be done. One call is in flight at
+//! a time, a processor waiting for its turn runs the pending one meanwhile.
+//!
+//! The targets only run the call once their interrupts are enabled: the
+//! caller must not hold a lock that other processors take with interrupts
+//! disabled, such as a run queue or the mapper. The function itself runs
+//! in an interrupt handler and takes no lock.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::cpu::{self, KernelGs};
+use crate::interrupts::apic;
+
+/// Held by the processor making a call, with interrupts disabled
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Function of the call in flight, set before `CALL_PENDING`. Only valid
+/// for the processors whose bit is set.
+static mut CALL_FUNCTION: Option<&'static (dyn Fn() + Sync)> = None;
+
+/// Processors that did not run the call in flight yet, one bit per id
+static CALL_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Runs the call in flight if the current processor is one of its targets
+fn run_pending_call() {
+    let bit = 1 << cpu::id();
+
+    if CALL_PENDING.load(Ordering::SeqCst) & bit == 0 {
+        return;
+    }
+
+    // The caller waits for our bit, the function stays valid until then
+    if let Some(f) = unsafe { CALL_FUNCTION } {
+        f();
+    }
+
+    CALL_PENDING.fetch_and(!bit, Ordering::SeqCst);
+}
+
+/// Runs `f` on the online processors of `mask`, the current one excluded,
+/// and returns once they all did
+pub fn call_function_mask<F>(mask: u64, f: &F)
+where
+    F: Fn() + Sync,
+{
+    interrupts::without_interrupts(|| {
+        let targets = mask & super::online_mask() & !(1 << cpu::id());
+
+        if targets == 0 {
+            return;
+        }
+
+        // Another processor may be waiting for us to run its call
+        let _call = loop {
+            if let Some(call) = CALL_LOCK.try_lock() {
+                break call;
+            }
+
+            run_pending_call();
+            core::sync::atomic::spin_loop_hint();
+        };
+
+        unsafe {
+            let f: &(dyn Fn() + Sync) = f;
+
+            // Cleared before returning, while `f` still lives
+            CALL_FUNCTION = Some(core::mem::transmute(f));
+        }
+
+        CALL_PENDING.store(targets, Ordering::SeqCst);
+
+        for cpu in (0..cpu::MAX_CPUS).filter(|&cpu| targets & (1 << cpu) != 0) {
+            apic::send_ipi(super::apic_id(cpu), apic::CALL_FUNCTION_VECTOR);
+        }
+
+        while CALL_PENDING.load(Ordering::SeqCst) != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+
+        unsafe { CALL_FUNCTION = None };
+    });
+}
+
+/// Runs `f` on every other processor and returns once they all did
+pub fn smp_call_function<F>(f: &F)
+where
+    F: Fn() + Sync,
+{
+    call_function_mask(!0, f);
+}
+
+/// Wakes up the processor `cpu` if it sleeps in its idle task, to run a
+/// task queued by another processor. A processor running a task picks the
+/// new one at the end of the time slice.
+pub fn send_reschedule(cpu: usize) {
+    if super::online_mask() & (1 << cpu) != 0 {
+        apic::send_ipi(super::apic_id(cpu), apic::RESCHEDULE_VECTOR);
+    }
+}
+
+/// Entry of `apic::CALL_FUNCTION_VECTOR`
+pub extern "x86-interrupt" fn call_function_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+
+    run_pending_call();
+
+    apic::end_of_interrupt();
+}
+
+/// Entry of `apic::RESCHEDULE_VECTOR`, the interrupt alone ends the `hlt`
+/// of the idle task
+pub extern "x86-interrupt" fn reschedule_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+
+    apic::end_of_interrupt();
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_smp_call_function() {
+    serial_print!("test_smp_call_function... ");
+
+    let calls = AtomicU64::new(0);
+    let seen = AtomicU64::new(0);
+
+    smp_call_function(&|| {
+        calls.fetch_add(1, Ordering::SeqCst);
+        seen.fetch_or(1 << cpu::id(), Ordering::SeqCst);
+    });
+
+    // Every other processor ran it once, the caller did not
+    assert_eq!(calls.load(Ordering::SeqCst), super::cpu_count() as u64 - 1);
+    assert_eq!(
+        seen.load(Ordering::SeqCst),
+        super::online_mask() & !(1 << cpu::id())
+    );
+
+    // Nothing to wait for without targets
+    call_function_mask(1 << cpu::id(), &|| panic!("called on the caller"));
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,277 @@
+//! Symmetric multiprocessing: starts the application processors listed in
+//! the MADT.
+//!
+//! Each processor is woken up with an INIT IPI then STARTUP IPIs, and runs
+//! the trampoline copied in low memory. It enters long mode with the page
+//! table of the boot processor and calls `ap_entry()`, which gives it a GDT,
+//! a TSS, its local APIC and an idle task. The processors are started one
+//! at a time.
+//!
+//! Once started, the processors reach each other with the IPIs of `ipi`.
+
+use alloc::{boxed::Box, vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr3};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::PhysAddr;
+
+use crate::acpi::madt::Madt;
+use crate::gdt;
+use crate::interrupts::{self, apic};
+use crate::memory::paging::{helpers, tlb};
+use crate::schedule::{self, KERNEL_STACK_SIZE};
+use crate::serial_println;
+use crate::syscall;
+use crate::time;
+
+pub mod cpu;
+pub mod ipi;
+pub mod percpu;
+
+use cpu::{Cpu, MAX_CPUS};
+
+/// Where the trampoline is copied, the STARTUP IPI takes its page number.
+/// The frame allocator never hands out frames below 1 MiB.
+const TRAMPOLINE_ADDRESS: u64 = 0x8000;
+
+// Delays of the startup sequence
+const INIT_DELAY_NS: u64 = 10_000_000;
+const STARTUP_DELAY_NS: u64 = 200_000;
+const START_TIMEOUT_NS: u64 = 100_000_000;
+const START_POLL_NS: u64 = 1_000_000;
+
+extern "C" {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+    static trampoline_params: u8;
+    fn read_cr4() -> u64;
+}
+
+/// Read by the trampoline, in the copy
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    cr0: u64,
+    cr4: u64,
+    stack: u64,
+    entry: u64,
+    arg: u64,
+}
+
+/// Processors running the kernel, the boot processor included
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Processors taking IPIs, one bit per id
+static ONLINE: AtomicU64 = AtomicU64::new(1);
+
+/// Set by the processor being started once it runs its idle task
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Local APIC id of every processor. Only written before the processor
+/// starts.
+static mut APIC_IDS: [u8; MAX_CPUS] = [0; MAX_CPUS];
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Processors that take IPIs, one bit per id
+pub fn online_mask() -> u64 {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// Local APIC id of the processor `cpu`
+pub fn apic_id(cpu: usize) -> u8 {
+    unsafe { APIC_IDS[cpu] }
+}
+
+/// Starts the usable processors of the MADT, one after the other.
+/// Needs the local APIC, the clocks and the scheduler.
+pub fn init() {
+    let madt = match Madt::find() {
+        Some(madt) if apic::is_enabled() => madt,
+        _ => {
+            serial_println!("   No local APIC, single processor");
+
+            return;
+        }
+    };
+
+    copy_trampoline();
+
+    let bsp_id = apic::id();
+
+    unsafe { APIC_IDS[0] = bsp_id };
+
+    for processor in &madt.processors {
+        if !processor.usable || processor.apic_id == bsp_id {
+            continue;
+        }
+
+        if cpu_count() == MAX_CPUS {
+            serial_println!(
+                "   Too many processors, APIC {} left halted",
+                processor.apic_id
+            );
+
+            continue;
+        }
+
+        if !start_processor(processor.apic_id) {
+            serial_println!("   Processor with APIC {} did not start", processor.apic_id);
+        }
+    }
+
+    serial_println!("   {} processors online", cpu_count());
+}
+
+fn copy_trampoline() {
+    let start = unsafe { &trampoline_start as *const u8 };
+    let size = unsafe { &trampoline_end as *const u8 as usize } - start as usize;
+
+    // Executable, the processors run it with paging enabled
+    helpers::identity_map_range(
+        PhysAddr::new(TRAMPOLINE_ADDRESS),
+        size as u64,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    );
+
+    unsafe { core::ptr::copy_nonoverlapping(start, TRAMPOLINE_ADDRESS as *mut u8, size) };
+}
+
+/// Parameters in the copy of the trampoline
+fn trampoline_params_mut() -> &'static mut TrampolineParams {
+    let offset =
+        unsafe { &trampoline_params as *const u8 as u64 - &trampoline_start as *const u8 as u64 };
+
+    unsafe { &mut *((TRAMPOLINE_ADDRESS + offset) as *mut TrampolineParams) }
+}
+
+/// Runs the startup sequence of the processor `apic_id` and waits for it to
+/// reach its idle task
+fn start_processor(apic_id: u8) -> bool {
+    let id = cpu_count();
+
+    unsafe { APIC_IDS[id] = apic_id };
+
+    let area = Box::leak(Box::new(cpu::create(id)));
+    let stack = Box::leak(vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice());
+
+    let cr3 = Cr3::read().0.start_address().as_u64();
+
+    // The trampoline loads CR3 in 32 bits
+    assert!(cr3 < 1 << 32, "kernel page table above 4 GiB");
+
+    *trampoline_params_mut() = TrampolineParams {
+        cr3,
+        cr0: Cr0::read_raw(),
+        cr4: unsafe { read_cr4() },
+        stack: (stack.as_ptr() as u64 + stack.len() as u64) & !0xf,
+        entry: ap_entry as u64,
+        arg: area as *mut Cpu as u64,
+    };
+
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    let page = (TRAMPOLINE_ADDRESS >> 12) as u8;
+
+    apic::send_init(apic_id);
+    time::busy_wait(INIT_DELAY_NS);
+
+    // The second STARTUP IPI is for the processors that miss the first one
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        time::busy_wait(STARTUP_DELAY_NS);
+
+        if AP_STARTED.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+
+    let mut waited = 0;
+
+    while !AP_STARTED.load(Ordering::SeqCst) {
+        if waited >= START_TIMEOUT_NS {
+            // Stopped before it reads the parameters of the next one
+            apic::send_init(apic_id);
+
+            return false;
+        }
+
+        time::busy_wait(START_POLL_NS);
+        waited += START_POLL_NS;
+    }
+
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+
+    true
+}
+
+/// First Rust code of an application processor, called by the trampoline on
+/// a fresh stack with interrupts disabled
+extern "C" fn ap_entry(area: &'static mut Cpu) -> ! {
+    unsafe { cpu::load(area) };
+
+    gdt::init_ap();
+    interrupts::init_ap();
+
+    // Shootdowns reach the processor from now on, the entries it cached
+    // before may be stale already
+    ONLINE.fetch_or(1 << cpu::id(), Ordering::SeqCst);
+    tlb::flush_all();
+
+    syscall::init();
+    time::init_ap();
+
+    // The context running now becomes the idle task of the processor
+    schedule::init_ap();
+
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    schedule::idle_loop();
+
+    unreachable!("idle task returned");
+}
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_tasks_stay_on_their_cpu() {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    serial_print!("test_tasks_stay_on_their_cpu... ");
+
+    // The tests run in the boot task
+    assert!(cpu::is_bsp());
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let count = cpu_count() * 2;
+
+    for _ in 0..count {
+        let seen = seen.clone();
+
+        schedule::spawn(move || {
+            let first = cpu::id();
+
+            schedule::yield_now();
+
+            seen.lock().push((first, cpu::id()));
+        });
+    }
+
+    while seen.lock().len() < count {
+        schedule::yield_now();
+    }
+
+    for &(first, then) in seen.lock().iter() {
+        assert_eq!(first, then);
+        assert!(first < cpu_count());
+    }
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,149 @@
+//! Per-processor variables, declared with `percpu!`.
+//!
+//! The variables are placed in the `.percpu` section, which is only a
+//! template: every processor works on a copy of it, found through the offset
+//! kept in its `Cpu` area. The copy of the boot processor is reserved after
+//! the BSS, the others are on the heap.
+//!
+//! A copy is only touched by its processor, interrupt handlers included: the
+//! variables are made of `Cell`s, or borrowed with interrupts disabled.
+
+use alloc::alloc::{alloc, Layout};
+
+use super::cpu::{self, MAX_CPUS};
+
+const ALIGNMENT: usize = 64;
+
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+    static mut __percpu_boot: u8;
+}
+
+/// Offset of the copy of every processor from the template. Only written
+/// before the processor starts.
+static mut OFFSETS: [u64; MAX_CPUS] = [0; MAX_CPUS];
+
+/// Declares variables with one copy per processor, each reached with
+/// `get()`
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::smp::percpu::PerCpu<$ty> =
+                $crate::smp::percpu::PerCpu::new($init);
+        )*
+    };
+}
+
+/// Variable of the template, declared with `percpu!`
+pub struct PerCpu<T> {
+    value: T,
+}
+
+// Each processor only uses its own copy
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    /// Copy of the current processor. Tasks never move to another processor,
+    /// it stays the right one.
+    pub fn get(&'static self) -> &'static T {
+        unsafe { self.at(cpu::percpu_offset()) }
+    }
+
+    /// Copy of the processor `cpu`
+    ///
+    /// # Safety
+    ///
+    /// The processor must not use the variable at the same time, it is not
+    /// started yet or the variable is synchronized.
+    pub unsafe fn get_for(&'static self, cpu: usize) -> &'static T {
+        self.at(OFFSETS[cpu])
+    }
+
+    unsafe fn at(&'static self, offset: u64) -> &'static T {
+        &*((&self.value as *const T as u64).wrapping_add(offset) as *const T)
+    }
+}
+
+fn template() -> &'static [u8] {
+    unsafe {
+        let start = &__percpu_start as *const u8;
+        let size = &__percpu_end as *const u8 as usize - start as usize;
+
+        core::slice::from_raw_parts(start, size)
+    }
+}
+
+fn offset_of(copy: *const u8) -> u64 {
+    (copy as u64).wrapping_sub(template().as_ptr() as u64)
+}
+
+/// Fills the copy of the boot processor, returns its offset
+pub fn init_boot() -> u64 {
+    let template = template();
+
+    unsafe {
+        let copy = &mut __percpu_boot as *mut u8;
+
+        core::ptr::copy_nonoverlapping(template.as_ptr(), copy, template.len());
+
+        OFFSETS[0] = offset_of(copy);
+        OFFSETS[0]
+    }
+}
+
+/// Allocates the copy of the processor `cpu`, returns its offset. Called
+/// before the processor starts.
+pub fn alloc_copy(cpu: usize) -> u64 {
+    let template = template();
+    let layout = Layout::from_size_align(template.len().max(1), ALIGNMENT).unwrap();
+
+    unsafe {
+        let copy = alloc(layout);
+
+        assert!(!copy.is_null(), "no memory for the per-CPU area");
+
+        // Writes every page, none faults once the processor uses it
+        core::ptr::copy_nonoverlapping(template.as_ptr(), copy, template.len());
+
+        OFFSETS[cpu] = offset_of(copy);
+        OFFSETS[cpu]
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{percpu, serial_print, serial_println};
+
+#[cfg(test)]
+percpu! {
+    static TEST_COUNTER: core::cell::Cell<u64> = core::cell::Cell::new(7);
+}
+
+#[test_case]
+fn test_percpu() {
+    serial_print!("test_percpu... ");
+
+    let counter = TEST_COUNTER.get();
+
+    assert_eq!(counter.get(), 7);
+
+    counter.set(42);
+
+    assert_eq!(TEST_COUNTER.get().get(), 42);
+    assert_eq!(unsafe { TEST_COUNTER.get_for(cpu::id()) }.get(), 42);
+
+    // The template keeps the value copied to the processors
+    assert_eq!(TEST_COUNTER.value.get(), 7);
+
+    serial_println!("[ok]");
+}
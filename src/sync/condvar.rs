@@ -0,0 +1,82 @@
+use super::MutexGuard;
+use crate::schedule::WaitQueue;
+
+/// Condition variable to be used with `sync::Mutex`
+pub struct Condvar {
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    pub fn new() -> Self {
+        Self {
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the mutex and sleeps until notified, then locks it again.
+    /// The task is queued before the mutex is released, a notification sent
+    /// by the next owner of the mutex cannot be lost.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+
+        self.queue.sleep_after(|| drop(guard));
+
+        mutex.lock()
+    }
+
+    /// Waits as long as `condition` returns true
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.queue.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.queue.wake_all();
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_condvar_notify() {
+    use super::Mutex;
+    use crate::schedule;
+    use alloc::sync::Arc;
+
+    serial_print!("test_condvar_notify... ");
+
+    let pair = Arc::new((Mutex::new(false), Condvar::new()));
+    let pair2 = pair.clone();
+
+    schedule::spawn(move || {
+        let (ready, condvar) = &*pair2;
+
+        *ready.lock() = true;
+        condvar.notify_one();
+    });
+
+    let (ready, condvar) = &*pair;
+    let guard = condvar.wait_while(ready.lock(), |ready| !*ready);
+
+    assert!(*guard);
+
+    serial_println!("[ok]");
+}
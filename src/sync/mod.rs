@@ -0,0 +1,64 @@
+//! Sleeping synchronisation primitives.
+//!
+//! Unlike `spin::Mutex`, a task waiting on these is put to sleep on a
+//! `WaitQueue` and the lock can safely be held across a reschedule.
+//! They can only be used once the scheduler is running, and never from an
+//! interrupt handler.
+
+mod condvar;
+mod mutex;
+mod rwlock;
+mod semaphore;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(debug_assertions)]
+const NO_OWNER: u64 = core::u64::MAX;
+
+/// Task holding a lock.
+/// Only tracked in debug builds, to detect a task locking twice the same
+/// lock instead of silently deadlocking.
+struct Owner {
+    #[cfg(debug_assertions)]
+    task: AtomicU64,
+}
+
+impl Owner {
+    fn new() -> Self {
+        Self {
+            #[cfg(debug_assertions)]
+            task: AtomicU64::new(NO_OWNER),
+        }
+    }
+
+    fn acquired(&self) {
+        #[cfg(debug_assertions)]
+        self.task
+            .store(crate::schedule::current_task_id().0, Ordering::SeqCst);
+    }
+
+    fn released(&self) {
+        #[cfg(debug_assertions)]
+        self.task.store(NO_OWNER, Ordering::SeqCst);
+    }
+
+    fn assert_not_current(&self, kind: &str) {
+        #[cfg(debug_assertions)]
+        {
+            let current = crate::schedule::current_task_id();
+
+            if self.task.load(Ordering::SeqCst) == current.0 {
+                panic!("Recursive locking of a {} by task {}", kind, current);
+            }
+        }
+
+        #[cfg(not(debug_assertions))]
+        let _ = kind;
+    }
+}
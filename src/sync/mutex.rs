@@ -0,0 +1,154 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::Owner;
+use crate::schedule::WaitQueue;
+
+/// Mutual exclusion lock putting the waiting tasks to sleep
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    owner: Owner,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: Owner::new(),
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.owner.assert_not_current("Mutex");
+
+        self.queue.wait_until(|| self.acquire());
+
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.acquire() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::SeqCst)
+    }
+
+    fn acquire(&self) -> bool {
+        let acquired = self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        if acquired {
+            self.owner.acquired();
+        }
+
+        acquired
+    }
+
+    fn release(&self) {
+        self.owner.released();
+        self.locked.store(false, Ordering::Release);
+        self.queue.wake_one();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.release();
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_mutex_held_across_yield() {
+    use super::Semaphore;
+    use crate::schedule;
+    use alloc::sync::Arc;
+
+    serial_print!("test_mutex_held_across_yield... ");
+
+    let counter = Arc::new(Mutex::new(0));
+    let done = Arc::new(Semaphore::new(0));
+
+    for _ in 0..2 {
+        let counter = counter.clone();
+        let done = done.clone();
+
+        schedule::spawn(move || {
+            for _ in 0..50 {
+                let mut value = counter.lock();
+                let read = *value;
+
+                schedule::yield_now();
+
+                *value = read + 1;
+            }
+
+            done.release();
+        });
+    }
+
+    done.acquire();
+    done.acquire();
+
+    assert_eq!(*counter.lock(), 100);
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_mutex_try_lock() {
+    serial_print!("test_mutex_try_lock... ");
+
+    let mutex = Mutex::new(());
+
+    let guard = mutex.lock();
+    assert!(mutex.try_lock().is_none());
+
+    drop(guard);
+    assert!(mutex.try_lock().is_some());
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,189 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::Owner;
+use crate::schedule::WaitQueue;
+
+// Set in `state` while a writer holds the lock, the other bits count the
+// readers
+const WRITER: usize = 1 << (core::mem::size_of::<usize>() * 8 - 1);
+
+/// Reader-writer lock putting the waiting tasks to sleep
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    writer: Owner,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            writer: Owner::new(),
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.writer.assert_not_current("RwLock");
+
+        self.queue.wait_until(|| self.acquire_read());
+
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.writer.assert_not_current("RwLock");
+
+        self.queue.wait_until(|| self.acquire_write());
+
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if self.acquire_read() {
+            Some(RwLockReadGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self.acquire_write() {
+            Some(RwLockWriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    fn acquire_read(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+
+        loop {
+            if state & WRITER != 0 {
+                return false;
+            }
+
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    fn acquire_write(&self) -> bool {
+        let acquired = self
+            .state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        if acquired {
+            self.writer.acquired();
+        }
+
+        acquired
+    }
+
+    fn release_read(&self) {
+        // Last reader out lets the writers in
+        if self.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.queue.wake_all();
+        }
+    }
+
+    fn release_write(&self) {
+        self.writer.released();
+        self.state.store(0, Ordering::Release);
+        self.queue.wake_all();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release_read();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release_write();
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_rwlock_readers_and_writer() {
+    serial_print!("test_rwlock_readers_and_writer... ");
+
+    let lock = RwLock::new(1);
+
+    {
+        let first = lock.read();
+        let second = lock.read();
+
+        assert_eq!(*first + *second, 2);
+        assert!(lock.try_write().is_none());
+    }
+
+    {
+        let mut writer = lock.write();
+        *writer = 2;
+
+        assert!(lock.try_read().is_none());
+    }
+
+    assert_eq!(*lock.read(), 2);
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,74 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::schedule::WaitQueue;
+
+/// Counting semaphore putting the waiting tasks to sleep
+pub struct Semaphore {
+    count: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Takes one unit, sleeping until one is available
+    pub fn acquire(&self) {
+        self.queue.wait_until(|| self.try_acquire());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::Relaxed);
+
+        loop {
+            if count == 0 {
+                return false;
+            }
+
+            match self.count.compare_exchange_weak(
+                count,
+                count - 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => count = actual,
+            }
+        }
+    }
+
+    /// Gives one unit back and wakes up a waiter
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.queue.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_semaphore_count() {
+    serial_print!("test_semaphore_count... ");
+
+    let semaphore = Semaphore::new(2);
+
+    assert!(semaphore.try_acquire());
+    assert!(semaphore.try_acquire());
+    assert!(!semaphore.try_acquire());
+
+    semaphore.release();
+    assert_eq!(semaphore.count(), 1);
+
+    serial_println!("[ok]");
+}
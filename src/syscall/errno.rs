@@ -0,0 +1,38 @@
+use core::fmt;
+
+/// Error returned to userland, as `-errno` in rax
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EINVAL = 22,
+    EMFILE = 24,
+    EPIPE = 32,
+    ENOSYS = 38,
+    ETIMEDOUT = 110,
+}
+
+impl Errno {
+    pub fn as_return_value(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
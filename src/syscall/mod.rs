@@ -0,0 +1,672 @@
+//! Kernel/user boundary.
+//!
+//! Userland enters with `syscall` (or `int 0x80` when debugging), the number
+//! in rax and up to 6 arguments in rdi, rsi, rdx, r10, r8 and r9. The result
+//! is returned in rax, errors as `-errno`.
+
+use alloc::{string::String, sync::Arc};
+use x86_64::{registers::model_specific::Msr, structures::idt::HandlerFunc, VirtAddr};
+
+use crate::ipc::{
+    futex, pipe,
+    port::{self, Capability, Message, MAX_PORT_NAME_LEN},
+};
+use crate::memory::paging;
+use crate::process::{self, signal, signal::SigHandler, Pid};
+use crate::smp::cpu;
+use crate::{gdt, schedule, serial_println, time, usermode};
+
+mod errno;
+pub mod user_ptr;
+
+pub use errno::Errno;
+
+pub type SyscallResult = Result<u64, Errno>;
+pub type SyscallHandler = fn(&mut SyscallFrame) -> SyscallResult;
+
+pub const SYSCALL_VECTOR: usize = 0x80;
+
+pub const SYS_EXIT: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_YIELD: usize = 2;
+pub const SYS_GETTID: usize = 3;
+pub const SYS_READ: usize = 4;
+pub const SYS_CLOSE: usize = 5;
+pub const SYS_GETPID: usize = 6;
+pub const SYS_GETPPID: usize = 7;
+pub const SYS_WAIT: usize = 8;
+pub const SYS_FORK: usize = 9;
+pub const SYS_EXECVE: usize = 10;
+pub const SYS_KILL: usize = 11;
+pub const SYS_SIGACTION: usize = 12;
+pub const SYS_SIGPROCMASK: usize = 13;
+pub const SYS_SIGRETURN: usize = 14;
+pub const SYS_PIPE: usize = 15;
+pub const SYS_DUP: usize = 16;
+pub const SYS_DUP2: usize = 17;
+pub const SYS_PORT_CREATE: usize = 18;
+pub const SYS_PORT_LOOKUP: usize = 19;
+pub const SYS_SEND: usize = 20;
+pub const SYS_RECEIVE: usize = 21;
+pub const SYS_CALL: usize = 22;
+pub const SYS_REPLY: usize = 23;
+pub const SYS_HANDLE_CLOSE: usize = 24;
+pub const SYS_FUTEX_WAIT: usize = 25;
+pub const SYS_FUTEX_WAKE: usize = 26;
+pub const SYS_THREAD_CREATE: usize = 27;
+pub const SYS_THREAD_EXIT: usize = 28;
+pub const SYS_SET_TLS: usize = 29;
+pub const SYS_TASK_LIST: usize = 30;
+pub const SYS_GETRLIMIT: usize = 31;
+pub const SYS_SETRLIMIT: usize = 32;
+pub const SYS_GETTIMEOFDAY: usize = 33;
+
+static SYSCALL_TABLE: [Option<SyscallHandler>; 34] = [
+    Some(sys_exit),
+    Some(sys_write),
+    Some(sys_yield),
+    Some(sys_gettid),
+    Some(sys_read),
+    Some(sys_close),
+    Some(sys_getpid),
+    Some(sys_getppid),
+    Some(sys_wait),
+    Some(sys_fork),
+    Some(sys_execve),
+    Some(sys_kill),
+    Some(sys_sigaction),
+    Some(sys_sigprocmask),
+    Some(sys_sigreturn),
+    Some(sys_pipe),
+    Some(sys_dup),
+    Some(sys_dup2),
+    Some(sys_port_create),
+    Some(sys_port_lookup),
+    Some(sys_send),
+    Some(sys_receive),
+    Some(sys_call),
+    Some(sys_reply),
+    Some(sys_handle_close),
+    Some(sys_futex_wait),
+    Some(sys_futex_wake),
+    Some(sys_thread_create),
+    Some(sys_thread_exit),
+    Some(sys_set_tls),
+    Some(sys_task_list),
+    Some(sys_getrlimit),
+    Some(sys_setrlimit),
+    Some(sys_gettimeofday),
+];
+
+// Limits on what `execve` copies from userland
+const MAX_PATH_LEN: u64 = 4096;
+const MAX_ARG_LEN: u64 = 4096;
+const MAX_ARGS: usize = 256;
+
+const IA32_EFER: u32 = 0xc000_0080;
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+
+const EFER_SYSTEM_CALL_EXTENSIONS: u64 = 1;
+
+// Cleared on entry: interrupts (re-enabled once on the kernel stack), trap
+// and direction flags
+const SYSCALL_FLAGS_MASK: u64 = 0x200 | 0x100 | 0x400;
+
+extern "C" {
+    fn syscall_entry();
+    fn int80_entry();
+}
+
+/// User registers saved by the entry stubs, in push order reversed.
+/// Any modification is restored when going back to userland.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn number(&self) -> usize {
+        self.rax as usize
+    }
+
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+pub fn init() {
+    let selectors = (gdt::kernel_code_selector(), gdt::user_data_selector());
+
+    serial_println!(
+        "   Kernel CS: {:?}, User SS: {:?}",
+        selectors.0,
+        selectors.1
+    );
+
+    // SYSCALL loads CS from STAR[47:32] and SS from the next entry.
+    // SYSRET loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16, the
+    // base is the entry right before user data.
+    let kernel_base = u64::from((selectors.0).0);
+    let user_base = u64::from((selectors.1).0) - 8;
+
+    unsafe {
+        let mut efer = Msr::new(IA32_EFER);
+        let value = efer.read();
+        efer.write(value | EFER_SYSTEM_CALL_EXTENSIONS);
+
+        Msr::new(IA32_STAR).write((user_base << 48) | (kernel_base << 32));
+        Msr::new(IA32_LSTAR).write(syscall_entry as u64);
+        Msr::new(IA32_FMASK).write(SYSCALL_FLAGS_MASK);
+    }
+}
+
+/// Handler of the `int 0x80` gate, installed in the IDT with DPL 3
+pub fn int80_handler() -> HandlerFunc {
+    unsafe { core::mem::transmute(int80_entry as unsafe extern "C" fn()) }
+}
+
+/// Stack used by the next syscall on the current processor, updated on
+/// every task switch
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    cpu::current().set_kernel_rsp(stack_top.as_u64());
+}
+
+/// Called by the entry stubs with interrupts enabled
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let handler = SYSCALL_TABLE
+        .get(frame.number())
+        .and_then(|handler| *handler);
+
+    let result = match handler {
+        Some(handler) => handler(frame),
+        None => Err(Errno::ENOSYS),
+    };
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => errno.as_return_value(),
+    };
+
+    signal::deliver(frame);
+}
+
+fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    let code = frame.args()[0];
+
+    process::exit_current(code as i32);
+}
+
+/// thread_create(entry, stack_top, arg, tls): returns the id of the new
+/// thread, it starts at `entry` with `arg` in rdi
+fn sys_thread_create(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (entry, stack_top, arg, tls) = (args[0], args[1], args[2], args[3]);
+
+    if !is_user_addr(entry) || !is_user_addr(stack_top) || (tls != 0 && !is_user_addr(tls)) {
+        return Err(Errno::EFAULT);
+    }
+
+    process::create_thread(entry, stack_top, arg, tls).map(|id| id.0)
+}
+
+fn sys_thread_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    let code = frame.args()[0];
+
+    process::exit_thread(code as i32);
+}
+
+/// set_tls(addr): sets the FS base of the current thread
+fn sys_set_tls(frame: &mut SyscallFrame) -> SyscallResult {
+    let tls = frame.args()[0];
+
+    // Writing a non canonical address to the register would fault
+    if tls != 0 && !is_user_addr(tls) {
+        return Err(Errno::EFAULT);
+    }
+
+    schedule::set_fs_base(tls);
+
+    Ok(0)
+}
+
+fn is_user_addr(addr: u64) -> bool {
+    addr >= paging::USER_SPACE_START && addr < paging::USER_SPACE_END
+}
+
+fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (fd, buf, len) = (args[0], args[1], args[2]);
+
+    let file = process::current_file(fd)?;
+    let bytes = user_ptr::user_slice(buf, len)?;
+
+    file.write(bytes).map(|written| written as u64)
+}
+
+fn sys_read(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (fd, buf, len) = (args[0], args[1], args[2]);
+
+    let file = process::current_file(fd)?;
+    let bytes = user_ptr::user_slice_mut(buf, len)?;
+
+    file.read(bytes).map(|read| read as u64)
+}
+
+fn sys_close(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd = frame.args()[0];
+    let pid = process::current_pid();
+
+    // Dropped outside of the process table lock
+    let file = process::with_processes(|table| table.get_mut(pid).unwrap().files.remove(fd))?;
+
+    drop(file);
+
+    Ok(0)
+}
+
+fn sys_pipe(frame: &mut SyscallFrame) -> SyscallResult {
+    let fds = frame.args()[0];
+    let pid = process::current_pid();
+
+    user_ptr::check_range(fds, core::mem::size_of::<[i32; 2]>() as u64, true)?;
+
+    let (reader, writer) = pipe::pipe();
+
+    let (read_fd, write_fd) = process::with_processes(|table| {
+        let process = table.get_mut(pid).unwrap();
+        let read_fd = process.open_file(Arc::new(reader))?;
+
+        match process.open_file(Arc::new(writer)) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(err) => {
+                // Nothing can wait on the new pipe yet, closing it here is fine
+                process.files.remove(read_fd).unwrap();
+
+                Err(err)
+            }
+        }
+    })?;
+
+    user_ptr::write_user(fds, [read_fd as i32, write_fd as i32])?;
+
+    Ok(0)
+}
+
+fn sys_dup(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd = frame.args()[0];
+    let pid = process::current_pid();
+
+    let file = process::current_file(fd)?;
+
+    process::with_processes(|table| table.get_mut(pid).unwrap().open_file(file))
+}
+
+fn sys_dup2(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (old_fd, new_fd) = (args[0], args[1]);
+    let pid = process::current_pid();
+
+    let file = process::current_file(old_fd)?;
+
+    if old_fd == new_fd {
+        return Ok(new_fd);
+    }
+
+    // Dropped outside of the process table lock
+    let replaced =
+        process::with_processes(|table| table.get_mut(pid).unwrap().open_file_at(new_fd, file))?;
+
+    drop(replaced);
+
+    Ok(new_fd)
+}
+
+/// port_create(name): the name is optional, a null one creates an anonymous
+/// port. Returns a handle with the right to receive.
+fn sys_port_create(frame: &mut SyscallFrame) -> SyscallResult {
+    let name = frame.args()[0];
+
+    let name = match name {
+        0 => None,
+        name => Some(user_ptr::user_str(name, MAX_PORT_NAME_LEN)?),
+    };
+
+    let right = port::create(name)?;
+
+    insert_handle(Capability::Receive(Arc::new(right)))
+}
+
+/// port_lookup(name): returns a handle with the right to send to the port
+fn sys_port_lookup(frame: &mut SyscallFrame) -> SyscallResult {
+    let name = user_ptr::user_str(frame.args()[0], MAX_PORT_NAME_LEN)?;
+
+    let port = port::lookup(name)?;
+
+    insert_handle(Capability::Send(port))
+}
+
+fn sys_send(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (handle, buf, len) = (args[0], args[1], args[2]);
+
+    let capability = process::current_handle(handle)?;
+    let message = Message::new(user_ptr::user_slice(buf, len)?)?;
+
+    capability.send_port()?.send(message)?;
+
+    Ok(0)
+}
+
+/// What `receive` tells about a message besides its content
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MessageInfo {
+    pub sender: u64,
+    /// Handle to answer with, -1 if the sender does not wait for an answer
+    pub reply_handle: i64,
+}
+
+/// receive(handle, buf, len, info): returns the length of the message, the
+/// end of it is lost if `buf` is too small. Unless `info` is null, the sender
+/// and a reply handle are stored there, otherwise a call is failed.
+fn sys_receive(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (handle, buf, len, info) = (args[0], args[1], args[2], args[3]);
+
+    let right = match process::current_handle(handle)? {
+        Capability::Receive(right) => right,
+        _ => return Err(Errno::EBADF),
+    };
+
+    // Checked before sleeping, the message would be lost on a bad pointer
+    let buf = user_ptr::user_slice_mut(buf, len)?;
+
+    if info != 0 {
+        user_ptr::check_range(info, core::mem::size_of::<MessageInfo>() as u64, true)?;
+    }
+
+    let mut message = right.receive()?;
+    let len = message.copy_to(buf);
+
+    if info != 0 {
+        let reply_handle = match message.reply.take() {
+            Some(reply) => insert_handle(Capability::Reply(Arc::new(reply)))? as i64,
+            None => -1,
+        };
+
+        let info_value = MessageInfo {
+            sender: message.sender.0,
+            reply_handle,
+        };
+
+        user_ptr::write_user(info, info_value)?;
+    }
+
+    Ok(len as u64)
+}
+
+/// call(handle, buf, len, reply_buf, reply_len): sends a message and waits
+/// for the answer. Returns the length of the answer, copied like `receive`.
+fn sys_call(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (handle, buf, len, reply_buf, reply_len) = (args[0], args[1], args[2], args[3], args[4]);
+
+    let capability = process::current_handle(handle)?;
+    let message = Message::new(user_ptr::user_slice(buf, len)?)?;
+    let reply_buf = user_ptr::user_slice_mut(reply_buf, reply_len)?;
+
+    let answer = capability.send_port()?.call(message)?;
+
+    Ok(answer.copy_to(reply_buf) as u64)
+}
+
+/// reply(handle, buf, len): answers a call, the reply handle is consumed
+fn sys_reply(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (handle, buf, len) = (args[0], args[1], args[2]);
+    let pid = process::current_pid();
+
+    let message = Message::new(user_ptr::user_slice(buf, len)?)?;
+
+    let reply = match process::current_handle(handle)? {
+        Capability::Reply(reply) => reply,
+        _ => return Err(Errno::EBADF),
+    };
+
+    // Consumed even if the answer fails, the call is over either way. Dropped
+    // outside of the process table lock.
+    let capability =
+        process::with_processes(|table| table.get_mut(pid).unwrap().handles.remove(handle))?;
+
+    drop(capability);
+
+    reply.reply(message)?;
+
+    Ok(0)
+}
+
+fn sys_handle_close(frame: &mut SyscallFrame) -> SyscallResult {
+    let handle = frame.args()[0];
+    let pid = process::current_pid();
+
+    // Dropped outside of the process table lock
+    let capability =
+        process::with_processes(|table| table.get_mut(pid).unwrap().handles.remove(handle))?;
+
+    drop(capability);
+
+    Ok(0)
+}
+
+/// futex_wait(addr, expected, timeout_ms): a null timeout waits forever
+fn sys_futex_wait(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (addr, expected, timeout_ms) = (args[0], args[1] as u32, args[2]);
+
+    let timeout = match timeout_ms {
+        0 => None,
+        ms => Some(schedule::ms_to_ticks(ms)),
+    };
+
+    futex::wait(addr, expected, timeout)?;
+
+    Ok(0)
+}
+
+/// futex_wake(addr, count): returns the number of tasks woken up
+fn sys_futex_wake(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (addr, count) = (args[0], args[1]);
+
+    futex::wake(addr, count as usize).map(|woken| woken as u64)
+}
+
+fn insert_handle(capability: Capability) -> SyscallResult {
+    let pid = process::current_pid();
+
+    process::with_processes(|table| table.get_mut(pid).unwrap().open_handle(capability))
+}
+
+fn sys_yield(_frame: &mut SyscallFrame) -> SyscallResult {
+    schedule::yield_now();
+
+    Ok(0)
+}
+
+fn sys_gettid(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(schedule::current_task_id().0)
+}
+
+/// task_list(buf, count): copies up to `count` `TaskInfo` records to `buf`
+/// and returns the number of tasks, which may be more
+fn sys_task_list(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (buf, count) = (args[0], args[1]);
+
+    let record_size = core::mem::size_of::<schedule::TaskInfo>() as u64;
+    let size = count.checked_mul(record_size).ok_or(Errno::EINVAL)?;
+
+    user_ptr::check_range(buf, size, true)?;
+
+    let tasks = schedule::tasks();
+
+    for (index, task) in tasks.iter().take(count as usize).enumerate() {
+        user_ptr::write_user(buf + index as u64 * record_size, *task)?;
+    }
+
+    Ok(tasks.len() as u64)
+}
+
+/// getrlimit(resource): returns the limit, `RLIM_INFINITY` if there is none
+fn sys_getrlimit(frame: &mut SyscallFrame) -> SyscallResult {
+    let resource = frame.args()[0];
+    let pid = process::current_pid();
+
+    process::with_processes(|table| table.get(pid).unwrap().limits.get(resource))
+}
+
+/// setrlimit(resource, value): limits can only be lowered
+fn sys_setrlimit(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (resource, value) = (args[0], args[1]);
+    let pid = process::current_pid();
+
+    process::with_processes(|table| table.get_mut(pid).unwrap().limits.lower(resource, value))?;
+
+    Ok(0)
+}
+
+/// gettimeofday(tv): returns the seconds since the epoch, and copies the
+/// `Timeval` to `tv` unless it is null
+fn sys_gettimeofday(frame: &mut SyscallFrame) -> SyscallResult {
+    let tv = frame.args()[0];
+    let now = time::gettimeofday();
+
+    if tv != 0 {
+        user_ptr::write_user(tv, now)?;
+    }
+
+    Ok(now.sec)
+}
+
+fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(process::current_pid().0)
+}
+
+fn sys_getppid(_frame: &mut SyscallFrame) -> SyscallResult {
+    let parent = process::parent_pid(process::current_pid()).ok_or(Errno::ESRCH)?;
+
+    Ok(parent.0)
+}
+
+/// wait(pid, status): pid -1 waits for any child. The exit code is stored
+/// at `status` unless it is null.
+fn sys_wait(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (target, status) = (args[0] as i64, args[1]);
+
+    let target = match target {
+        -1 => None,
+        pid if pid > 0 => Some(Pid(pid as u64)),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    // Checked before sleeping, the child would be lost on a bad pointer
+    if status != 0 {
+        user_ptr::check_range(status, core::mem::size_of::<i32>() as u64, true)?;
+    }
+
+    let (pid, code) = process::wait(target)?;
+
+    if status != 0 {
+        user_ptr::write_user(status, code)?;
+    }
+
+    Ok(pid.0)
+}
+
+fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult {
+    process::fork(frame).map(|pid| pid.0)
+}
+
+/// execve(path, argv, envp): argv and envp are null terminated arrays of
+/// strings, either may be null. Only returns on error.
+fn sys_execve(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+
+    // Copied now, the user memory is about to go away
+    let path = String::from(user_ptr::user_str(args[0], MAX_PATH_LEN)?);
+    let argv = user_ptr::user_str_array(args[1], MAX_ARGS, MAX_ARG_LEN)?;
+    let envp = user_ptr::user_str_array(args[2], MAX_ARGS, MAX_ARG_LEN)?;
+
+    let (entry, stack_pointer) = process::exec(&path, &argv, &envp)?;
+
+    // Nothing on this stack is dropped once in userland
+    drop((path, argv, envp));
+
+    unsafe { usermode::enter(entry, stack_pointer) }
+}
+
+fn sys_kill(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+
+    signal::send(Pid(args[0]), args[1])?;
+
+    Ok(0)
+}
+
+/// sigaction(signal, handler, mask, restorer): the handler is SIG_DFL,
+/// SIG_IGN or a function returning to `restorer`. Returns the previous
+/// handler address.
+fn sys_sigaction(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (signal, handler, mask, restorer) = (args[0], args[1], args[2], args[3]);
+
+    let handler = match handler {
+        signal::SIG_DFL => SigHandler::Default,
+        signal::SIG_IGN => SigHandler::Ignore,
+        _ if is_user_addr(handler) && is_user_addr(restorer) => SigHandler::User {
+            handler,
+            mask,
+            restorer,
+        },
+        _ => return Err(Errno::EFAULT),
+    };
+
+    match signal::set_handler(signal, handler)? {
+        SigHandler::Default => Ok(signal::SIG_DFL),
+        SigHandler::Ignore => Ok(signal::SIG_IGN),
+        SigHandler::User { handler, .. } => Ok(handler),
+    }
+}
+
+/// sigprocmask(how, set): returns the previous mask
+fn sys_sigprocmask(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+
+    signal::set_blocked(args[0], args[1])
+}
+
+fn sys_sigreturn(frame: &mut SyscallFrame) -> SyscallResult {
+    signal::sigreturn(frame)
+}
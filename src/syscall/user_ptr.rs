@@ -0,0 +1,162 @@
+use alloc::{string::String, vec::Vec};
+use core::mem::size_of;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+use super::Errno;
+use crate::memory::paging::{self, cow, helpers, PAGE_SIZE};
+use crate::process;
+
+/// Checks that `[addr, addr + len)` is user memory of the current address
+/// space. Pages that are not mapped yet are demand paged like the page fault
+/// handler would do.
+pub fn check_range(addr: u64, len: u64, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    // Compared as integers first, `VirtAddr::new` panics on non canonical
+    // addresses
+    let end = addr.checked_add(len).ok_or(Errno::EFAULT)?;
+
+    if addr < paging::USER_SPACE_START || end > paging::USER_SPACE_END {
+        return Err(Errno::EFAULT);
+    }
+
+    let first = Page::containing_address(VirtAddr::new(addr));
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+
+    for page in Page::range_inclusive(first, last) {
+        match helpers::page_flags(page) {
+            None => process::demand_page(page.start_address())?,
+            Some(flags) => {
+                if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                    return Err(Errno::EFAULT);
+                }
+
+                // The kernel does not fault on read-only pages, copy-on-write
+                // has to be resolved here
+                if write
+                    && !flags.contains(PageTableFlags::WRITABLE)
+                    && !cow::copy_on_write(page.start_address())
+                {
+                    return Err(Errno::EFAULT);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn user_slice<'a>(addr: u64, len: u64) -> Result<&'a [u8], Errno> {
+    check_range(addr, len, false)?;
+
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+pub fn user_slice_mut<'a>(addr: u64, len: u64) -> Result<&'a mut [u8], Errno> {
+    check_range(addr, len, true)?;
+
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+pub fn read_user<T: Copy>(addr: u64) -> Result<T, Errno> {
+    check_range(addr, size_of::<T>() as u64, false)?;
+
+    Ok(unsafe { core::ptr::read_unaligned(addr as *const T) })
+}
+
+pub fn write_user<T: Copy>(addr: u64, value: T) -> Result<(), Errno> {
+    check_range(addr, size_of::<T>() as u64, true)?;
+
+    unsafe { core::ptr::write_unaligned(addr as *mut T, value) };
+
+    Ok(())
+}
+
+/// Reads a NUL terminated string of at most `max_len` bytes
+pub fn user_str<'a>(addr: u64, max_len: u64) -> Result<&'a str, Errno> {
+    if addr < paging::USER_SPACE_START || addr >= paging::USER_SPACE_END {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut len = 0;
+
+    loop {
+        if len >= max_len {
+            return Err(Errno::EINVAL);
+        }
+
+        // Check one page at a time, the string may end before the next one
+        let page_end = (addr + len) / PAGE_SIZE * PAGE_SIZE + PAGE_SIZE;
+        let chunk = user_slice(addr + len, page_end - (addr + len))?;
+
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(position) => {
+                len += position as u64;
+                break;
+            }
+            None => len += chunk.len() as u64,
+        }
+    }
+
+    if len > max_len {
+        return Err(Errno::EINVAL);
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+
+    core::str::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
+
+/// Copies a null terminated array of strings, like the `argv` of `execve`.
+/// A null array is empty.
+pub fn user_str_array(addr: u64, max_count: usize, max_len: u64) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+
+    if addr == 0 {
+        return Ok(strings);
+    }
+
+    loop {
+        let pointer_addr = addr
+            .checked_add((strings.len() * size_of::<u64>()) as u64)
+            .ok_or(Errno::EFAULT)?;
+        let pointer: u64 = read_user(pointer_addr)?;
+
+        if pointer == 0 {
+            return Ok(strings);
+        }
+
+        if strings.len() >= max_count {
+            return Err(Errno::E2BIG);
+        }
+
+        strings.push(String::from(user_str(pointer, max_len)?));
+    }
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_check_range_rejects_kernel_memory() {
+    serial_print!("test_check_range_rejects_kernel_memory... ");
+
+    assert_eq!(check_range(0x100000, 8, false), Err(Errno::EFAULT));
+    assert_eq!(
+        check_range(paging::USER_SPACE_END - 4, 8, false),
+        Err(Errno::EFAULT)
+    );
+    assert_eq!(
+        check_range(core::u64::MAX - 4, 8, false),
+        Err(Errno::EFAULT)
+    );
+
+    serial_println!("[ok]");
+}
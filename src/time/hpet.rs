@@ -0,0 +1,188 @@
+//! HPET: a counter running at a fixed rate of at least 10 MHz, with
+//! comparators raising interrupts when it reaches their value.
+//!
+//! Only the comparator 0 is used, in legacy replacement mode: its interrupt
+//! takes the place of the PIT on IRQ 0.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+use super::NS_PER_SEC;
+use crate::acpi::hpet::HpetTable;
+use crate::memory::paging::helpers;
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIGURATION: u64 = 0x010;
+const REG_MAIN_COUNTER: u64 = 0x0f0;
+const REG_TIMER_0_CONFIGURATION: u64 = 0x100;
+const REG_TIMER_0_COMPARATOR: u64 = 0x108;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+// The next comparator write sets the period of a periodic timer
+const TIMER_SET_PERIOD: u64 = 1 << 6;
+
+const FEMTOSECONDS_PER_NS: u64 = 1_000_000;
+// The counter period must be at most 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// Address of the registers, zero without HPET
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Counter period in femtoseconds
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+/// Smallest period of a periodic comparator, in counts
+static MINIMUM_TICK: AtomicU64 = AtomicU64::new(0);
+
+// Read from the table, it is a copy of the capabilities
+static COUNTER_64_BITS: AtomicBool = AtomicBool::new(false);
+static LEGACY_ROUTE: AtomicBool = AtomicBool::new(false);
+
+unsafe fn read_register(reg: u64) -> u64 {
+    core::ptr::read_volatile((BASE.load(Ordering::Relaxed) + reg) as *const u64)
+}
+
+unsafe fn write_register(reg: u64, value: u64) {
+    core::ptr::write_volatile((BASE.load(Ordering::Relaxed) + reg) as *mut u64, value)
+}
+
+/// Maps the registers and starts the counter, returns false if the HPET
+/// is not usable
+pub fn init(table: &HpetTable) -> bool {
+    helpers::identity_map_device(PhysAddr::new(table.address), 4096);
+
+    BASE.store(table.address, Ordering::SeqCst);
+
+    let period = unsafe { read_register(REG_CAPABILITIES) } >> 32;
+
+    if period == 0 || period > MAX_PERIOD_FS {
+        BASE.store(0, Ordering::SeqCst);
+
+        return false;
+    }
+
+    PERIOD.store(period, Ordering::SeqCst);
+    MINIMUM_TICK.store(u64::from(table.minimum_tick), Ordering::SeqCst);
+    COUNTER_64_BITS.store(table.counter_64_bits, Ordering::SeqCst);
+    LEGACY_ROUTE.store(table.legacy_route, Ordering::SeqCst);
+
+    unsafe {
+        write_register(REG_TIMER_0_CONFIGURATION, 0);
+
+        let configuration = read_register(REG_CONFIGURATION);
+
+        write_register(REG_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+    }
+
+    true
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Whether the counter takes years to wrap around, 32 bits ones wrap in
+/// minutes
+pub fn is_64_bits() -> bool {
+    COUNTER_64_BITS.load(Ordering::Relaxed)
+}
+
+/// Whether the comparator 0 can raise the timer interrupt instead of the
+/// PIT, at a periodic rate
+pub fn can_replace_pit() -> bool {
+    LEGACY_ROUTE.load(Ordering::Relaxed)
+        && unsafe { read_register(REG_TIMER_0_CONFIGURATION) } & TIMER_PERIODIC_CAPABLE != 0
+}
+
+/// Whether the comparators took over IRQ 0 and IRQ 8 from the PIT and the
+/// RTC
+pub fn replaces_legacy_timers() -> bool {
+    unsafe { read_register(REG_CONFIGURATION) & CONFIGURATION_LEGACY_ROUTE != 0 }
+}
+
+/// Counts per second
+pub fn frequency() -> u64 {
+    NS_PER_SEC * FEMTOSECONDS_PER_NS / PERIOD.load(Ordering::Relaxed)
+}
+
+pub fn read() -> u64 {
+    unsafe { read_register(REG_MAIN_COUNTER) }
+}
+
+/// Nanoseconds covered by `counts`
+pub fn counts_to_ns(counts: u64) -> u64 {
+    (u128::from(counts) * u128::from(PERIOD.load(Ordering::Relaxed))
+        / u128::from(FEMTOSECONDS_PER_NS)) as u64
+}
+
+fn ns_to_counts(ns: u64) -> u64 {
+    (u128::from(ns) * u128::from(FEMTOSECONDS_PER_NS) / u128::from(PERIOD.load(Ordering::Relaxed)))
+        .max(1) as u64
+}
+
+/// Spins for `ns`. Usable with interrupts disabled, it raises none.
+pub fn busy_wait(ns: u64) {
+    let start = read();
+    let counts = ns_to_counts(ns);
+
+    // Wrapping, a 32 bits counter reads back as 32 bits
+    while read().wrapping_sub(start) & counter_mask() < counts {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+fn counter_mask() -> u64 {
+    if is_64_bits() {
+        u64::max_value()
+    } else {
+        u64::from(u32::max_value())
+    }
+}
+
+unsafe fn enable_legacy_route() {
+    let configuration = read_register(REG_CONFIGURATION);
+
+    write_register(
+        REG_CONFIGURATION,
+        configuration | CONFIGURATION_LEGACY_ROUTE,
+    );
+}
+
+/// Raises IRQ 0 every `ns`, in place of the PIT. Periods shorter than the
+/// minimum tick of the table are lengthened.
+pub fn set_periodic(ns: u64) {
+    unsafe {
+        enable_legacy_route();
+
+        let period = ns_to_counts(ns).max(MINIMUM_TICK.load(Ordering::Relaxed));
+
+        write_register(
+            REG_TIMER_0_CONFIGURATION,
+            TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_SET_PERIOD,
+        );
+        // First the next deadline, then the period
+        write_register(REG_TIMER_0_COMPARATOR, read().wrapping_add(period));
+        write_register(REG_TIMER_0_COMPARATOR, period);
+    }
+}
+
+/// Raises IRQ 0 once, after `ns`, in place of the PIT
+pub fn set_oneshot(ns: u64) {
+    unsafe {
+        enable_legacy_route();
+
+        write_register(REG_TIMER_0_CONFIGURATION, TIMER_INTERRUPT_ENABLE);
+        write_register(
+            REG_TIMER_0_COMPARATOR,
+            read().wrapping_add(ns_to_counts(ns)),
+        );
+    }
+}
+
+/// Stops the interrupts of the comparator 0
+pub fn stop() {
+    unsafe { write_register(REG_TIMER_0_CONFIGURATION, 0) };
+}
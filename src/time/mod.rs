@@ -0,0 +1,415 @@
+//! Timekeeping: the timer interrupt and the monotonic clock.
+//!
+//! The timer raises `HZ` interrupts per second, from the local APIC timer of
+//! each processor when the APIC is enabled, otherwise from the HPET or the
+//! PIT. While the idle task of a processor runs, its periodic tick is
+//! replaced by a single interrupt at its next deadline; the HPET and the PIT
+//! only tick for the boot processor. The monotonic clock
+//! reads the best clocksource found at boot, the others are calibrated
+//! against it. The wall clock is the date of the RTC at boot, moved forward
+//! by the monotonic clock.
+
+use core::cell::Cell;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::acpi::hpet::HpetTable;
+use crate::interrupts::{self, apic, irq};
+use crate::percpu;
+use crate::process::{self, signal};
+use crate::schedule;
+use crate::serial_println;
+use crate::smp::cpu;
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+/// Timer interrupts per second
+pub const HZ: u64 = 100;
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+pub const NS_PER_TICK: u64 = NS_PER_SEC / HZ;
+
+const CALIBRATION_NS: u64 = 10_000_000;
+
+/// Counter read by the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Clocksource {
+    /// Cycles of the processor, the cheapest to read
+    Tsc,
+    Hpet,
+    /// Timer interrupts, as precise as a tick
+    Pit,
+}
+
+impl fmt::Display for Clocksource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Clocksource::Tsc => write!(f, "TSC"),
+            Clocksource::Hpet => write!(f, "HPET"),
+            Clocksource::Pit => write!(f, "PIT"),
+        }
+    }
+}
+
+/// Timer raising the ticks and the one-shot interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum EventDevice {
+    Apic,
+    /// Comparator 0, on IRQ 0
+    Hpet,
+    Pit,
+}
+
+static CLOCKSOURCE: AtomicU8 = AtomicU8::new(Clocksource::Pit as u8);
+static EVENT_DEVICE: AtomicU8 = AtomicU8::new(EventDevice::Pit as u8);
+
+/// Cycles per second of the TSC
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// Reading of the clocksource at `init()`
+static CLOCK_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Counts per second of the APIC timer
+static APIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interrupts since `init()`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+percpu! {
+    /// Set while the idle task runs without the periodic tick
+    static TICK_STOPPED: Cell<bool> = Cell::new(false);
+}
+
+/// Nanoseconds from the epoch to the start of the monotonic clock
+static EPOCH_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Wall clock time, as returned to userland
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Timeval {
+    pub sec: u64,
+    pub usec: u64,
+}
+
+pub fn clocksource() -> Clocksource {
+    match CLOCKSOURCE.load(Ordering::Relaxed) {
+        0 => Clocksource::Tsc,
+        1 => Clocksource::Hpet,
+        _ => Clocksource::Pit,
+    }
+}
+
+fn event_device() -> EventDevice {
+    match EVENT_DEVICE.load(Ordering::Relaxed) {
+        0 => EventDevice::Apic,
+        1 => EventDevice::Hpet,
+        _ => EventDevice::Pit,
+    }
+}
+
+/// Picks the clocksource and the timer, calibrates them against the HPET
+/// or the PIT and starts the periodic tick. Needs the APIC set up, if there
+/// is one.
+pub fn init() {
+    let has_hpet = HpetTable::find().map_or(false, |table| hpet::init(&table));
+
+    let (tsc_cycles, apic_counts) = cpu_interrupts::without_interrupts(|| {
+        if apic::is_enabled() {
+            apic::start_timer_count();
+        }
+
+        let start = tsc::read();
+
+        if has_hpet {
+            hpet::busy_wait(CALIBRATION_NS);
+        } else {
+            pit::busy_wait(CALIBRATION_NS);
+        }
+
+        let tsc_cycles = tsc::read() - start;
+        let apic_counts = if apic::is_enabled() {
+            apic::stop_timer_count()
+        } else {
+            0
+        };
+
+        (tsc_cycles, apic_counts)
+    });
+
+    if tsc::is_supported() {
+        let frequency = tsc_cycles * (NS_PER_SEC / CALIBRATION_NS);
+
+        TSC_FREQUENCY.store(frequency, Ordering::SeqCst);
+
+        serial_println!("   TSC at {} kHz", frequency / 1000);
+    }
+
+    if has_hpet {
+        serial_println!("   HPET at {} kHz", hpet::frequency() / 1000);
+    }
+
+    // A TSC that changes rate with the processor frequency is only used
+    // when there is nothing better
+    let clocksource = if tsc::is_supported() && tsc::is_invariant() {
+        Clocksource::Tsc
+    } else if has_hpet && hpet::is_64_bits() {
+        Clocksource::Hpet
+    } else if tsc::is_supported() {
+        Clocksource::Tsc
+    } else {
+        Clocksource::Pit
+    };
+
+    CLOCK_BASE.store(
+        match clocksource {
+            Clocksource::Tsc => tsc::read(),
+            Clocksource::Hpet => hpet::read(),
+            Clocksource::Pit => 0,
+        },
+        Ordering::SeqCst,
+    );
+    CLOCKSOURCE.store(clocksource as u8, Ordering::SeqCst);
+
+    serial_println!("   Clocksource: {}", clocksource);
+
+    let event_device = if apic_counts != 0 {
+        EventDevice::Apic
+    } else if has_hpet && hpet::can_replace_pit() {
+        EventDevice::Hpet
+    } else {
+        EventDevice::Pit
+    };
+
+    EVENT_DEVICE.store(event_device as u8, Ordering::SeqCst);
+
+    if event_device == EventDevice::Apic {
+        let frequency = u64::from(apic_counts) * (NS_PER_SEC / CALIBRATION_NS);
+
+        APIC_TIMER_FREQUENCY.store(frequency, Ordering::SeqCst);
+
+        serial_println!("   APIC timer at {} kHz, {} Hz", frequency / 1000, HZ);
+    } else {
+        irq::register_irq(irq::IRQ_TIMER, legacy_timer_interrupt)
+            .expect("timer IRQ already registered");
+
+        serial_println!("   {:?} timer on IRQ 0 at {} Hz", event_device, HZ);
+    }
+
+    // Left running for nothing otherwise
+    if event_device != EventDevice::Pit {
+        pit::stop();
+    }
+
+    start_tick();
+
+    rtc::init();
+
+    let date = rtc::read();
+
+    EPOCH_OFFSET.store(
+        (date.to_unix() * NS_PER_SEC).saturating_sub(uptime()),
+        Ordering::SeqCst,
+    );
+
+    serial_println!("   Date: {} UTC", date);
+}
+
+/// Starts the periodic tick of an application processor, on its APIC timer
+/// calibrated like the one of the boot processor
+pub fn init_ap() {
+    if event_device() == EventDevice::Apic {
+        start_tick();
+    }
+}
+
+fn start_tick() {
+    match event_device() {
+        EventDevice::Apic => {
+            apic::set_timer_periodic((APIC_TIMER_FREQUENCY.load(Ordering::Relaxed) / HZ) as u32)
+        }
+        EventDevice::Hpet => hpet::set_periodic(NS_PER_TICK),
+        EventDevice::Pit => pit::set_periodic(HZ),
+    }
+}
+
+/// Spins for `ns` on the HPET or the PIT, whichever is there. Usable with
+/// interrupts disabled, before the clocks are set up.
+pub fn busy_wait(ns: u64) {
+    if hpet::is_enabled() {
+        return hpet::busy_wait(ns);
+    }
+
+    let mut left = ns;
+
+    while left > 0 {
+        let delay = left.min(pit::MAX_DELAY_NS);
+
+        pit::busy_wait(delay);
+        left -= delay;
+    }
+}
+
+/// Nanoseconds since the clocks were set up, never going back
+pub fn uptime() -> u64 {
+    let base = CLOCK_BASE.load(Ordering::Relaxed);
+
+    match clocksource() {
+        Clocksource::Tsc => {
+            let cycles = tsc::read().saturating_sub(base);
+
+            (u128::from(cycles) * u128::from(NS_PER_SEC)
+                / u128::from(TSC_FREQUENCY.load(Ordering::Relaxed))) as u64
+        }
+        Clocksource::Hpet => hpet::counts_to_ns(hpet::read().saturating_sub(base)),
+        Clocksource::Pit => TICKS.load(Ordering::Relaxed) * NS_PER_TICK,
+    }
+}
+
+/// Nanoseconds since 1970-01-01 00:00:00 UTC
+pub fn realtime() -> u64 {
+    EPOCH_OFFSET.load(Ordering::Relaxed) + uptime()
+}
+
+/// Seconds since 1970-01-01 00:00:00 UTC
+pub fn time() -> u64 {
+    realtime() / NS_PER_SEC
+}
+
+pub fn gettimeofday() -> Timeval {
+    let now = realtime();
+
+    Timeval {
+        sec: now / NS_PER_SEC,
+        usec: now % NS_PER_SEC / 1000,
+    }
+}
+
+/// Ticks elapsed according to the clock, counted even while the periodic
+/// tick is stopped
+pub fn ticks() -> u64 {
+    uptime() / NS_PER_TICK
+}
+
+/// Replaces the periodic tick of the current processor by one interrupt at
+/// tick `deadline`, or by none. Kept periodic when the clock is made of the
+/// ticks. Called by the idle task with interrupts disabled.
+pub fn stop_tick(deadline: Option<u64>) {
+    if clocksource() == Clocksource::Pit {
+        return;
+    }
+
+    // The HPET and the PIT tick for the boot processor only
+    if event_device() != EventDevice::Apic && !cpu::is_bsp() {
+        return;
+    }
+
+    let delay = deadline.map(|deadline| {
+        deadline
+            .saturating_mul(NS_PER_TICK)
+            .saturating_sub(uptime())
+    });
+
+    // Already due, the next tick handles it
+    if delay == Some(0) {
+        return;
+    }
+
+    TICK_STOPPED.get().set(true);
+
+    // A delay too long for the timer ends early, the idle task stops the
+    // tick again
+    match (event_device(), delay) {
+        (EventDevice::Apic, Some(ns)) => {
+            let count = u128::from(ns) * u128::from(APIC_TIMER_FREQUENCY.load(Ordering::Relaxed))
+                / u128::from(NS_PER_SEC);
+
+            apic::set_timer_oneshot(count.max(1).min(u128::from(u32::max_value())) as u32);
+        }
+        (EventDevice::Apic, None) => apic::stop_timer(),
+        (EventDevice::Hpet, Some(ns)) => hpet::set_oneshot(ns),
+        (EventDevice::Hpet, None) => hpet::stop(),
+        (EventDevice::Pit, Some(ns)) => pit::set_oneshot(ns),
+        (EventDevice::Pit, None) => pit::stop(),
+    }
+}
+
+/// Brings back the periodic tick of the current processor after
+/// `stop_tick()`
+pub fn restart_tick() {
+    if TICK_STOPPED.get().replace(false) {
+        start_tick();
+    }
+}
+
+/// Runs on every tick, whichever timer raised it. Every processor has its
+/// own tick, the boot processor keeps the count.
+fn timer_interrupt(stack_frame: &mut InterruptStackFrame) {
+    if cpu::is_bsp() {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The one-shot interrupt of an idle processor
+    restart_tick();
+
+    process::account_tick();
+    schedule::tick(interrupts::from_user(stack_frame));
+
+    // Lets a process stuck in a loop be killed or stopped
+    if interrupts::from_user(stack_frame) {
+        signal::deliver_default_actions(stack_frame);
+    }
+}
+
+/// The PIT or the HPET in its place
+fn legacy_timer_interrupt(_irq: u8, stack_frame: &mut InterruptStackFrame) -> bool {
+    timer_interrupt(stack_frame);
+
+    true
+}
+
+/// Entry of `apic::TIMER_VECTOR`
+pub extern "x86-interrupt" fn apic_timer_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = cpu::KernelGs::enter(stack_frame);
+
+    apic::end_of_interrupt();
+
+    timer_interrupt(stack_frame);
+}
+
+// tests
+
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_uptime() {
+    serial_print!("test_uptime... ");
+
+    let start = uptime();
+    let tick = schedule::ticks();
+
+    while schedule::ticks() < tick + 2 {
+        x86_64::instructions::hlt();
+    }
+
+    let elapsed = uptime() - start;
+
+    // Two ticks are at least one period apart, give or take the calibration
+    assert!(elapsed >= NS_PER_TICK / 2);
+    assert!(elapsed < NS_PER_SEC);
+
+    // The RTC of the test machines is set after 2020
+    assert!(time() > 1_577_836_800);
+    // A second may pass between the two reads
+    let now = gettimeofday().sec;
+
+    assert!(time() - now <= 1);
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,85 @@
+//! The 8254 PIT: channel 0 raises the timer interrupt, channel 2 measures
+//! short delays to calibrate the other clocks.
+
+use x86_64::instructions::port::Port;
+
+use super::NS_PER_SEC;
+
+/// Input clock of the counters, in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// Longest delay of a count, the counters have 16 bits
+pub const MAX_DELAY_NS: u64 = 0xffff * NS_PER_SEC / FREQUENCY;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// Gate of the channel 2 and speaker, the output of the channel 2 reads back
+// on bit 5
+const PORT_B: u16 = 0x61;
+
+const SELECT_CHANNEL_0: u8 = 0b00 << 6;
+const SELECT_CHANNEL_2: u8 = 0b10 << 6;
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+const MODE_TERMINAL_COUNT: u8 = 0b000 << 1;
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+const PORT_B_GATE_2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUTPUT_2: u8 = 1 << 5;
+
+/// Count of input clock periods covering `ns`, within the 16 bits
+fn count(ns: u64) -> u16 {
+    (ns.saturating_mul(FREQUENCY) / NS_PER_SEC)
+        .max(1)
+        .min(0xffff) as u16
+}
+
+unsafe fn program(command: u8, channel: u16, count: u16) {
+    Port::<u8>::new(COMMAND).write(command | ACCESS_LOW_HIGH);
+
+    let mut channel = Port::<u8>::new(channel);
+
+    channel.write(count as u8);
+    channel.write((count >> 8) as u8);
+}
+
+/// Raises the timer interrupt `hz` times per second
+pub fn set_periodic(hz: u64) {
+    let divisor = (FREQUENCY / hz).max(1).min(0xffff) as u16;
+
+    unsafe { program(SELECT_CHANNEL_0 | MODE_RATE_GENERATOR, CHANNEL_0, divisor) };
+}
+
+/// Raises the timer interrupt once, after `ns` or `MAX_DELAY_NS`
+pub fn set_oneshot(ns: u64) {
+    unsafe { program(SELECT_CHANNEL_0 | MODE_TERMINAL_COUNT, CHANNEL_0, count(ns)) };
+}
+
+/// Stops the timer interrupt: the channel 0 waits for a count that is never
+/// written
+pub fn stop() {
+    unsafe {
+        Port::<u8>::new(COMMAND).write(SELECT_CHANNEL_0 | ACCESS_LOW_HIGH | MODE_TERMINAL_COUNT)
+    };
+}
+
+/// Spins for `ns`, at most `MAX_DELAY_NS`, on the channel 2. Usable with
+/// interrupts disabled, it raises none.
+pub fn busy_wait(ns: u64) {
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+
+        port_b.write((value & !PORT_B_SPEAKER) | PORT_B_GATE_2);
+
+        program(SELECT_CHANNEL_2 | MODE_TERMINAL_COUNT, CHANNEL_2, count(ns));
+
+        // The output goes high at the end of the count
+        while port_b.read() & PORT_B_OUTPUT_2 == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+
+        port_b.write(value);
+    }
+}
@@ -0,0 +1,375 @@
+//! CMOS real-time clock: the date kept by the battery backed clock, taken
+//! as UTC.
+//!
+//! The registers hold BCD or binary values, with 12 or 24 hours, as the
+//! firmware chose. They are read twice, outside of an update, until both
+//! readings agree.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::hpet;
+use crate::acpi;
+use crate::interrupts::irq::{self, IRQ_RTC};
+use crate::syscall::Errno;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0f;
+const STATUS_B_24_HOURS: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+const STATUS_C_INTERRUPT: u8 = 1 << 7;
+
+const HOURS_PM: u8 = 1 << 7;
+
+// Index of the century register in the FADT, after the header. Zero when
+// the clock has none.
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+const FADT_CENTURY: usize = 108 - 36;
+
+const DEFAULT_CENTURY: u16 = 20;
+
+// Days from 0000-03-01 to 1970-01-01, in the proleptic Gregorian calendar
+const DAYS_TO_EPOCH: u64 = 719_468;
+const DAYS_PER_ERA: u64 = 146_097;
+
+/// Held while the index and data ports are used
+static CMOS: Mutex<()> = Mutex::new(());
+
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+fn with_cmos<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+
+        f()
+    })
+}
+
+/// Only called with the CMOS locked
+unsafe fn read_register(reg: u8) -> u8 {
+    Port::<u8>::new(CMOS_INDEX).write(reg);
+    Port::<u8>::new(CMOS_DATA).read()
+}
+
+unsafe fn write_register(reg: u8, value: u8) {
+    Port::<u8>::new(CMOS_INDEX).write(reg);
+    Port::<u8>::new(CMOS_DATA).write(value);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC, 0 for an earlier date read
+    /// from a clock that is not set
+    pub fn to_unix(&self) -> u64 {
+        let (month, day) = (u64::from(self.month), u64::from(self.day));
+        // Years starting in March, the leap day comes last
+        let year = u64::from(self.year) - if month <= 2 { 1 } else { 0 };
+
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = match (era * DAYS_PER_ERA + day_of_era).checked_sub(DAYS_TO_EPOCH) {
+            Some(days) => days,
+            None => return 0,
+        };
+
+        days * 86_400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = seconds / 86_400 + DAYS_TO_EPOCH;
+        let seconds = seconds % 86_400;
+
+        let era = days / DAYS_PER_ERA;
+        let day_of_era = days - era * DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let march_month = (5 * day_of_year + 2) / 153;
+        let month = if march_month < 10 {
+            march_month + 3
+        } else {
+            march_month - 9
+        };
+        let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * march_month + 2) / 5 + 1) as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Finds the century register, if the FADT tells where it is
+pub fn init() {
+    let century = acpi::find_table(FADT_SIGNATURE)
+        .and_then(|table| table.data().get(FADT_CENTURY).copied())
+        .unwrap_or(0);
+
+    CENTURY_REGISTER.store(century, Ordering::SeqCst);
+}
+
+/// Seconds, minutes, hours, day, month, year and century, as stored
+fn read_registers() -> [u8; 7] {
+    let century = CENTURY_REGISTER.load(Ordering::Relaxed);
+
+    with_cmos(|| unsafe {
+        while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+
+        [
+            read_register(REG_SECONDS),
+            read_register(REG_MINUTES),
+            read_register(REG_HOURS),
+            read_register(REG_DAY),
+            read_register(REG_MONTH),
+            read_register(REG_YEAR),
+            if century != 0 {
+                read_register(century)
+            } else {
+                0
+            },
+        ]
+    })
+}
+
+/// Converts the registers to a date, according to the format in the status
+/// register B
+fn decode(registers: [u8; 7], status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| {
+        if binary {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0x0f)
+        }
+    };
+
+    let mut hour = convert(registers[2] & !HOURS_PM);
+
+    if status_b & STATUS_B_24_HOURS == 0 {
+        // 12 AM is midnight
+        hour %= 12;
+
+        if registers[2] & HOURS_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    let century = match registers[6] {
+        0 => DEFAULT_CENTURY,
+        century => u16::from(convert(century)),
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(convert(registers[5])),
+        month: convert(registers[4]),
+        day: convert(registers[3]),
+        hour,
+        minute: convert(registers[1]),
+        second: convert(registers[0]),
+    }
+}
+
+/// Current date and time of the clock
+pub fn read() -> DateTime {
+    let mut registers = read_registers();
+
+    // An update may have started between the check and the reads
+    loop {
+        let again = read_registers();
+
+        if again == registers {
+            break;
+        }
+
+        registers = again;
+    }
+
+    let status_b = with_cmos(|| unsafe { read_register(REG_STATUS_B) });
+
+    decode(registers, status_b)
+}
+
+/// Raises IRQ 8 at `32768 >> (rate - 1)` Hz, `rate` from 3 (8192 Hz) to 15
+/// (2 Hz). Fails with `EBUSY` while the HPET replaces the PIT, it takes
+/// IRQ 8 as well.
+pub fn enable_periodic(rate: u8) -> Result<(), Errno> {
+    if rate < 3 || rate > 15 {
+        return Err(Errno::EINVAL);
+    }
+
+    if hpet::is_enabled() && hpet::replaces_legacy_timers() {
+        return Err(Errno::EBUSY);
+    }
+
+    irq::register_irq(IRQ_RTC, rtc_interrupt)?;
+
+    with_cmos(|| unsafe {
+        let status_a = read_register(REG_STATUS_A);
+
+        write_register(REG_STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+
+        let status_b = read_register(REG_STATUS_B);
+
+        write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+
+        // A pending interrupt would block the next ones
+        read_register(REG_STATUS_C);
+    });
+
+    Ok(())
+}
+
+pub fn disable_periodic() -> Result<(), Errno> {
+    with_cmos(|| unsafe {
+        let status_b = read_register(REG_STATUS_B);
+
+        write_register(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+    });
+
+    irq::unregister_irq(IRQ_RTC, rtc_interrupt)
+}
+
+/// Periodic interrupts received since boot
+pub fn periodic_interrupts() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+fn rtc_interrupt(_irq: u8, _stack_frame: &mut InterruptStackFrame) -> bool {
+    // Reading the flags acknowledges the interrupt
+    let flags = with_cmos(|| unsafe { read_register(REG_STATUS_C) });
+
+    if flags & STATUS_C_PERIODIC != 0 {
+        PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    flags & STATUS_C_INTERRUPT != 0
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_rtc_decode() {
+    use alloc::string::ToString;
+
+    serial_print!("test_rtc_decode... ");
+
+    // BCD, 12 hours: 2021-07-04 9:05:30 PM
+    let date = decode([0x30, 0x05, 0x89, 0x04, 0x07, 0x21, 0x20], 0);
+
+    assert_eq!(
+        date,
+        DateTime {
+            year: 2021,
+            month: 7,
+            day: 4,
+            hour: 21,
+            minute: 5,
+            second: 30,
+        }
+    );
+
+    // BCD, 12 hours: 12:15 AM is right after midnight
+    assert_eq!(decode([0, 0x15, 0x12, 1, 1, 0x99, 0x19], 0).hour, 0);
+
+    // Binary, 24 hours, no century register
+    let date = decode(
+        [59, 59, 23, 31, 12, 24, 0],
+        STATUS_B_BINARY | STATUS_B_24_HOURS,
+    );
+
+    assert_eq!(date.year, 2024);
+    assert_eq!(date.hour, 23);
+    assert_eq!(date.to_string(), "2024-12-31 23:59:59");
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_unix_time() {
+    use alloc::string::ToString;
+
+    serial_print!("test_unix_time... ");
+
+    let leap_day = DateTime {
+        year: 2000,
+        month: 2,
+        day: 29,
+        hour: 12,
+        minute: 34,
+        second: 56,
+    };
+
+    assert_eq!(DateTime::from_unix(0).to_string(), "1970-01-01 00:00:00");
+    assert_eq!(leap_day.to_unix(), 951_827_696);
+    assert_eq!(DateTime::from_unix(951_827_696), leap_day);
+    assert_eq!(
+        DateTime {
+            year: 1969,
+            ..leap_day
+        }
+        .to_unix(),
+        0
+    );
+    assert_eq!(
+        DateTime::from_unix(1_735_689_599).to_string(),
+        "2024-12-31 23:59:59"
+    );
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,27 @@
+//! Time stamp counter: the cycles since reset, read in a few cycles.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+const CPUID_FEATURES: u32 = 1;
+const CPUID_FEATURES_EDX_TSC: u32 = 1 << 4;
+
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_POWER_MANAGEMENT_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(CPUID_FEATURES).edx & CPUID_FEATURES_EDX_TSC != 0 }
+}
+
+/// Whether the rate stays the same across frequency changes and sleep
+/// states
+pub fn is_invariant() -> bool {
+    unsafe {
+        __cpuid(CPUID_EXTENDED_MAX).eax >= CPUID_POWER_MANAGEMENT
+            && __cpuid(CPUID_POWER_MANAGEMENT).edx & CPUID_POWER_MANAGEMENT_EDX_INVARIANT_TSC != 0
+    }
+}
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
@@ -0,0 +1,92 @@
+use alloc::sync::Arc;
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::memory::paging::AddressSpace;
+use crate::process::{self, Pid};
+use crate::syscall::{Errno, SyscallFrame};
+
+extern "C" {
+    fn enter_user_mode(rip: u64, rsp: u64, cs: u64, ss: u64) -> !;
+    fn return_to_user(frame: *const SyscallFrame, cs: u64, ss: u64) -> !;
+}
+
+// Flags userland may not change: IOPL, nested task and virtual 8086 mode
+const PRIVILEGED_RFLAGS: u64 = 0x3000 | 0x4000 | 0x2_0000;
+const RFLAGS_INTERRUPT_FLAG: u64 = 0x200;
+
+/// Drops the current task to ring 3 at `entry` with its stack pointer set to
+/// `stack_top`. Both must be in user accessible pages.
+pub unsafe fn enter(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    enter_user_mode(
+        entry.as_u64(),
+        stack_top.as_u64(),
+        u64::from(gdt::user_code_selector().0),
+        u64::from(gdt::user_data_selector().0),
+    )
+}
+
+/// Goes back to ring 3 with all the registers taken from `frame`, as if the
+/// syscall that saved it had just returned
+pub unsafe fn resume(frame: &SyscallFrame) -> ! {
+    let mut frame = *frame;
+
+    frame.rflags = (frame.rflags & !PRIVILEGED_RFLAGS) | RFLAGS_INTERRUPT_FLAG;
+
+    return_to_user(
+        &frame,
+        u64::from(gdt::user_code_selector().0),
+        u64::from(gdt::user_data_selector().0),
+    )
+}
+
+/// Starts a child process that goes straight to ring 3, sharing the page
+/// table of the kernel
+pub fn spawn(entry: VirtAddr, stack_top: VirtAddr) -> Result<Pid, Errno> {
+    process::create(None, move || unsafe { enter(entry, stack_top) })
+}
+
+/// Starts a child process that goes to ring 3 in its own address space
+pub fn spawn_in(
+    address_space: Arc<AddressSpace>,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+) -> Result<Pid, Errno> {
+    process::create(Some(address_space), move || unsafe {
+        enter(entry, stack_top)
+    })
+}
+
+// tests
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_user_fault_on_kernel_page() {
+    use crate::memory::paging::{helpers, PAGE_SIZE, USER_SPACE_START};
+
+    serial_print!("test_user_fault_on_kernel_page... ");
+
+    let code_addr = VirtAddr::new(USER_SPACE_START);
+    let stack_addr = VirtAddr::new(USER_SPACE_START + 0x10000);
+
+    helpers::alloc_user_page(code_addr);
+    helpers::alloc_user_page(stack_addr);
+
+    // mov rax, [0x100000] ; jmp $
+    let code: [u8; 10] = [0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x10, 0x00, 0xeb, 0xfe];
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), code_addr.as_mut_ptr(), code.len());
+    }
+
+    let pid = spawn(code_addr, stack_addr + PAGE_SIZE).unwrap();
+
+    assert_eq!(
+        process::wait(Some(pid)),
+        Ok((pid, -(process::signal::SIGSEGV as i32)))
+    );
+
+    serial_println!("[ok]");
+}